@@ -168,6 +168,9 @@ So if you could go ahead and try to remember to do that from now on, that'd be g
                     RuntimeError::TooManyIncludes => {
                         eprintln!("Too many included scripts.");
                     }
+                    RuntimeError::TooManyNestedCalls => {
+                        eprintln!("Too many nested function calls.");
+                    }
                     RuntimeError::InvalidInstruction(instruction) => {
                         eprintln!(
                             "Invalid instruction {:?} found at {}:{}.",
@@ -190,6 +193,18 @@ So if you could go ahead and try to remember to do that from now on, that'd be g
                     RuntimeError::CPULimitReached => {
                         eprintln!("Script exceeded the configured CPU limit.");
                     }
+                    RuntimeError::MemoryLimitReached => {
+                        eprintln!("Script exceeded the configured memory limit.");
+                    }
+                    RuntimeError::TimeLimitReached => {
+                        eprintln!("Script exceeded the configured time limit.");
+                    }
+                    RuntimeError::RateLimitExceeded => {
+                        eprintln!("Script exceeded the configured rate limit.");
+                    }
+                    RuntimeError::PolicyViolation(violation) => {
+                        eprintln!("Script action {violation:?} is not allowed.");
+                    }
                 }
                 input = true.into();
             }
@@ -0,0 +1,155 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use crate::compiler::{
+    grammar::{
+        expr::Expression,
+        instruction::{Block, CompilerState, Instruction},
+    },
+    lexer::{
+        tokenizer::TokenInfo,
+        word::{Word, WORDS},
+        Token,
+    },
+    CompileError, ErrorType,
+};
+
+/*
+   function <name: string> [<arguments: string-list>] <block>
+   return [<value: expression>]
+*/
+
+impl<'x> CompilerState<'x> {
+    pub(crate) fn parse_function(&mut self) -> Result<Block, CompileError> {
+        let token_info = self.tokens.unwrap_next()?;
+        let name = match &token_info.token {
+            Token::StringConstant(name) => name.to_string().to_ascii_lowercase(),
+            _ => return Err(token_info.expected("constant string")),
+        };
+        if !is_identifier(&name) || WORDS.contains_key(&name) {
+            return Err(token_info.custom(ErrorType::InvalidFunctionName(name)));
+        } else if self.functions.contains_key(&name) || self.compiler.functions.contains_key(&name)
+        {
+            return Err(token_info.custom(ErrorType::FunctionAlreadyDefined(name)));
+        }
+
+        // Arguments are the first local variables of the function scope
+        let mut block = Block::new(Word::Function);
+        if !matches!(
+            self.tokens.peek().map(|r| r.map(|t| &t.token)),
+            Some(Ok(Token::CurlyOpen))
+        ) {
+            for argument in self.parse_static_strings()? {
+                let argument = argument.to_ascii_lowercase();
+                if !is_identifier(&argument) {
+                    return Err(token_info.custom(ErrorType::InvalidArguments));
+                } else if argument.len() >= self.compiler.max_variable_name_size {
+                    return Err(token_info.custom(ErrorType::VariableTooLong));
+                } else if block.vars_local.contains_key(&argument) {
+                    return Err(token_info.custom(ErrorType::DuplicatedParameter));
+                }
+                let var_id = block.vars_local.len();
+                block.vars_local.insert(argument, var_id);
+            }
+        }
+
+        // The body is skipped over and only entered through calls
+        self.instructions.push(Instruction::Jmp(usize::MAX));
+        self.functions.insert(
+            name,
+            (
+                self.instructions.len() as u32,
+                block.vars_local.len() as u32,
+            ),
+        );
+        self.fnc_vars_num = self.vars_num;
+        self.vars_num = block.vars_local.len();
+
+        Ok(block)
+    }
+
+    pub(crate) fn parse_function_call(
+        &mut self,
+        token_info: TokenInfo,
+    ) -> Result<Instruction, CompileError> {
+        let (name, (pos, num_args)) = match &token_info.token {
+            Token::Unknown(name) if self.functions.contains_key(name) => {
+                (name.to_string(), self.functions[name])
+            }
+            _ => return Err(token_info.expected("function name")),
+        };
+
+        let mut expr = Vec::with_capacity(num_args as usize + 1);
+        while !matches!(
+            self.tokens.peek().map(|r| r.map(|t| &t.token)),
+            Some(Ok(Token::Comma
+                | Token::ParenthesisClose
+                | Token::CurlyOpen))
+        ) {
+            let argument = self.tokens.unwrap_next()?;
+            expr.push(Expression::Value(self.parse_string_token(argument)?));
+        }
+
+        if expr.len() == num_args as usize {
+            expr.push(Expression::Call { pos, num_args });
+            Ok(Instruction::Eval(expr))
+        } else {
+            Err(token_info.custom(ErrorType::InvalidExpression(format!(
+                "Function {:?} expected {} arguments, got {}",
+                name,
+                num_args,
+                expr.len()
+            ))))
+        }
+    }
+
+    pub(crate) fn parse_function_return(&mut self) -> Result<(), CompileError> {
+        let expr = if matches!(
+            self.tokens.peek().map(|r| r.map(|t| &t.token)),
+            Some(Ok(Token::StringConstant(_) | Token::StringVariable(_)))
+        ) {
+            self.parse_expr()?
+        } else {
+            Vec::new()
+        };
+        self.instructions.push(Instruction::FncReturn(expr));
+        Ok(())
+    }
+
+    pub(crate) fn is_in_function(&self) -> bool {
+        [&self.block]
+            .into_iter()
+            .chain(self.block_stack.iter())
+            .any(|b| matches!(&b.btype, Word::Function))
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.as_bytes()
+        .first()
+        .is_some_and(|ch| ch.is_ascii_alphabetic() || *ch == b'_')
+        && name
+            .as_bytes()
+            .iter()
+            .all(|ch| ch.is_ascii_alphanumeric() || *ch == b'_')
+}
@@ -32,7 +32,7 @@ use crate::{
         lexer::{word::Word, Token},
         CompileError, ErrorType, Value,
     },
-    runtime::actions::action_notify::{validate_from, validate_uri},
+    runtime::actions::action_notify::{parse_uri, validate_from, validate_uri},
     FileCarbonCopy,
 };
 
@@ -141,7 +141,14 @@ impl<'x> CompilerState<'x> {
                 }
                 _ => {
                     if let Token::StringConstant(uri) = &token_info.token {
-                        if validate_uri(uri.to_string().as_ref()).is_none() {
+                        let uri = uri.to_string();
+                        if validate_uri(uri.as_ref()).is_none()
+                            && !parse_uri(uri.as_ref()).is_some_and(|(scheme, _)| {
+                                self.compiler
+                                    .notify_schemes
+                                    .contains(&scheme.to_ascii_lowercase())
+                            })
+                        {
                             return Err(token_info.custom(ErrorType::InvalidURI));
                         }
                     }
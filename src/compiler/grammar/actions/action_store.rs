@@ -0,0 +1,73 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use serde::{Deserialize, Serialize};
+
+use crate::compiler::{
+    grammar::instruction::{CompilerState, Instruction},
+    lexer::{word::Word, Token},
+    CompileError, ErrorType, Value,
+};
+
+/*
+   store [:ttl <seconds: number>] :key <key: string> <value: string>
+*/
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Store {
+    pub key: Value,
+    pub value: Value,
+    pub ttl: Option<u64>,
+}
+
+impl<'x> CompilerState<'x> {
+    pub(crate) fn parse_store(&mut self) -> Result<(), CompileError> {
+        let mut key = None;
+        let mut ttl = None;
+
+        let (key, value) = loop {
+            let token_info = self.tokens.unwrap_next()?;
+            match token_info.token {
+                Token::Tag(Word::Key) => {
+                    self.validate_argument(1, None, token_info.line_num, token_info.line_pos)?;
+                    key = self.parse_string()?.into();
+                }
+                Token::Tag(Word::Ttl) => {
+                    self.validate_argument(2, None, token_info.line_num, token_info.line_pos)?;
+                    ttl = (self.tokens.expect_number(u64::MAX as usize)? as u64).into();
+                }
+                _ => {
+                    if let Some(key) = key {
+                        break (key, self.parse_string_token(token_info)?);
+                    } else {
+                        return Err(token_info.custom(ErrorType::InvalidArguments));
+                    }
+                }
+            }
+        };
+
+        self.instructions
+            .push(Instruction::Store(Store { key, value, ttl }));
+        Ok(())
+    }
+}
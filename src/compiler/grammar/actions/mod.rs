@@ -25,6 +25,7 @@ pub mod action_convert;
 pub mod action_editheader;
 pub mod action_fileinto;
 pub mod action_flags;
+pub mod action_function;
 pub mod action_include;
 pub mod action_keep;
 pub mod action_mime;
@@ -33,4 +34,5 @@ pub mod action_redirect;
 pub mod action_reject;
 pub mod action_require;
 pub mod action_set;
+pub mod action_store;
 pub mod action_vacation;
@@ -23,9 +23,13 @@
 
 use std::sync::Arc;
 
+use phf::phf_map;
 use serde::{Deserialize, Serialize};
 
-use crate::compiler::{Number, VariableType};
+use crate::{
+    compiler::{Number, Regex, Value, VariableType},
+    runtime::tests::glob::GlobPattern,
+};
 
 pub mod parser;
 pub mod tokenizer;
@@ -36,10 +40,32 @@ pub(crate) enum Expression {
     Constant(Constant),
     BinaryOperator(BinaryOperator),
     UnaryOperator(UnaryOperator),
-    JmpIf { val: bool, pos: u32 },
-    Function { id: u32, num_args: u32 },
+    JmpIf {
+        val: bool,
+        pos: u32,
+    },
+    Function {
+        id: u32,
+        num_args: u32,
+    },
     ArrayAccess,
     ArrayBuild(u32),
+    Call {
+        pos: u32,
+        num_args: u32,
+    },
+    BuiltIn {
+        fnc: BuiltIn,
+        num_args: u32,
+        pattern: Option<Pattern>,
+    },
+    Value(Value),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub(crate) enum Pattern {
+    Regex(Regex),
+    Glob(GlobPattern),
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -98,6 +124,7 @@ pub(crate) enum Token {
         name: String,
         id: u32,
         num_args: u32,
+        kind: FunctionKind,
     },
     Number(Number),
     String(String),
@@ -109,3 +136,65 @@ pub(crate) enum Token {
     CloseBracket,
     Comma,
 }
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum FunctionKind {
+    // Registered by the host through FunctionMap
+    Native,
+    // Declared by the script with "function"
+    Script,
+    BuiltIn(BuiltIn),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub(crate) enum BuiltIn {
+    JsonParse,
+    JsonEncode,
+    Matches,
+    RegexMatch,
+    RegexCaptures,
+    RegexReplace,
+    Now,
+    ParseDate,
+    DateDiff,
+    FormatDate,
+    Weekday,
+    Hour,
+}
+
+// Functions available to every expression, host functions with the same
+// name take precedence.
+pub(crate) static BUILT_INS: phf::Map<&'static str, (BuiltIn, u32)> = phf_map! {
+    "json_parse" => (BuiltIn::JsonParse, 1),
+    "json_encode" => (BuiltIn::JsonEncode, 1),
+    "matches" => (BuiltIn::Matches, 2),
+    "regex_match" => (BuiltIn::RegexMatch, 2),
+    "regex_captures" => (BuiltIn::RegexCaptures, 2),
+    "regex_replace" => (BuiltIn::RegexReplace, 3),
+    "now" => (BuiltIn::Now, 0),
+    "parse_date" => (BuiltIn::ParseDate, 1),
+    "date_diff" => (BuiltIn::DateDiff, 3),
+    "format_date" => (BuiltIn::FormatDate, 3),
+    "weekday" => (BuiltIn::Weekday, 2),
+    "hour" => (BuiltIn::Hour, 2),
+};
+
+impl BuiltIn {
+    // Position of the argument holding a regular expression or glob pattern
+    pub(crate) fn pattern_arg(&self) -> Option<u32> {
+        match self {
+            BuiltIn::Matches
+            | BuiltIn::RegexMatch
+            | BuiltIn::RegexCaptures
+            | BuiltIn::RegexReplace => Some(1),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn uses_regex(&self) -> bool {
+        matches!(
+            self,
+            BuiltIn::RegexMatch | BuiltIn::RegexCaptures | BuiltIn::RegexReplace
+        )
+    }
+}
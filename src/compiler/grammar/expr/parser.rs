@@ -21,7 +21,12 @@
  * for more details.
 */
 
-use super::{tokenizer::Tokenizer, BinaryOperator, Expression, Token};
+use crate::{compiler::Regex, runtime::tests::glob::GlobPattern};
+
+use super::{
+    tokenizer::Tokenizer, BinaryOperator, BuiltIn, Constant, Expression, FunctionKind, Pattern,
+    Token,
+};
 
 pub(crate) struct ExpressionParser<'x, F>
 where
@@ -95,8 +100,15 @@ where
                         }
                     }
 
-                    if let Some((Token::Function { id, num_args, name }, _)) =
-                        self.operator_stack.last()
+                    if let Some((
+                        Token::Function {
+                            id,
+                            num_args,
+                            name,
+                            kind,
+                        },
+                        _,
+                    )) = self.operator_stack.last()
                     {
                         let got_args = self.arg_count.pop().unwrap();
                         if got_args != *num_args as i32 {
@@ -110,10 +122,19 @@ where
                             });
                         }
 
-                        let expr = match *id {
-                            ID_ARRAY_ACCESS => Expression::ArrayAccess,
-                            ID_ARRAY_BUILD => Expression::ArrayBuild(*num_args),
-                            id => Expression::Function {
+                        let expr = match (*kind, *id) {
+                            (FunctionKind::Script, pos) => Expression::Call {
+                                pos,
+                                num_args: *num_args,
+                            },
+                            (FunctionKind::BuiltIn(fnc), _) => Expression::BuiltIn {
+                                fnc,
+                                num_args: *num_args,
+                                pattern: self.compile_pattern(fnc, *num_args)?,
+                            },
+                            (_, ID_ARRAY_ACCESS) => Expression::ArrayAccess,
+                            (_, ID_ARRAY_BUILD) => Expression::ArrayBuild(*num_args),
+                            (_, id) => Expression::Function {
                                 id,
                                 num_args: *num_args,
                             },
@@ -165,11 +186,23 @@ where
                     self.operator_stack
                         .push((Token::BinaryOperator(bop), jmp_pos));
                 }
-                Token::Function { id, name, num_args } => {
+                Token::Function {
+                    id,
+                    name,
+                    num_args,
+                    kind,
+                } => {
                     self.inc_arg_count();
                     self.arg_count.push(0);
-                    self.operator_stack
-                        .push((Token::Function { id, name, num_args }, None))
+                    self.operator_stack.push((
+                        Token::Function {
+                            id,
+                            name,
+                            num_args,
+                            kind,
+                        },
+                        None,
+                    ))
                 }
                 Token::OpenBracket => {
                     // Array functions
@@ -185,6 +218,7 @@ where
                             id,
                             name: String::from("array"),
                             num_args,
+                            kind: FunctionKind::Native,
                         },
                         None,
                     ));
@@ -246,6 +280,40 @@ where
         }
     }
 
+    // Patterns passed as string constants are validated and compiled once,
+    // the arguments that follow must be constants as well.
+    fn compile_pattern(&self, fnc: BuiltIn, num_args: u32) -> Result<Option<Pattern>, String> {
+        let Some(arg_pos) = fnc.pattern_arg() else {
+            return Ok(None);
+        };
+        let num_trailing = num_args.saturating_sub(arg_pos + 1) as usize;
+        let trailing_pos = match self.output.len().checked_sub(num_trailing) {
+            Some(pos) if pos > 0 => pos,
+            _ => return Ok(None),
+        };
+        if !self.output[trailing_pos..]
+            .iter()
+            .all(|expr| matches!(expr, Expression::Constant(_)))
+        {
+            return Ok(None);
+        }
+
+        match &self.output[trailing_pos - 1] {
+            Expression::Constant(Constant::String(expr)) if fnc == BuiltIn::Matches => {
+                Ok(Some(Pattern::Glob(GlobPattern::compile(expr, true))))
+            }
+            Expression::Constant(Constant::String(expr)) => fancy_regex::Regex::new(expr)
+                .map(|regex| {
+                    Some(Pattern::Regex(Regex {
+                        regex,
+                        expr: expr.to_string(),
+                    }))
+                })
+                .map_err(|err| format!("Invalid regular expression {expr:?}: {err}")),
+            _ => Ok(None),
+        }
+    }
+
     fn update_jmp_pos(&mut self, jmp_pos: Option<usize>) {
         if let Some(jmp_pos) = jmp_pos {
             let cur_pos = self.output.len();
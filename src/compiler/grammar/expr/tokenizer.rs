@@ -100,7 +100,7 @@ where
                 b'[' if matches!(self.buf.get(0..7), Some(b"header.")) => {
                     self.buf.push(ch);
                 }
-                b'-' if self.buf.last().map_or(false, |c| *c == b'[')
+                b'-' if self.buf.last().is_some_and(|c| *c == b'[')
                     || matches!(self.buf.get(0..7), Some(b"header.")) =>
                 {
                     self.buf.push(ch);
@@ -111,7 +111,7 @@ where
                 b']' if self.buf.contains(&b'[') => {
                     self.buf.push(b']');
                 }
-                b'*' if self.buf.last().map_or(false, |&c| c == b'[' || c == b'.') => {
+                b'*' if self.buf.last().is_some_and(|&c| c == b'[' || c == b'.') => {
                     self.buf.push(ch);
                 }
                 _ => {
@@ -247,7 +247,7 @@ where
                     );
 
                     return if prev_token.is_some() {
-                        self.next_token.push(token);
+                        self.next_token.insert(0, token);
                         Ok(prev_token)
                     } else {
                         Ok(Some(token))
@@ -296,7 +296,30 @@ where
                 }
             }
 
-            (self.token_map)(&buf, has_dot)
+            match (self.token_map)(&buf, has_dot) {
+                Err(err) if has_dot => self.parse_property(&buf).ok_or(err),
+                result => result,
+            }
+        }
+    }
+
+    // Rewrites "obj.key.sub" as "obj["key"]["sub"]"
+    fn parse_property(&mut self, buf: &str) -> Option<Token> {
+        for (pos, _) in buf.rmatch_indices('.') {
+            let (name, keys) = buf.split_at(pos);
+            if let Ok(token @ Token::Variable(_)) = (self.token_map)(name, name.contains('.')) {
+                let keys = keys[1..].split('.').collect::<Vec<_>>();
+                if keys.iter().any(|key| key.is_empty()) {
+                    return None;
+                }
+                for key in keys.into_iter().rev() {
+                    self.next_token.push(Token::CloseBracket);
+                    self.next_token.push(Token::String(key.to_string()));
+                    self.next_token.push(Token::OpenBracket);
+                }
+                return Some(token);
+            }
         }
+        None
     }
 }
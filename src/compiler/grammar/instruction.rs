@@ -46,10 +46,11 @@ use super::{
         action_redirect::Redirect,
         action_reject::Reject,
         action_set::{Let, Set},
+        action_store::Store,
         action_vacation::Vacation,
     },
-    expr::Expression,
-    Capability, Clear, Invalid, While,
+    expr::{Constant, Expression},
+    Capability, Clear, ForEach, Invalid, While,
 };
 
 use super::tests::test_ihave::Error;
@@ -108,11 +109,18 @@ pub(crate) enum Instruction {
 
     // For every line extension
     While(While),
+    ForEach(ForEach),
 
     // Expression extension
     Eval(Vec<Expression>),
     Let(Let),
 
+    // Store extension
+    Store(Store),
+
+    // Functions extension
+    FncReturn(Vec<Expression>),
+
     // Test only
     #[cfg(test)]
     TestCmd(Vec<Value>),
@@ -150,6 +158,10 @@ pub(crate) struct CompilerState<'x> {
     pub(crate) vars_local: usize,
     pub(crate) param_check: [bool; MAX_PARAMS],
     pub(crate) includes_num: usize,
+    pub(crate) uses_message_parts: bool,
+    pub(crate) message_refs: Vec<usize>,
+    pub(crate) functions: AHashMap<String, (u32, u32)>,
+    pub(crate) fnc_vars_num: usize,
 }
 
 impl Compiler {
@@ -176,11 +188,18 @@ impl Compiler {
             vars_local: 0,
             param_check: [false; MAX_PARAMS],
             includes_num: 0,
+            uses_message_parts: false,
+            message_refs: Vec::new(),
+            functions: AHashMap::new(),
+            fnc_vars_num: 0,
         };
+        let mut statement_start = 0;
 
         while let Some(token_info) = state.tokens.next() {
             let token_info = token_info?;
             state.reset_param_check();
+            state.mark_message_refs(statement_start);
+            statement_start = state.instructions.len();
 
             match token_info.token {
                 Token::Identifier(instruction) => {
@@ -259,7 +278,7 @@ impl Compiler {
                                 let tag = state.tokens.next().unwrap().unwrap();
                                 let label = state.tokens.expect_static_string()?;
                                 for block in &state.block_stack {
-                                    if block.label.as_ref().map_or(false, |n| n.eq(&label)) {
+                                    if block.label.as_ref().is_some_and(|n| n.eq(&label)) {
                                         return Err(
                                             tag.custom(ErrorType::LabelAlreadyDefined(label))
                                         );
@@ -282,16 +301,9 @@ impl Compiler {
                             if let Some(Ok(Token::Tag(Word::Name))) =
                                 state.tokens.peek().map(|r| r.map(|t| &t.token))
                             {
-                                state.validate_argument(
-                                    0,
-                                    Capability::ForEveryPart.into(),
-                                    token_info.line_num,
-                                    token_info.line_pos,
-                                )?;
-
                                 let tag = state.tokens.next().unwrap().unwrap();
                                 let label = state.tokens.expect_static_string()?;
-                                let mut label_found = false;
+                                let mut label_found = None;
                                 let mut num_pops = 0;
 
                                 for block in [&mut state.block]
@@ -300,28 +312,51 @@ impl Compiler {
                                 {
                                     if let Word::ForEveryPart = &block.btype {
                                         num_pops += 1;
-                                        if block.label.as_ref().map_or(false, |n| n.eq(&label)) {
+                                    }
+                                    if matches!(&block.btype, Word::ForEveryPart | Word::ForEach)
+                                        && block.label.as_ref().is_some_and(|n| n.eq(&label))
+                                    {
+                                        if num_pops > 0 {
                                             state
                                                 .instructions
                                                 .push(Instruction::ForEveryPartPop(num_pops));
-                                            block.break_jmps.push(state.instructions.len());
-                                            label_found = true;
-                                            break;
                                         }
+                                        block.break_jmps.push(state.instructions.len());
+                                        label_found = Some(block.btype);
+                                        break;
                                     }
                                 }
 
-                                if !label_found {
-                                    return Err(tag.custom(ErrorType::LabelUndefined(label)));
+                                match label_found {
+                                    Some(Word::ForEveryPart) => state.validate_argument(
+                                        0,
+                                        Capability::ForEveryPart.into(),
+                                        token_info.line_num,
+                                        token_info.line_pos,
+                                    )?,
+                                    Some(_) => state.validate_argument(
+                                        0,
+                                        Capability::While.into(),
+                                        token_info.line_num,
+                                        token_info.line_pos,
+                                    )?,
+                                    None => {
+                                        return Err(tag.custom(ErrorType::LabelUndefined(label)));
+                                    }
                                 }
                             } else {
                                 let mut block_found = None;
-                                if matches!(&state.block.btype, Word::ForEveryPart | Word::While) {
+                                if matches!(
+                                    &state.block.btype,
+                                    Word::ForEveryPart | Word::While | Word::ForEach
+                                ) {
                                     block_found = Some(&mut state.block);
                                 } else {
                                     for block in state.block_stack.iter_mut().rev() {
-                                        if matches!(&block.btype, Word::ForEveryPart | Word::While)
-                                        {
+                                        if matches!(
+                                            &block.btype,
+                                            Word::ForEveryPart | Word::While | Word::ForEach
+                                        ) {
                                             block_found = Some(block);
                                             break;
                                         }
@@ -490,12 +525,15 @@ impl Compiler {
                             }
                         }
                         Word::Return => {
-                            state.validate_argument(
-                                0,
-                                Capability::Include.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
+                            let is_in_function = state.is_in_function();
+                            if !is_in_function {
+                                state.validate_argument(
+                                    0,
+                                    Capability::Include.into(),
+                                    token_info.line_num,
+                                    token_info.line_pos,
+                                )?;
+                            }
                             let mut num_pops = 0;
 
                             for block in [&state.block]
@@ -513,7 +551,11 @@ impl Compiler {
                                     .push(Instruction::ForEveryPartPop(num_pops));
                             }
 
-                            state.instructions.push(Instruction::Return);
+                            if is_in_function {
+                                state.parse_function_return()?;
+                            } else {
+                                state.instructions.push(Instruction::Return);
+                            }
                         }
                         Word::Global => {
                             state.validate_argument(
@@ -568,6 +610,17 @@ impl Compiler {
                             state.instructions.push(Instruction::Eval(expr));
                         }
 
+                        // Store extension
+                        Word::Store => {
+                            state.validate_argument(
+                                0,
+                                Capability::Store.into(),
+                                token_info.line_num,
+                                token_info.line_pos,
+                            )?;
+                            state.parse_store()?;
+                        }
+
                         // While extension
                         Word::While => {
                             state.validate_argument(
@@ -585,6 +638,77 @@ impl Compiler {
                                 jz_pos: usize::MAX,
                             }));
                         }
+                        Word::ForEach => {
+                            state.validate_argument(
+                                0,
+                                Capability::While.into(),
+                                token_info.line_num,
+                                token_info.line_pos,
+                            )?;
+
+                            let mut block = Block::new(Word::ForEach);
+                            let mut index = None;
+                            let (item, expr) = loop {
+                                let token_info = state.tokens.unwrap_next()?;
+                                match token_info.token {
+                                    Token::Tag(Word::Name) => {
+                                        state.validate_argument(
+                                            1,
+                                            None,
+                                            token_info.line_num,
+                                            token_info.line_pos,
+                                        )?;
+                                        let label = state.tokens.expect_static_string()?;
+                                        if [&state.block]
+                                            .into_iter()
+                                            .chain(state.block_stack.iter())
+                                            .any(|b| b.label.as_ref().is_some_and(|n| n.eq(&label)))
+                                        {
+                                            return Err(token_info
+                                                .custom(ErrorType::LabelAlreadyDefined(label)));
+                                        }
+                                        block = block.with_label(label);
+                                    }
+                                    Token::Tag(Word::Index) => {
+                                        state.validate_argument(
+                                            2,
+                                            None,
+                                            token_info.line_num,
+                                            token_info.line_pos,
+                                        )?;
+                                        let name = state.tokens.unwrap_next()?;
+                                        index = state.parse_variable_name(name, false)?.into();
+                                    }
+                                    _ => {
+                                        let item = state.parse_variable_name(token_info, false)?;
+                                        break (item, state.parse_expr()?);
+                                    }
+                                }
+                            };
+
+                            // The list and position are kept in hidden variables of the
+                            // enclosing block, the counter is reset before entering the loop
+                            let id = state.instructions.len();
+                            let list = VariableType::Local(
+                                state.register_local_var(format!("!foreach_list{id}"), false),
+                            );
+                            let counter = VariableType::Local(
+                                state.register_local_var(format!("!foreach_pos{id}"), false),
+                            );
+                            state.instructions.push(Instruction::Let(Let {
+                                name: counter.clone(),
+                                expr: vec![Expression::Constant(Constant::Integer(-1))],
+                            }));
+                            state.instructions.push(Instruction::ForEach(ForEach {
+                                expr,
+                                list,
+                                counter,
+                                item,
+                                index,
+                                jz_pos: usize::MAX,
+                            }));
+                            is_new_block = block.into();
+                        }
                         Word::Continue => {
                             state.validate_argument(
                                 0,
@@ -592,24 +716,65 @@ impl Compiler {
                                 token_info.line_num,
                                 token_info.line_pos,
                             )?;
-                            let mut found_while = 0;
-                            for block in [&state.block]
+                            let label = if let Some(Ok(Token::Tag(Word::Name))) =
+                                state.tokens.peek().map(|r| r.map(|t| &t.token))
+                            {
+                                let tag = state.tokens.next().unwrap().unwrap();
+                                Some((tag, state.tokens.expect_static_string()?))
+                            } else {
+                                None
+                            };
+
+                            // The start of a loop is recorded in its enclosing block
+                            let blocks = [&state.block]
                                 .into_iter()
                                 .chain(state.block_stack.iter().rev())
-                            {
-                                if let Word::While = &block.btype {
-                                    found_while += 1;
-                                } else if found_while == 1 {
-                                    state
-                                        .instructions
-                                        .push(Instruction::Jmp(block.last_block_start));
-                                    found_while += 1;
+                                .collect::<Vec<_>>();
+                            let mut num_pops = 0;
+                            let mut loop_start = None;
+                            for (block, parent) in blocks.iter().zip(blocks.iter().skip(1)) {
+                                if matches!(&block.btype, Word::While | Word::ForEach)
+                                    && label.as_ref().is_none_or(|(_, label)| {
+                                        block.label.as_ref().is_some_and(|n| n.eq(label))
+                                    })
+                                {
+                                    loop_start = Some(parent.last_block_start);
                                     break;
+                                } else if let Word::ForEveryPart = &block.btype {
+                                    num_pops += 1;
+                                }
+                            }
+
+                            match (loop_start, label) {
+                                (Some(loop_start), _) => {
+                                    if num_pops > 0 {
+                                        state
+                                            .instructions
+                                            .push(Instruction::ForEveryPartPop(num_pops));
+                                    }
+                                    state.instructions.push(Instruction::Jmp(loop_start));
+                                }
+                                (None, Some((tag, label))) => {
+                                    return Err(tag.custom(ErrorType::LabelUndefined(label)));
+                                }
+                                (None, None) => {
+                                    return Err(token_info.custom(ErrorType::ContinueOutsideLoop));
                                 }
                             }
-                            if found_while != 2 {
-                                return Err(token_info.custom(ErrorType::ContinueOutsideLoop));
+                        }
+
+                        // Functions extension
+                        Word::Function => {
+                            state.validate_argument(
+                                0,
+                                Capability::Functions.into(),
+                                token_info.line_num,
+                                token_info.line_pos,
+                            )?;
+                            if !state.block_stack.is_empty() {
+                                return Err(token_info.custom(ErrorType::FunctionNotTopLevel));
                             }
+                            is_new_block = state.parse_function()?.into();
                         }
 
                         _ => {
@@ -721,17 +886,21 @@ impl Compiler {
                             }
                             state.last_block_type = Word::Else;
                         }
-                        Word::While => {
+                        Word::While | Word::ForEach => {
                             state
                                 .instructions
                                 .push(Instruction::Jmp(prev_block.last_block_start));
                             let cur_pos = state.instructions.len();
-                            if let Instruction::While(fep) =
-                                &mut state.instructions[prev_block.last_block_start]
-                            {
-                                fep.jz_pos = cur_pos;
-                            } else {
-                                debug_assert!(false, "This should not have happened.");
+                            match &mut state.instructions[prev_block.last_block_start] {
+                                Instruction::While(while_) => {
+                                    while_.jz_pos = cur_pos;
+                                }
+                                Instruction::ForEach(foreach) => {
+                                    foreach.jz_pos = cur_pos;
+                                }
+                                _ => {
+                                    debug_assert!(false, "This should not have happened.");
+                                }
                             }
                             for pos in state.block.break_jmps {
                                 if let Instruction::Jmp(jmp_pos) = &mut state.instructions[pos] {
@@ -742,6 +911,19 @@ impl Compiler {
                             }
                             state.last_block_type = Word::Not;
                         }
+                        Word::Function => {
+                            state.instructions.push(Instruction::FncReturn(Vec::new()));
+                            let cur_pos = state.instructions.len();
+                            if let Instruction::Jmp(jmp_pos) =
+                                &mut state.instructions[prev_block.last_block_start]
+                            {
+                                *jmp_pos = cur_pos;
+                            } else {
+                                debug_assert!(false, "This should not have happened.");
+                            }
+                            state.vars_num = state.fnc_vars_num;
+                            state.last_block_type = Word::Not;
+                        }
                         _ => {
                             debug_assert!(false, "This should not have happened.");
                         }
@@ -815,6 +997,8 @@ impl Compiler {
             }
         }
 
+        state.mark_message_refs(statement_start);
+
         if !state.block_stack.is_empty() {
             return Err(CompileError {
                 line_num: state.block.line_num,
@@ -830,10 +1014,24 @@ impl Compiler {
             num_vars += state.vars_local;
         }
 
+        // Instructions that need the full message when parsing lazily
+        let mut message_refs = state.message_refs;
+        message_refs.extend(
+            state
+                .instructions
+                .iter()
+                .enumerate()
+                .filter(|(_, instruction)| instruction.requires_message())
+                .map(|(pos, _)| pos),
+        );
+        message_refs.sort_unstable();
+        message_refs.dedup();
+
         Ok(Sieve {
             instructions: state.instructions,
             num_vars,
             num_match_vars: state.vars_match_max,
+            message_refs,
         })
     }
 }
@@ -841,16 +1039,8 @@ impl Compiler {
 impl<'x> CompilerState<'x> {
     pub(crate) fn is_var_local(&self, name: &str) -> bool {
         let name = name.to_ascii_lowercase();
-        if self.block.vars_local.contains_key(&name) {
-            true
-        } else {
-            for block in self.block_stack.iter().rev() {
-                if block.vars_local.contains_key(&name) {
-                    return true;
-                }
-            }
-            false
-        }
+        self.scope()
+            .any(|block| block.vars_local.contains_key(&name))
     }
 
     pub(crate) fn is_var_global(&self, name: &str) -> bool {
@@ -861,18 +1051,22 @@ impl<'x> CompilerState<'x> {
     pub(crate) fn register_local_var(&mut self, name: String, register_as_local: bool) -> usize {
         if let Some(var_id) = self.get_local_var(&name) {
             var_id
-        } else if !register_as_local || self.block_stack.is_empty() {
+        } else if !register_as_local
+            || self.block_stack.is_empty()
+            || matches!(&self.block.btype, Word::Function)
+        {
             let var_id = self.vars_num;
             self.block.vars_local.insert(name, var_id);
             self.vars_num += 1;
             var_id
         } else {
             let var_id = usize::MAX - self.vars_local;
-            self.block_stack
-                .first_mut()
-                .unwrap()
-                .vars_local
-                .insert(name, usize::MAX - self.vars_local);
+            let scope = self
+                .block_stack
+                .iter()
+                .position(|block| matches!(&block.btype, Word::Function))
+                .unwrap_or(0);
+            self.block_stack[scope].vars_local.insert(name, var_id);
             self.vars_local += 1;
             var_id
         }
@@ -884,16 +1078,21 @@ impl<'x> CompilerState<'x> {
 
     pub(crate) fn get_local_var(&self, name: &str) -> Option<usize> {
         let name = name.to_ascii_lowercase();
-        if let Some(var_id) = self.block.vars_local.get(&name) {
-            Some(*var_id)
-        } else {
-            for block in self.block_stack.iter().rev() {
-                if let Some(var_id) = block.vars_local.get(&name) {
-                    return Some(*var_id);
-                }
-            }
-            None
-        }
+        self.scope()
+            .find_map(|block| block.vars_local.get(&name).copied())
+    }
+
+    // Blocks whose variables are visible, function bodies do not see the outer scope
+    fn scope(&self) -> impl Iterator<Item = &Block> {
+        let mut is_visible = true;
+        [&self.block]
+            .into_iter()
+            .chain(self.block_stack.iter().rev())
+            .take_while(move |block| {
+                let result = is_visible;
+                is_visible = !matches!(&block.btype, Word::Function);
+                result
+            })
     }
 
     pub(crate) fn register_match_var(&mut self, num: usize) -> bool {
@@ -951,7 +1150,12 @@ impl<'x> CompilerState<'x> {
     }
 
     pub(crate) fn block_end(&mut self) {
-        let vars_num_block = self.block.vars_local.len();
+        let vars_num_block = self
+            .block
+            .vars_local
+            .values()
+            .filter(|var_id| **var_id < self.vars_num)
+            .count();
         if vars_num_block > 0 {
             if self.vars_num > self.vars_num_max {
                 self.vars_num_max = self.vars_num;
@@ -971,6 +1175,14 @@ impl<'x> CompilerState<'x> {
         }
     }
 
+    fn mark_message_refs(&mut self, statement_start: usize) {
+        if self.uses_message_parts {
+            self.uses_message_parts = false;
+            self.message_refs
+                .extend(statement_start..self.instructions.len());
+        }
+    }
+
     fn map_local_vars(&mut self, last_id: usize) {
         for instruction in &mut self.instructions {
             match instruction {
@@ -1023,6 +1235,17 @@ impl<'x> CompilerState<'x> {
                 Instruction::While(v) => {
                     v.expr.map_local_vars(last_id);
                 }
+                Instruction::ForEach(v) => {
+                    v.expr.map_local_vars(last_id);
+                    v.list.map_local_vars(last_id);
+                    v.counter.map_local_vars(last_id);
+                    v.item.map_local_vars(last_id);
+                    v.index.map_local_vars(last_id);
+                }
+                Instruction::Store(v) => {
+                    v.key.map_local_vars(last_id);
+                    v.value.map_local_vars(last_id);
+                }
                 Instruction::Eval(v) => {
                     v.map_local_vars(last_id);
                 }
@@ -1053,12 +1276,82 @@ impl<'x> CompilerState<'x> {
                 Instruction::Include(v) => {
                     v.value.map_local_vars(last_id);
                 }
+                Instruction::FncReturn(v) => {
+                    v.map_local_vars(last_id);
+                }
                 _ => {}
             }
         }
     }
 }
 
+impl Instruction {
+    pub(crate) fn requires_message(&self) -> bool {
+        match self {
+            Instruction::ForEveryPartPush
+            | Instruction::ForEveryPart(_)
+            | Instruction::ForEveryPartPop(_)
+            | Instruction::Replace(_)
+            | Instruction::Enclose(_)
+            | Instruction::ExtractText(_)
+            | Instruction::Convert(_) => true,
+            Instruction::DeleteHeader(v) => v.mime_anychild,
+            Instruction::Test(test) => match test {
+                Test::Body(_) | Test::Convert(_) => true,
+                Test::Address(v) => v.mime_anychild,
+                Test::Exists(v) => v.mime_anychild,
+                Test::Header(v) => v.mime_anychild,
+                Test::Date(v) => v.mime_anychild,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    pub(crate) fn is_body_scan(&self) -> bool {
+        matches!(
+            self,
+            Instruction::Replace(_)
+                | Instruction::Enclose(_)
+                | Instruction::ExtractText(_)
+                | Instruction::Convert(_)
+                | Instruction::Test(Test::Body(_) | Test::Convert(_))
+        )
+    }
+
+    pub(crate) fn uses_regex(&self) -> bool {
+        let match_type = match self {
+            Instruction::Eval(expr) | Instruction::FncReturn(expr) => return expr_uses_regex(expr),
+            Instruction::Let(v) => return expr_uses_regex(&v.expr),
+            Instruction::While(v) => return expr_uses_regex(&v.expr),
+            Instruction::ForEach(v) => return expr_uses_regex(&v.expr),
+            Instruction::DeleteHeader(v) => &v.match_type,
+            Instruction::Test(test) => match test {
+                Test::Address(v) => &v.match_type,
+                Test::Envelope(v) => &v.match_type,
+                Test::Header(v) => &v.match_type,
+                Test::Body(v) => &v.match_type,
+                Test::Date(v) => &v.match_type,
+                Test::CurrentDate(v) => &v.match_type,
+                Test::String(v) | Test::Environment(v) => &v.match_type,
+                Test::NotifyMethodCapability(v) => &v.match_type,
+                Test::HasFlag(v) => &v.match_type,
+                Test::Metadata(v) => &v.match_type,
+                Test::SpamTest(v) => &v.match_type,
+                Test::VirusTest(v) => &v.match_type,
+                _ => return false,
+            },
+            _ => return false,
+        };
+        matches!(match_type, MatchType::Regex(_))
+    }
+}
+
+fn expr_uses_regex(expr: &[Expression]) -> bool {
+    expr.iter()
+        .any(|expr| matches!(expr, Expression::BuiltIn { fnc, .. } if fnc.uses_regex()))
+}
+
 pub trait MapLocalVars {
     fn map_local_vars(&mut self, last_id: usize);
 }
@@ -1147,6 +1440,17 @@ impl MapLocalVars for Test {
                 v.mailbox.map_local_vars(last_id);
                 v.attributes.map_local_vars(last_id);
             }
+            Test::Stored(v) => {
+                v.key.map_local_vars(last_id);
+            }
+            Test::RateLimit(v) => {
+                v.key.map_local_vars(last_id);
+            }
+            Test::AuthResults(v) => {
+                v.method.map_local_vars(last_id);
+                v.results.map_local_vars(last_id);
+                v.authserv_ids.map_local_vars(last_id);
+            }
             Test::Vacation(v) => {
                 v.addresses.map_local_vars(last_id);
                 v.handle.map_local_vars(last_id);
@@ -1192,8 +1496,10 @@ impl<T: MapLocalVars> MapLocalVars for Option<T> {
 
 impl MapLocalVars for Expression {
     fn map_local_vars(&mut self, last_id: usize) {
-        if let Expression::Variable(var) = self {
-            var.map_local_vars(last_id)
+        match self {
+            Expression::Variable(var) => var.map_local_vars(last_id),
+            Expression::Value(value) => value.map_local_vars(last_id),
+            _ => (),
         }
     }
 }
@@ -30,7 +30,7 @@ use self::{expr::Expression, instruction::CompilerState};
 
 use super::{
     lexer::{tokenizer::TokenInfo, word::Word, Token},
-    CompileError, ErrorType, Regex, Value,
+    CompileError, ErrorType, Regex, Value, VariableType,
 };
 
 pub mod actions;
@@ -90,6 +90,10 @@ pub enum Capability {
     // Extensions
     Expressions,
     While,
+    AuthResults,
+    Store,
+    RateLimit,
+    Functions,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -152,6 +156,16 @@ pub(crate) struct While {
     pub jz_pos: usize,
 }
 
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub(crate) struct ForEach {
+    pub expr: Vec<Expression>,
+    pub list: VariableType,
+    pub counter: VariableType,
+    pub item: VariableType,
+    pub index: Option<VariableType>,
+    pub jz_pos: usize,
+}
+
 impl<'x> CompilerState<'x> {
     #[inline(always)]
     pub fn expect_instruction_end(&mut self) -> Result<(), CompileError> {
@@ -200,10 +214,8 @@ impl<'x> CompilerState<'x> {
                         d_count -= 1;
                     }
                 }
-                Token::Comma => {
-                    if d_count == 0 {
-                        break;
-                    }
+                Token::Comma if d_count == 0 => {
+                    break;
                 }
                 Token::CurlyOpen => {
                     break;
@@ -626,6 +638,10 @@ impl Display for Capability {
             Capability::VirusTest => f.write_str("virustest"),
             Capability::While => f.write_str("vnd.stalwart.while"),
             Capability::Expressions => f.write_str("vnd.stalwart.expressions"),
+            Capability::AuthResults => f.write_str("vnd.stalwart.authresults"),
+            Capability::Store => f.write_str("vnd.stalwart.store"),
+            Capability::RateLimit => f.write_str("vnd.stalwart.ratelimit"),
+            Capability::Functions => f.write_str("vnd.stalwart.functions"),
             Capability::Other(capability) => f.write_str(capability),
         }
     }
@@ -683,4 +699,8 @@ static CAPABILITIES: phf::Map<&'static str, Capability> = phf_map! {
     // Extensions
     "vnd.stalwart.while" => Capability::While,
     "vnd.stalwart.expressions" => Capability::Expressions,
+    "vnd.stalwart.authresults" => Capability::AuthResults,
+    "vnd.stalwart.store" => Capability::Store,
+    "vnd.stalwart.ratelimit" => Capability::RateLimit,
+    "vnd.stalwart.functions" => Capability::Functions,
 };
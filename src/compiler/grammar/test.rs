@@ -25,7 +25,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::compiler::{
     lexer::{tokenizer::TokenInfo, word::Word, Token},
-    CompileError, ErrorType,
+    CompileError, ErrorType, VariableType,
 };
 
 use super::{
@@ -34,6 +34,7 @@ use super::{
     instruction::{CompilerState, Instruction},
     tests::{
         test_address::TestAddress,
+        test_authresults::TestAuthResults,
         test_body::TestBody,
         test_date::{TestCurrentDate, TestDate},
         test_duplicate::TestDuplicate,
@@ -46,9 +47,11 @@ use super::{
         test_mailbox::{TestMailboxExists, TestMetadata, TestMetadataExists},
         test_mailboxid::TestMailboxIdExists,
         test_notify::{TestNotifyMethodCapability, TestValidNotifyMethod},
+        test_ratelimit::TestRateLimit,
         test_size::TestSize,
         test_spamtest::{TestSpamTest, TestVirusTest},
         test_specialuse::TestSpecialUseExists,
+        test_stored::TestStored,
         test_string::TestString,
     },
     Capability, Invalid,
@@ -114,6 +117,11 @@ pub(crate) enum Test {
     // RFC 5230
     Vacation(TestVacation),
 
+    // Extensions
+    AuthResults(TestAuthResults),
+    Stored(TestStored),
+    RateLimit(TestRateLimit),
+
     // Only test
     #[cfg(test)]
     TestCmd {
@@ -122,12 +130,6 @@ pub(crate) enum Test {
     },
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct EvalExpression {
-    pub expr: Vec<Expression>,
-    pub is_not: bool,
-}
-
 #[derive(Debug)]
 struct Block {
     is_all: bool,
@@ -495,6 +497,39 @@ impl<'x> CompilerState<'x> {
                         self.parse_test_specialuseexists()?.into()
                     }
 
+                    // AuthResults extension
+                    Token::Identifier(Word::AuthResults) => {
+                        self.validate_argument(
+                            0,
+                            Capability::AuthResults.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        self.parse_test_authresults()?.into()
+                    }
+
+                    // Store extension
+                    Token::Identifier(Word::Stored) => {
+                        self.validate_argument(
+                            0,
+                            Capability::Store.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        self.parse_test_stored()?.into()
+                    }
+
+                    // Rate limit extension
+                    Token::Identifier(Word::RateLimit) => {
+                        self.validate_argument(
+                            0,
+                            Capability::RateLimit.into(),
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
+                        self.parse_test_ratelimit()?.into()
+                    }
+
                     // Expressions extension
                     Token::Identifier(Word::Eval) => {
                         self.validate_argument(
@@ -506,6 +541,11 @@ impl<'x> CompilerState<'x> {
 
                         Instruction::Eval(self.parse_expr()?)
                     }
+
+                    // Functions extension
+                    Token::Unknown(ref name) if self.functions.contains_key(name) => {
+                        self.parse_function_call(token_info)?
+                    }
                     Token::Identifier(word) => {
                         self.ignore_test()?;
                         Test::Invalid(Invalid {
@@ -588,13 +628,22 @@ impl<'x> CompilerState<'x> {
             _ => return Err(next_token.expected("string")),
         };
 
-        match ExpressionParser::from_tokenizer(Tokenizer::from_iter(
+        let result = ExpressionParser::from_tokenizer(Tokenizer::from_iter(
             expr.iter().enumerate().peekable(),
             |var_name, maybe_namespace| self.parse_expr_fnc_or_var(var_name, maybe_namespace),
         ))
         .parse()
-        {
-            Ok(parser) => Ok(parser.output),
+        .map(|parser| parser.output);
+        match result {
+            Ok(output) => {
+                if output
+                    .iter()
+                    .any(|expr| matches!(expr, Expression::Variable(VariableType::Part(_))))
+                {
+                    self.uses_message_parts = true;
+                }
+                Ok(output)
+            }
             Err(err) => {
                 let err = ErrorType::InvalidExpression(format!(
                     "{}: {}",
@@ -689,6 +738,15 @@ impl Instruction {
                 Test::SpecialUseExists(op) => {
                     op.is_not = true;
                 }
+                Test::AuthResults(op) => {
+                    op.is_not = true;
+                }
+                Test::Stored(op) => {
+                    op.is_not = true;
+                }
+                Test::RateLimit(op) => {
+                    op.is_not = true;
+                }
                 #[cfg(test)]
                 Test::TestCmd { is_not, .. } => {
                     *is_not = true;
@@ -22,6 +22,7 @@
 */
 
 pub mod test_address;
+pub mod test_authresults;
 pub mod test_body;
 pub mod test_date;
 pub mod test_duplicate;
@@ -35,7 +36,9 @@ pub mod test_ihave;
 pub mod test_mailbox;
 pub mod test_mailboxid;
 pub mod test_notify;
+pub mod test_ratelimit;
 pub mod test_size;
 pub mod test_spamtest;
 pub mod test_specialuse;
+pub mod test_stored;
 pub mod test_string;
@@ -0,0 +1,90 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use serde::{Deserialize, Serialize};
+
+use crate::compiler::{
+    grammar::instruction::CompilerState,
+    lexer::{word::Word, Token},
+    CompileError, Value,
+};
+
+use crate::compiler::grammar::test::Test;
+
+/*
+           Usage:    authresults [":authserv_id" <authserv-ids: string-list>]
+                     ":method" <method: string>
+                     [":result" <results: string-list>]
+*/
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct TestAuthResults {
+    pub method: Value,
+    pub results: Vec<Value>,
+    pub authserv_ids: Vec<Value>,
+    pub is_not: bool,
+}
+
+impl<'x> CompilerState<'x> {
+    pub(crate) fn parse_test_authresults(&mut self) -> Result<Test, CompileError> {
+        let mut method = None;
+        let mut results = Vec::new();
+        let mut authserv_ids = Vec::new();
+
+        while let Some(token_info) = self.tokens.peek() {
+            let token_info = token_info?;
+            let line_num = token_info.line_num;
+            let line_pos = token_info.line_pos;
+
+            match token_info.token {
+                Token::Tag(Word::Method) => {
+                    self.validate_argument(1, None, line_num, line_pos)?;
+                    self.tokens.next();
+                    method = self.parse_string()?.into();
+                }
+                Token::Tag(Word::Result) => {
+                    self.validate_argument(2, None, line_num, line_pos)?;
+                    self.tokens.next();
+                    results = self.parse_strings(false)?;
+                }
+                Token::Tag(Word::AuthservId) => {
+                    self.validate_argument(3, None, line_num, line_pos)?;
+                    self.tokens.next();
+                    authserv_ids = self.parse_strings(false)?;
+                }
+                _ => break,
+            }
+        }
+
+        if let Some(method) = method {
+            Ok(Test::AuthResults(TestAuthResults {
+                method,
+                results,
+                authserv_ids,
+                is_not: false,
+            }))
+        } else {
+            Err(self.tokens.unwrap_next()?.missing_tag(":method"))
+        }
+    }
+}
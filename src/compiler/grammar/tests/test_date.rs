@@ -21,6 +21,7 @@
  * for more details.
 */
 
+use chrono_tz::Tz;
 use mail_parser::HeaderName;
 use phf::phf_map;
 use serde::{Deserialize, Serialize};
@@ -48,7 +49,7 @@ pub(crate) struct TestDate {
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) struct TestCurrentDate {
-    pub zone: Option<i64>,
+    pub zone: Option<Zone>,
     pub match_type: MatchType,
     pub comparator: Comparator,
     pub date_part: DatePart,
@@ -59,6 +60,7 @@ pub(crate) struct TestCurrentDate {
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) enum Zone {
     Time(i64),
+    Named(Tz),
     Original,
     Local,
 }
@@ -166,7 +168,7 @@ impl<'x> CompilerState<'x> {
                 }
                 Token::Tag(Word::Zone) => {
                     self.validate_argument(7, None, token_info.line_num, token_info.line_pos)?;
-                    zone = Zone::Time(self.parse_timezone()?);
+                    zone = self.parse_timezone()?;
                 }
                 _ => {
                     if header_name.is_none() {
@@ -287,18 +289,27 @@ impl<'x> CompilerState<'x> {
         }))
     }
 
-    pub(crate) fn parse_timezone(&mut self) -> Result<i64, CompileError> {
+    pub(crate) fn parse_timezone(&mut self) -> Result<Zone, CompileError> {
         let token_info = self.tokens.unwrap_next()?;
         if let Token::StringConstant(value) = &token_info.token {
             let timezone = match value {
-                StringConstant::String(value) => value.parse::<i64>().unwrap_or(i64::MAX),
+                StringConstant::String(value) => match value.parse::<i64>() {
+                    Ok(timezone) => timezone,
+                    Err(_) => {
+                        // IANA time zone name, such as "Europe/Berlin"
+                        return value
+                            .parse::<Tz>()
+                            .map(Zone::Named)
+                            .map_err(|_| token_info.expected("valid time zone"));
+                    }
+                },
                 StringConstant::Number(Number::Integer(n)) => *n,
                 StringConstant::Number(Number::Float(n)) => *n as i64,
             };
 
             return match timezone {
-                0..=1400 => Ok((timezone / 100 * 3600) + (timezone % 100 * 60)),
-                -1200..=-1 => Ok((timezone / 100 * 3600) - (-timezone % 100 * 60)),
+                0..=1400 => Ok(Zone::Time((timezone / 100 * 3600) + (timezone % 100 * 60))),
+                -1200..=-1 => Ok(Zone::Time((timezone / 100 * 3600) - (-timezone % 100 * 60))),
                 _ => Err(token_info.expected("invalid timezone")),
             };
         }
@@ -35,6 +35,8 @@ use crate::{
 
 use crate::compiler::grammar::{test::Test, AddressPart, MatchType};
 
+use super::test_date::Zone;
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) struct TestEnvelope {
     pub envelope_list: Vec<Envelope>,
@@ -42,7 +44,7 @@ pub(crate) struct TestEnvelope {
     pub address_part: AddressPart,
     pub match_type: MatchType,
     pub comparator: Comparator,
-    pub zone: Option<i64>,
+    pub zone: Option<Zone>,
     pub is_not: bool,
 }
 
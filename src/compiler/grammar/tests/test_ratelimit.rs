@@ -0,0 +1,89 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use serde::{Deserialize, Serialize};
+
+use crate::compiler::{
+    grammar::{instruction::CompilerState, test::Test},
+    lexer::{word::Word, Token},
+    CompileError, ErrorType, Value,
+};
+
+/*
+   ratelimit :key <key: string> :count <number> :period <seconds: number>
+*/
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct TestRateLimit {
+    pub key: Value,
+    pub count: u64,
+    pub period: u64,
+    pub is_not: bool,
+}
+
+impl<'x> CompilerState<'x> {
+    pub(crate) fn parse_test_ratelimit(&mut self) -> Result<Test, CompileError> {
+        let mut key = None;
+        let mut count = None;
+        let mut period = None;
+
+        while let Some(token_info) = self.tokens.peek() {
+            let token_info = token_info?;
+            let line_num = token_info.line_num;
+            let line_pos = token_info.line_pos;
+
+            match token_info.token {
+                Token::Tag(Word::Key) => {
+                    self.validate_argument(1, None, line_num, line_pos)?;
+                    self.tokens.next();
+                    key = self.parse_string()?.into();
+                }
+                Token::Tag(Word::Count) => {
+                    self.validate_argument(2, None, line_num, line_pos)?;
+                    self.tokens.next();
+                    count = (self.tokens.expect_number(u64::MAX as usize)? as u64).into();
+                }
+                Token::Tag(Word::Period) => {
+                    self.validate_argument(3, None, line_num, line_pos)?;
+                    self.tokens.next();
+                    period = (self.tokens.expect_number(u64::MAX as usize)? as u64).into();
+                }
+                _ => break,
+            }
+        }
+
+        if let (Some(key), Some(count), Some(period)) = (key, count, period) {
+            Ok(Test::RateLimit(TestRateLimit {
+                key,
+                count,
+                period,
+                is_not: false,
+            }))
+        } else {
+            Err(self
+                .tokens
+                .unwrap_next()?
+                .custom(ErrorType::InvalidArguments))
+        }
+    }
+}
@@ -0,0 +1,54 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use serde::{Deserialize, Serialize};
+
+use crate::compiler::{
+    grammar::{instruction::CompilerState, test::Test},
+    lexer::{word::Word, Token},
+    CompileError, Value,
+};
+
+/*
+   stored :key <key: string>
+*/
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct TestStored {
+    pub key: Value,
+    pub is_not: bool,
+}
+
+impl<'x> CompilerState<'x> {
+    pub(crate) fn parse_test_stored(&mut self) -> Result<Test, CompileError> {
+        let token_info = self.tokens.unwrap_next()?;
+        if let Token::Tag(Word::Key) = token_info.token {
+            Ok(Test::Stored(TestStored {
+                key: self.parse_string()?,
+                is_not: false,
+            }))
+        } else {
+            Err(token_info.expected(":key"))
+        }
+    }
+}
@@ -28,12 +28,12 @@ use mail_parser::HeaderName;
 use crate::{
     compiler::{
         grammar::{
-            expr::{self},
+            expr::{self, FunctionKind, BUILT_INS},
             instruction::CompilerState,
             AddressPart,
         },
-        ContentTypePart, ErrorType, HeaderPart, HeaderVariable, MessagePart, Number,
-        ReceivedHostname, ReceivedPart, Value, VariableType,
+        AuthResultsPart, ContentTypePart, ErrorType, HeaderPart, HeaderVariable, MessagePart,
+        Number, ReceivedHostname, ReceivedPart, Value, VariableType,
     },
     runtime::eval::IntoString,
     Envelope, MAX_MATCH_VARIABLES,
@@ -105,45 +105,44 @@ impl<'x> CompilerState<'x> {
                         var_has_namespace = true;
                     }
                     b'0'..=b'9' => {}
-                    b'}' => {
-                        if pos > var_start_pos {
-                            // Add any text before the variable
-                            if !decode_buf.is_empty() {
-                                self.add_value(
-                                    &mut items,
-                                    &decode_buf,
-                                    parse_decoded,
-                                    text_has_digits,
-                                    text_has_dots,
-                                )?;
-                                decode_buf.clear();
-                                text_has_digits = true;
-                                text_has_dots = false;
-                            }
+                    b'}' if pos > var_start_pos => {
+                        // Add any text before the variable
+                        if !decode_buf.is_empty() {
+                            self.add_value(
+                                &mut items,
+                                &decode_buf,
+                                parse_decoded,
+                                text_has_digits,
+                                text_has_dots,
+                            )?;
+                            decode_buf.clear();
+                            text_has_digits = true;
+                            text_has_dots = false;
+                        }
 
-                            // Parse variable type
-                            let var_name = std::str::from_utf8(&bytes[var_start_pos..pos]).unwrap();
-                            let var_type = if !var_is_number {
-                                self.parse_variable(var_name, var_has_namespace)
-                            } else {
-                                self.parse_match_variable(var_name)
-                            };
-
-                            match var_type {
-                                Ok(Some(var)) => items.push(Value::Variable(var)),
-                                Ok(None) => {}
-                                Err(
-                                    ErrorType::InvalidNamespace(_) | ErrorType::InvalidEnvelope(_),
-                                ) => {
-                                    is_var_error = true;
+                        // Parse variable type
+                        let var_name = std::str::from_utf8(&bytes[var_start_pos..pos]).unwrap();
+                        let var_type = if !var_is_number {
+                            self.parse_variable(var_name, var_has_namespace)
+                        } else {
+                            self.parse_match_variable(var_name)
+                        };
+
+                        match var_type {
+                            Ok(Some(var)) => {
+                                if matches!(var, VariableType::Part(_)) {
+                                    self.uses_message_parts = true;
                                 }
-                                Err(e) => return Err(e),
+                                items.push(Value::Variable(var))
                             }
-
-                            state = State::None;
-                        } else {
-                            is_var_error = true;
+                            Ok(None) => {}
+                            Err(ErrorType::InvalidNamespace(_) | ErrorType::InvalidEnvelope(_)) => {
+                                is_var_error = true;
+                            }
+                            Err(e) => return Err(e),
                         }
+
+                        state = State::None;
                     }
                     b':' => {
                         if parse_decoded && !var_has_namespace {
@@ -310,6 +309,9 @@ impl<'x> CompilerState<'x> {
                 Some(("env", var_name)) if !var_name.is_empty() => {
                     VariableType::Environment(var_name.to_string())
                 }
+                Some(("store", var_name)) if !var_name.is_empty() => {
+                    VariableType::Store(var_name.to_string())
+                }
                 Some(("envelope", var_name)) if !var_name.is_empty() => {
                     let envelope = match var_name {
                         "from" => Envelope::From,
@@ -500,6 +502,23 @@ impl<'x> CompilerState<'x> {
                         name: var_name.to_string(),
                         id: *id,
                         num_args: *num_args,
+                        kind: FunctionKind::Native,
+                    })
+                } else if let Some((pos, num_args)) =
+                    self.functions.get(&var_name.to_ascii_lowercase())
+                {
+                    Ok(expr::Token::Function {
+                        name: var_name.to_string(),
+                        id: *pos,
+                        num_args: *num_args,
+                        kind: FunctionKind::Script,
+                    })
+                } else if let Some((fnc, num_args)) = BUILT_INS.get(var_name) {
+                    Ok(expr::Token::Function {
+                        name: var_name.to_string(),
+                        id: 0,
+                        num_args: *num_args,
+                        kind: FunctionKind::BuiltIn(*fnc),
                     })
                 } else {
                     Err(format!("Invalid variable or function name {var_name:?}"))
@@ -583,6 +602,9 @@ impl TryFrom<&str> for HeaderPart {
                 }
             }
 
+            // Authentication-Results
+            "auth" => HeaderPart::AuthResults(AuthResultsPart::try_from(subvalue)?),
+
             // Id
             "id" => HeaderPart::Id,
 
@@ -630,6 +652,32 @@ impl TryFrom<&str> for ReceivedPart {
     }
 }
 
+impl TryFrom<&str> for AuthResultsPart {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let mut parts = value.splitn(3, '.');
+        Ok(
+            match (parts.next().unwrap_or_default(), parts.next(), parts.next()) {
+                ("id", None, None) => AuthResultsPart::AuthservId,
+                ("", _, _) => return Err(()),
+                (method, None, None) => AuthResultsPart::Result(method.to_ascii_lowercase()),
+                (method, Some("reason"), None) => {
+                    AuthResultsPart::Reason(method.to_ascii_lowercase())
+                }
+                (method, Some(ptype), Some(name)) if !ptype.is_empty() && !name.is_empty() => {
+                    AuthResultsPart::Property {
+                        method: method.to_ascii_lowercase(),
+                        ptype: ptype.to_ascii_lowercase(),
+                        name: name.to_ascii_lowercase(),
+                    }
+                }
+                _ => return Err(()),
+            },
+        )
+    }
+}
+
 impl TryFrom<&str> for AddressPart {
     type Error = ();
 
@@ -670,6 +718,7 @@ impl Display for VariableType {
             VariableType::Match(v) => write!(f, "${{{v}}}"),
             VariableType::Global(v) => write!(f, "${{global.{v}}}"),
             VariableType::Environment(v) => write!(f, "${{env.{v}}}"),
+            VariableType::Store(v) => write!(f, "${{store.{v}}}"),
 
             VariableType::Envelope(env) => f.write_str(match env {
                 Envelope::From => "${{envelope.from}}",
@@ -747,7 +796,7 @@ mod tests {
     use crate::compiler::lexer::tokenizer::Tokenizer;
     use crate::compiler::lexer::word::Word;
     use crate::compiler::{AddressPart, HeaderPart, HeaderVariable, VariableType};
-    use crate::{AHashSet, Compiler};
+    use crate::{AHashMap, AHashSet, Compiler};
 
     #[test]
     fn tokenize_string() {
@@ -774,6 +823,10 @@ mod tests {
             vars_match_max: usize::MAX,
             param_check: [false; MAX_PARAMS],
             includes_num: 0,
+            uses_message_parts: false,
+            message_refs: Vec::new(),
+            functions: AHashMap::new(),
+            fnc_vars_num: 0,
         };
 
         for (input, expected_result) in [
@@ -443,7 +443,7 @@ impl<'x> Iterator for Tokenizer<'x> {
                         return Some(Err(self.invalid_character()));
                     }
                 },
-                State::BracketComment { .. } => match ch {
+                State::BracketComment => match ch {
                     b'/' if last_ch == b'*' => {
                         self.state = State::None;
                     }
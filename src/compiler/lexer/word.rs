@@ -152,11 +152,23 @@ pub(crate) enum Word {
     Zone,
 
     // Extensions
+    AuthResults,
+    AuthservId,
+    Method,
+    Result,
     Eval,
     Local,
     While,
+    ForEach,
     Let,
     Continue,
+    Store,
+    Stored,
+    Key,
+    Ttl,
+    RateLimit,
+    Period,
+    Function,
 }
 
 pub(crate) static WORDS: phf::Map<&'static str, Word> = phf_map! {
@@ -283,11 +295,23 @@ pub(crate) static WORDS: phf::Map<&'static str, Word> = phf_map! {
     "value" => Word::Value,
     "virustest" => Word::VirusTest,
     "zone" => Word::Zone,
+    "authresults" => Word::AuthResults,
+    "authserv_id" => Word::AuthservId,
+    "method" => Word::Method,
+    "result" => Word::Result,
     "eval" => Word::Eval,
     "local" => Word::Local,
     "while" => Word::While,
+    "foreach" => Word::ForEach,
     "let" => Word::Let,
     "continue" => Word::Continue,
+    "store" => Word::Store,
+    "stored" => Word::Stored,
+    "key" => Word::Key,
+    "ttl" => Word::Ttl,
+    "ratelimit" => Word::RateLimit,
+    "period" => Word::Period,
+    "function" => Word::Function,
 };
 
 impl Display for Word {
@@ -416,11 +440,23 @@ impl Display for Word {
             Word::Value => f.write_str("value"),
             Word::VirusTest => f.write_str("virustest"),
             Word::Zone => f.write_str("zone"),
+            Word::AuthResults => f.write_str("authresults"),
+            Word::AuthservId => f.write_str("authserv_id"),
+            Word::Method => f.write_str("method"),
+            Word::Result => f.write_str("result"),
             Word::Eval => f.write_str("eval"),
             Word::Local => f.write_str("local"),
             Word::While => f.write_str("while"),
+            Word::ForEach => f.write_str("foreach"),
             Word::Let => f.write_str("let"),
             Word::Continue => f.write_str("continue"),
+            Word::Store => f.write_str("store"),
+            Word::Stored => f.write_str("stored"),
+            Word::Key => f.write_str("key"),
+            Word::Ttl => f.write_str("ttl"),
+            Word::RateLimit => f.write_str("ratelimit"),
+            Word::Period => f.write_str("period"),
+            Word::Function => f.write_str("function"),
         }
     }
 }
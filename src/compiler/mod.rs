@@ -23,7 +23,7 @@
 
 use std::{borrow::Cow, fmt::Display, sync::Arc};
 
-use ahash::AHashMap;
+use ahash::{AHashMap, AHashSet};
 use mail_parser::HeaderName;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
@@ -86,6 +86,9 @@ pub enum ErrorType {
     DuplicatedParameter,
     UndeclaredCapability(Capability),
     MissingTag(Cow<'static, str>),
+    InvalidFunctionName(String),
+    FunctionAlreadyDefined(String),
+    FunctionNotTopLevel,
 }
 
 impl Default for Compiler {
@@ -115,6 +118,7 @@ pub enum VariableType {
     Match(usize),
     Global(String),
     Environment(String),
+    Store(String),
     Envelope(Envelope),
     Header(HeaderVariable),
     Part(MessagePart),
@@ -150,6 +154,7 @@ pub enum HeaderPart {
     Address(AddressPart),
     ContentType(ContentTypePart),
     Received(ReceivedPart),
+    AuthResults(AuthResultsPart),
     Raw,
     RawName,
     Exists,
@@ -179,6 +184,18 @@ pub enum ReceivedPart {
     DateRaw,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuthResultsPart {
+    AuthservId,
+    Result(String),
+    Reason(String),
+    Property {
+        method: String,
+        ptype: String,
+        name: String,
+    },
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ReceivedHostname {
     Name,
@@ -237,6 +254,7 @@ impl Compiler {
             max_includes: 6,
             functions: AHashMap::new(),
             no_capability_check: false,
+            notify_schemes: AHashSet::new(),
         }
     }
 
@@ -343,6 +361,18 @@ impl Compiler {
     pub fn set_no_capability_check(&mut self, value: bool) {
         self.no_capability_check = value;
     }
+
+    // Accepts notification URIs with this scheme, for methods registered
+    // on the runtime.
+    pub fn with_notify_scheme(mut self, scheme: impl AsRef<str>) -> Self {
+        self.set_notify_scheme(scheme);
+        self
+    }
+
+    pub fn set_notify_scheme(&mut self, scheme: impl AsRef<str>) {
+        self.notify_schemes
+            .insert(scheme.as_ref().to_ascii_lowercase());
+    }
 }
 
 impl CompileError {
@@ -474,6 +504,13 @@ impl Display for CompileError {
                 write!(f, "Undeclared capability '{value}'")
             }
             ErrorType::MissingTag(value) => write!(f, "Missing tag {value:?}"),
+            ErrorType::InvalidFunctionName(value) => write!(f, "Invalid function name {value:?}"),
+            ErrorType::FunctionAlreadyDefined(value) => {
+                write!(f, "Function {value:?} already defined")
+            }
+            ErrorType::FunctionNotTopLevel => {
+                write!(f, "Functions can only be declared at the top level")
+            }
         }?;
 
         write!(
@@ -489,6 +526,10 @@ impl Display for RuntimeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             RuntimeError::TooManyIncludes => write!(f, ""),
+            RuntimeError::TooManyNestedCalls => write!(
+                f,
+                "Script exceeded the maximum number of nested function calls."
+            ),
             RuntimeError::InvalidInstruction(value) => write!(
                 f,
                 "Script executed invalid instruction {:?} at line {}, column {}.",
@@ -509,6 +550,22 @@ impl Display for RuntimeError {
                 f,
                 "Script exceeded the maximum number of instructions allowed to execute."
             ),
+            RuntimeError::MemoryLimitReached => {
+                write!(f, "Script exceeded the maximum amount of memory allowed.")
+            }
+            RuntimeError::TimeLimitReached => {
+                write!(f, "Script exceeded the maximum execution time allowed.")
+            }
+            RuntimeError::RateLimitExceeded => {
+                write!(f, "Script exceeded the outgoing message rate limit.")
+            }
+            RuntimeError::PolicyViolation(violation) => {
+                write!(
+                    f,
+                    "Action {:?} to {:?} is not allowed by policy.",
+                    violation.action, violation.target
+                )
+            }
         }
     }
 }
@@ -530,7 +587,7 @@ mod tests {
 
         for file_name in fs::read_dir(&test_dir).unwrap() {
             let mut file_name = file_name.unwrap().path();
-            if file_name.extension().map_or(false, |e| e == "sieve") {
+            if file_name.extension().is_some_and(|e| e == "sieve") {
                 println!("Parsing {}", file_name.display());
 
                 /*if !file_name
@@ -0,0 +1,565 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{iter::Peekable, str::Chars};
+
+use super::{Action, Condition, Conversion, ScriptBuilder, Untranslated, UntranslatedReason};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    String(String),
+    Regex { pattern: String, flags: String },
+    Number(u64),
+    Backtick,
+    ParenOpen,
+    ParenClose,
+    CurlyOpen,
+    CurlyClose,
+    Not,
+    And,
+    Or,
+    Lt,
+    Gt,
+    Other(char),
+    Eol,
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    source: Vec<String>,
+    pos: usize,
+    untranslated: Vec<Untranslated>,
+}
+
+pub fn convert(mailfilter: &str) -> Conversion {
+    let mut parser = Parser {
+        tokens: tokenize(mailfilter),
+        source: mailfilter.lines().map(|l| l.trim().to_string()).collect(),
+        pos: 0,
+        untranslated: Vec::new(),
+    };
+    let mut builder = ScriptBuilder::default();
+    parser.parse_block(&mut builder, false);
+
+    Conversion {
+        script: builder.build(),
+        untranslated: parser.untranslated,
+    }
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<(usize, Token)> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    fn skip_eol(&mut self) {
+        while matches!(self.peek(), Some(Token::Eol)) {
+            self.pos += 1;
+        }
+    }
+
+    fn parse_block(&mut self, builder: &mut ScriptBuilder, is_nested: bool) {
+        loop {
+            self.skip_eol();
+            let Some((line_num, token)) = self.next() else {
+                return;
+            };
+
+            match token {
+                Token::CurlyClose if is_nested => return,
+                Token::Word(word) if word == "if" => {
+                    self.parse_if(builder, line_num, "if");
+                }
+                Token::Word(word) if ["to", "cc"].contains(&word.as_str()) => {
+                    let copy = word == "cc";
+                    match self.parse_destination() {
+                        Ok(action) => {
+                            builder.action(&action, copy);
+                            if !copy {
+                                builder.line("stop;");
+                            }
+                        }
+                        Err(reason) => self.report(builder, line_num, reason),
+                    }
+                    self.skip_statement();
+                }
+                Token::Word(word) if word == "exit" => {
+                    builder.line("stop;");
+                    self.skip_statement();
+                }
+                Token::Word(word) if word == "include" => {
+                    self.report(builder, line_num, UntranslatedReason::Include);
+                    self.skip_statement();
+                }
+                Token::Word(_) if matches!(self.peek(), Some(Token::Other('='))) => {
+                    let reason = if self.statement_has_backtick() {
+                        UntranslatedReason::Backtick
+                    } else {
+                        UntranslatedReason::Assignment
+                    };
+                    self.report(builder, line_num, reason);
+                    self.skip_statement();
+                }
+                Token::Backtick => {
+                    self.report(builder, line_num, UntranslatedReason::Backtick);
+                    self.skip_statement();
+                }
+                Token::CurlyOpen => {
+                    self.report(builder, line_num, UntranslatedReason::UnsupportedStatement);
+                    self.skip_block();
+                }
+                _ => {
+                    self.report(builder, line_num, UntranslatedReason::UnsupportedStatement);
+                    self.skip_statement();
+                }
+            }
+        }
+    }
+
+    fn parse_if(&mut self, builder: &mut ScriptBuilder, line_num: usize, keyword: &str) {
+        let condition = if matches!(self.peek(), Some(Token::ParenOpen)) {
+            self.pos += 1;
+            self.parse_expr().and_then(|condition| {
+                if matches!(self.next(), Some((_, Token::ParenClose))) {
+                    Ok(condition)
+                } else {
+                    Err(UntranslatedReason::UnsupportedCondition)
+                }
+            })
+        } else {
+            Err(UntranslatedReason::UnsupportedCondition)
+        };
+        self.skip_eol();
+        let has_block = matches!(self.peek(), Some(Token::CurlyOpen));
+        if has_block {
+            self.pos += 1;
+        }
+
+        match condition {
+            Ok(condition) if has_block => {
+                let test = builder.condition(&condition);
+                builder.open_block(format!("{keyword} {test}"));
+                self.parse_block(builder, true);
+                builder.close_block();
+            }
+            Ok(_) => {
+                self.report(builder, line_num, UntranslatedReason::UnsupportedStatement);
+                self.skip_statement();
+                return;
+            }
+            Err(reason) => {
+                self.report(builder, line_num, reason);
+                if has_block {
+                    self.skip_block();
+                } else {
+                    self.skip_statement();
+                }
+                // Skip any else branches of an untranslated condition
+                while self.peek_else().is_some() {
+                    if self.skip_else() {
+                        break;
+                    }
+                }
+                return;
+            }
+        }
+
+        match self.peek_else() {
+            Some((line_num, true)) => {
+                self.skip_else_keyword();
+                self.parse_if(builder, line_num, "elsif");
+            }
+            Some((line_num, false)) => {
+                self.skip_else_keyword();
+                self.skip_eol();
+                if matches!(self.peek(), Some(Token::CurlyOpen)) {
+                    self.pos += 1;
+                    builder.open_block("else");
+                    self.parse_block(builder, true);
+                    builder.close_block();
+                } else {
+                    self.report(builder, line_num, UntranslatedReason::UnsupportedStatement);
+                    self.skip_statement();
+                }
+            }
+            None => (),
+        }
+    }
+
+    // Returns the line number of the next 'else' / 'elsif' and whether it
+    // introduces another condition.
+    fn peek_else(&self) -> Option<(usize, bool)> {
+        let mut pos = self.pos;
+        while let Some((line_num, token)) = self.tokens.get(pos) {
+            match token {
+                Token::Eol => pos += 1,
+                Token::Word(word) if word == "elsif" => return Some((*line_num, true)),
+                Token::Word(word) if word == "else" => {
+                    return Some((
+                        *line_num,
+                        matches!(self.tokens.get(pos + 1), Some((_, Token::Word(w))) if w == "if"),
+                    ));
+                }
+                _ => return None,
+            }
+        }
+        None
+    }
+
+    fn skip_else_keyword(&mut self) {
+        self.skip_eol();
+        if let Some((_, Token::Word(word))) = self.next() {
+            if word == "else" && matches!(self.peek(), Some(Token::Word(w)) if w == "if") {
+                self.pos += 1;
+            }
+        }
+    }
+
+    // Skips an else branch, returns true if it was the final one.
+    fn skip_else(&mut self) -> bool {
+        let is_final = matches!(self.peek_else(), Some((_, false)));
+        self.skip_else_keyword();
+        if !is_final {
+            self.skip_eol();
+            if matches!(self.peek(), Some(Token::ParenOpen)) {
+                let mut depth = 0;
+                while let Some((_, token)) = self.next() {
+                    match token {
+                        Token::ParenOpen => depth += 1,
+                        Token::ParenClose => {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+            }
+        }
+        self.skip_eol();
+        if matches!(self.peek(), Some(Token::CurlyOpen)) {
+            self.pos += 1;
+            self.skip_block();
+        } else {
+            self.skip_statement();
+        }
+        is_final
+    }
+
+    fn parse_expr(&mut self) -> Result<Condition, UntranslatedReason> {
+        let mut conditions = vec![self.parse_and()?];
+        while matches!(self.peek(), Some(Token::Or)) {
+            self.pos += 1;
+            conditions.push(self.parse_and()?);
+        }
+        Ok(if conditions.len() == 1 {
+            conditions.pop().unwrap()
+        } else {
+            Condition::AnyOf(conditions)
+        })
+    }
+
+    fn parse_and(&mut self) -> Result<Condition, UntranslatedReason> {
+        let mut conditions = vec![self.parse_unary()?];
+        while matches!(self.peek(), Some(Token::And)) {
+            self.pos += 1;
+            conditions.push(self.parse_unary()?);
+        }
+        Ok(if conditions.len() == 1 {
+            conditions.pop().unwrap()
+        } else {
+            Condition::AllOf(conditions)
+        })
+    }
+
+    fn parse_unary(&mut self) -> Result<Condition, UntranslatedReason> {
+        match self.next() {
+            Some((_, Token::Not)) => self.parse_unary().map(Condition::negate),
+            Some((_, Token::ParenOpen)) => {
+                let condition = self.parse_expr()?;
+                if matches!(self.next(), Some((_, Token::ParenClose))) {
+                    Ok(condition)
+                } else {
+                    Err(UntranslatedReason::UnsupportedCondition)
+                }
+            }
+            Some((_, Token::Regex { pattern, flags })) => {
+                let case_sensitive = flags.contains('D');
+                let body = flags.contains('b');
+                let header = flags.contains('h') || !body;
+                if flags
+                    .chars()
+                    .any(|ch| !['h', 'b', 'D', 'w', 'W'].contains(&ch))
+                {
+                    return Err(UntranslatedReason::UnsupportedCondition);
+                }
+                let header = if header {
+                    Some(
+                        Condition::from_header_regex(&pattern, case_sensitive)
+                            .ok_or(UntranslatedReason::UnsupportedCondition)?,
+                    )
+                } else {
+                    None
+                };
+                let body = if body {
+                    Some(
+                        Condition::from_body_regex(&pattern, case_sensitive)
+                            .ok_or(UntranslatedReason::UnsupportedCondition)?,
+                    )
+                } else {
+                    None
+                };
+                match (header, body) {
+                    (Some(header), Some(body)) => Ok(Condition::AnyOf(vec![header, body])),
+                    (Some(condition), None) | (None, Some(condition)) => Ok(condition),
+                    (None, None) => Err(UntranslatedReason::UnsupportedCondition),
+                }
+            }
+            Some((_, Token::Word(word))) if word == "$SIZE" => {
+                let over = match self.next() {
+                    Some((_, Token::Gt)) => true,
+                    Some((_, Token::Lt)) => false,
+                    _ => return Err(UntranslatedReason::UnsupportedCondition),
+                };
+                if let Some((_, Token::Number(limit))) = self.next() {
+                    Ok(Condition::Size { over, limit })
+                } else {
+                    Err(UntranslatedReason::UnsupportedCondition)
+                }
+            }
+            Some((_, Token::Backtick)) => Err(UntranslatedReason::Backtick),
+            _ => Err(UntranslatedReason::UnsupportedCondition),
+        }
+    }
+
+    fn parse_destination(&mut self) -> Result<Action, UntranslatedReason> {
+        match self.next() {
+            Some((_, Token::String(destination))) => Action::parse(&destination),
+            Some((_, Token::Word(destination))) => Action::parse(&destination),
+            Some((_, Token::Backtick)) => Err(UntranslatedReason::Backtick),
+            Some((_, Token::Other('|'))) => Err(UntranslatedReason::Pipe),
+            Some((_, Token::Not)) => {
+                let mut addresses = String::from("!");
+                while let Some((_, Token::Word(address) | Token::String(address))) =
+                    self.tokens.get(self.pos).cloned()
+                {
+                    addresses.push(' ');
+                    addresses.push_str(&address);
+                    self.pos += 1;
+                }
+                Action::parse(&addresses)
+            }
+            _ => Err(UntranslatedReason::UnsupportedAction),
+        }
+    }
+
+    fn statement_has_backtick(&self) -> bool {
+        self.tokens[self.pos..]
+            .iter()
+            .take_while(|(_, token)| !matches!(token, Token::Eol))
+            .any(|(_, token)| matches!(token, Token::Backtick))
+    }
+
+    fn skip_statement(&mut self) {
+        while let Some(token) = self.peek() {
+            match token {
+                Token::Eol => break,
+                Token::CurlyClose => break,
+                Token::CurlyOpen => {
+                    self.pos += 1;
+                    self.skip_block();
+                }
+                _ => self.pos += 1,
+            }
+        }
+    }
+
+    fn skip_block(&mut self) {
+        let mut depth = 1;
+        while let Some((_, token)) = self.next() {
+            match token {
+                Token::CurlyOpen => depth += 1,
+                Token::CurlyClose => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn report(&mut self, builder: &mut ScriptBuilder, line_num: usize, reason: UntranslatedReason) {
+        let untranslated = Untranslated {
+            line_num,
+            line: self
+                .source
+                .get(line_num.saturating_sub(1))
+                .cloned()
+                .unwrap_or_default(),
+            reason,
+        };
+        builder.comment(&untranslated);
+        self.untranslated.push(untranslated);
+    }
+}
+
+fn tokenize(text: &str) -> Vec<(usize, Token)> {
+    let mut tokens = Vec::new();
+    let mut line_num = 1;
+    let mut iter = text.chars().peekable();
+
+    while let Some(ch) = iter.next() {
+        let token = match ch {
+            '\n' => {
+                line_num += 1;
+                Token::Eol
+            }
+            ';' => Token::Eol,
+            ' ' | '\t' | '\r' => continue,
+            '\\' if iter.peek() == Some(&'\n') => {
+                iter.next();
+                line_num += 1;
+                continue;
+            }
+            '#' => {
+                while iter.peek().is_some_and(|ch| *ch != '\n') {
+                    iter.next();
+                }
+                continue;
+            }
+            '"' | '\'' => {
+                let mut value = String::new();
+                while let Some(next_ch) = iter.next() {
+                    match next_ch {
+                        '\\' if ch == '"' => {
+                            if let Some(next_ch) = iter.next() {
+                                value.push(next_ch);
+                            }
+                        }
+                        next_ch if next_ch == ch => break,
+                        '\n' => {
+                            line_num += 1;
+                            value.push(next_ch);
+                        }
+                        next_ch => value.push(next_ch),
+                    }
+                }
+                Token::String(value)
+            }
+            '/' if matches!(
+                tokens.last(),
+                Some((_, Token::ParenOpen | Token::Not | Token::And | Token::Or))
+            ) =>
+            {
+                let pattern = read_regex(&mut iter);
+                let mut flags = String::new();
+                if iter.peek() == Some(&':') {
+                    iter.next();
+                    while let Some(ch) = iter.peek().filter(|ch| ch.is_ascii_alphabetic()) {
+                        flags.push(*ch);
+                        iter.next();
+                    }
+                }
+                Token::Regex { pattern, flags }
+            }
+            '`' => Token::Backtick,
+            '(' => Token::ParenOpen,
+            ')' => Token::ParenClose,
+            '{' => Token::CurlyOpen,
+            '}' => Token::CurlyClose,
+            '!' => Token::Not,
+            '<' => Token::Lt,
+            '>' => Token::Gt,
+            '&' if iter.peek() == Some(&'&') => {
+                iter.next();
+                Token::And
+            }
+            '|' if iter.peek() == Some(&'|') => {
+                iter.next();
+                Token::Or
+            }
+            '0'..='9' => {
+                let mut value = String::from(ch);
+                while let Some(ch) = iter.peek().filter(|ch| ch.is_ascii_digit()) {
+                    value.push(*ch);
+                    iter.next();
+                }
+                Token::Number(value.parse().unwrap_or(u64::MAX))
+            }
+            ch if ch.is_alphanumeric() || ['$', '_', '.', '-', '@', '/'].contains(&ch) => {
+                let mut value = String::from(ch);
+                while let Some(ch) = iter.peek().filter(|ch| {
+                    ch.is_alphanumeric() || ['$', '_', '.', '-', '@', '/', '{', '}'].contains(ch)
+                }) {
+                    value.push(*ch);
+                    iter.next();
+                }
+                Token::Word(value)
+            }
+            ch => Token::Other(ch),
+        };
+        tokens.push((
+            line_num - usize::from(token == Token::Eol && ch == '\n'),
+            token,
+        ));
+    }
+
+    tokens
+}
+
+fn read_regex(iter: &mut Peekable<Chars<'_>>) -> String {
+    let mut pattern = String::new();
+    let mut in_class = false;
+    while let Some(ch) = iter.next() {
+        match ch {
+            '\\' => {
+                pattern.push(ch);
+                if let Some(ch) = iter.next() {
+                    pattern.push(ch);
+                }
+            }
+            '[' => {
+                in_class = true;
+                pattern.push(ch);
+            }
+            ']' => {
+                in_class = false;
+                pattern.push(ch);
+            }
+            '/' if !in_class => break,
+            '\n' => break,
+            ch => pattern.push(ch),
+        }
+    }
+    pattern
+}
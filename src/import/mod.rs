@@ -0,0 +1,419 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{collections::BTreeSet, fmt::Display};
+
+pub mod maildrop;
+pub mod procmail;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conversion {
+    pub script: String,
+    pub untranslated: Vec<Untranslated>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Untranslated {
+    pub line_num: usize,
+    pub line: String,
+    pub reason: UntranslatedReason,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UntranslatedReason {
+    Pipe,
+    Backtick,
+    Assignment,
+    Include,
+    UnsupportedFlag(char),
+    UnsupportedCondition,
+    UnsupportedAction,
+    UnsupportedStatement,
+    MissingAction,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Condition {
+    Header { names: Vec<String>, pattern: String },
+    Exists(String),
+    Body(String),
+    Size { over: bool, limit: u64 },
+    Not(Box<Condition>),
+    AllOf(Vec<Condition>),
+    AnyOf(Vec<Condition>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Action {
+    FileInto(String),
+    Redirect(Vec<String>),
+    Discard,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ScriptBuilder {
+    capabilities: BTreeSet<&'static str>,
+    body: String,
+    depth: usize,
+}
+
+// Procmail's ^TO_ macro, restricted to the headers Sieve can address
+static TO_HEADERS: &[&str] = &[
+    "To",
+    "Cc",
+    "Bcc",
+    "Resent-To",
+    "Resent-Cc",
+    "Resent-Bcc",
+    "Apparently-To",
+    "Apparently-Resent-To",
+    "Original-To",
+    "Delivered-To",
+    "Envelope-To",
+    "X-Envelope-To",
+];
+
+impl ScriptBuilder {
+    pub fn require(&mut self, capability: &'static str) {
+        self.capabilities.insert(capability);
+    }
+
+    pub fn line(&mut self, text: impl AsRef<str>) {
+        for _ in 0..self.depth {
+            self.body.push_str("    ");
+        }
+        self.body.push_str(text.as_ref());
+        self.body.push('\n');
+    }
+
+    pub fn comment(&mut self, untranslated: &Untranslated) {
+        self.line(format!(
+            "# Untranslated ({}): {}",
+            untranslated.reason,
+            untranslated.line.replace(['\r', '\n'], " ")
+        ));
+    }
+
+    pub fn open_block(&mut self, text: impl AsRef<str>) {
+        self.line(format!("{} {{", text.as_ref()));
+        self.depth += 1;
+    }
+
+    pub fn close_block(&mut self) {
+        self.depth = self.depth.saturating_sub(1);
+        self.line("}");
+    }
+
+    pub fn condition(&mut self, condition: &Condition) -> String {
+        match condition {
+            Condition::Header { names, pattern } => {
+                self.require("regex");
+                format!("header :regex {} {}", string_list(names), quote(pattern))
+            }
+            Condition::Exists(name) => format!("exists {}", quote(name)),
+            Condition::Body(pattern) => {
+                self.require("body");
+                self.require("regex");
+                format!("body :text :regex {}", quote(pattern))
+            }
+            Condition::Size { over, limit } => {
+                format!("size {} {limit}", if *over { ":over" } else { ":under" })
+            }
+            Condition::Not(condition) => format!("not {}", self.condition(condition)),
+            Condition::AllOf(conditions) | Condition::AnyOf(conditions) => {
+                if conditions.len() == 1 {
+                    return self.condition(&conditions[0]);
+                }
+                let mut result = String::from(if matches!(condition, Condition::AllOf(_)) {
+                    "allof("
+                } else {
+                    "anyof("
+                });
+                for (pos, condition) in conditions.iter().enumerate() {
+                    if pos > 0 {
+                        result.push_str(", ");
+                    }
+                    result.push_str(&self.condition(condition));
+                }
+                result.push(')');
+                result
+            }
+        }
+    }
+
+    pub fn action(&mut self, action: &Action, copy: bool) {
+        let copy = if copy {
+            self.require("copy");
+            ":copy "
+        } else {
+            ""
+        };
+        match action {
+            Action::FileInto(folder) => {
+                self.require("fileinto");
+                self.line(format!("fileinto {copy}{};", quote(folder)));
+            }
+            Action::Redirect(addresses) => {
+                for address in addresses {
+                    self.line(format!("redirect {copy}{};", quote(address)));
+                }
+            }
+            Action::Discard => {
+                self.line("discard;");
+            }
+        }
+    }
+
+    pub fn build(self) -> String {
+        let mut script = String::with_capacity(self.body.len() + 64);
+        if !self.capabilities.is_empty() {
+            script.push_str("require ");
+            script.push_str(&string_list(self.capabilities.iter()));
+            script.push_str(";\n\n");
+        }
+        script.push_str(&self.body);
+        script
+    }
+}
+
+impl Condition {
+    pub fn from_header_regex(regex: &str, case_sensitive: bool) -> Option<Condition> {
+        let regex = regex.strip_prefix('^')?;
+        if regex.contains("\\/") || regex.starts_with('^') {
+            return None;
+        }
+
+        let (names, pattern) = if let Some(pattern) = regex.strip_prefix("TO_") {
+            (TO_HEADERS.iter().map(|h| h.to_string()).collect(), pattern)
+        } else if let Some(pattern) = regex
+            .strip_prefix("TO")
+            .filter(|p| !p.starts_with(|ch: char| ch.is_ascii_alphanumeric() || ch == '-'))
+        {
+            (TO_HEADERS.iter().map(|h| h.to_string()).collect(), pattern)
+        } else {
+            let (name, pattern) = regex.split_once(':')?;
+            if name.is_empty()
+                || !name
+                    .bytes()
+                    .all(|ch| ch.is_ascii_alphanumeric() || ch == b'-' || ch == b'_')
+            {
+                return None;
+            }
+            let pattern = strip_leading_space(pattern);
+            if pattern.is_empty() || pattern == ".*" {
+                return Some(Condition::Exists(name.to_string()));
+            }
+            return Some(Condition::Header {
+                names: vec![name.to_string()],
+                pattern: if let Some(pattern) = pattern.strip_prefix(".*") {
+                    with_case(pattern, case_sensitive)
+                } else {
+                    with_case(&format!("^{pattern}"), case_sensitive)
+                },
+            });
+        };
+
+        let pattern = pattern.strip_prefix(".*").unwrap_or(pattern);
+        Some(Condition::Header {
+            names,
+            pattern: with_case(pattern, case_sensitive),
+        })
+    }
+
+    pub fn from_body_regex(regex: &str, case_sensitive: bool) -> Option<Condition> {
+        if regex.contains("\\/") {
+            None
+        } else {
+            Some(Condition::Body(with_case(regex, case_sensitive)))
+        }
+    }
+
+    pub fn negate(self) -> Condition {
+        match self {
+            Condition::Not(condition) => *condition,
+            condition => Condition::Not(Box::new(condition)),
+        }
+    }
+}
+
+impl Action {
+    pub fn parse(destination: &str) -> Result<Action, UntranslatedReason> {
+        let destination = destination.trim();
+        if let Some(addresses) = destination.strip_prefix('!') {
+            let addresses = addresses
+                .split([' ', '\t', ','])
+                .filter(|a| !a.is_empty())
+                .map(|a| a.to_string())
+                .collect::<Vec<_>>();
+            if !addresses.is_empty() && addresses.iter().all(|a| !a.starts_with('-')) {
+                Ok(Action::Redirect(addresses))
+            } else {
+                Err(UntranslatedReason::UnsupportedAction)
+            }
+        } else if destination.starts_with('|') {
+            Err(UntranslatedReason::Pipe)
+        } else if destination.contains('`') {
+            Err(UntranslatedReason::Backtick)
+        } else if destination == "/dev/null" {
+            Ok(Action::Discard)
+        } else {
+            let folder = ["$MAILDIR/", "${MAILDIR}/", "$DEFAULT", "${DEFAULT}"]
+                .iter()
+                .find_map(|prefix| destination.strip_prefix(prefix))
+                .unwrap_or(destination);
+            let folder = folder.trim_end_matches('/');
+            let folder = folder.strip_prefix("./").unwrap_or(folder);
+            let folder = folder.strip_prefix('.').unwrap_or(folder);
+            if folder.is_empty() {
+                Ok(Action::FileInto("INBOX".to_string()))
+            } else if folder.contains('$') || folder.contains(char::is_whitespace) {
+                Err(UntranslatedReason::UnsupportedAction)
+            } else {
+                Ok(Action::FileInto(folder.to_string()))
+            }
+        }
+    }
+}
+
+fn strip_leading_space(mut pattern: &str) -> &str {
+    loop {
+        let prev_len = pattern.len();
+        for prefix in ["[ \t]*", "[\t ]*", "[ ]*", "\\s*", " *", " +", " ", "\t"] {
+            pattern = pattern.strip_prefix(prefix).unwrap_or(pattern);
+        }
+        if pattern.len() == prev_len {
+            return pattern;
+        }
+    }
+}
+
+fn with_case(pattern: &str, case_sensitive: bool) -> String {
+    if case_sensitive {
+        pattern.to_string()
+    } else {
+        format!("(?i){pattern}")
+    }
+}
+
+pub(crate) fn quote(text: &str) -> String {
+    let mut result = String::with_capacity(text.len() + 2);
+    result.push('"');
+    for ch in text.chars() {
+        if ['\\', '"'].contains(&ch) {
+            result.push('\\');
+        }
+        result.push(ch);
+    }
+    result.push('"');
+    result
+}
+
+fn string_list(items: impl IntoIterator<Item = impl AsRef<str>>) -> String {
+    let items = items
+        .into_iter()
+        .map(|item| quote(item.as_ref()))
+        .collect::<Vec<_>>();
+    if items.len() == 1 {
+        items.into_iter().next().unwrap()
+    } else {
+        format!("[{}]", items.join(", "))
+    }
+}
+
+impl Display for UntranslatedReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UntranslatedReason::Pipe => write!(f, "pipe to external program"),
+            UntranslatedReason::Backtick => write!(f, "backtick command substitution"),
+            UntranslatedReason::Assignment => write!(f, "variable assignment"),
+            UntranslatedReason::Include => write!(f, "include of external file"),
+            UntranslatedReason::UnsupportedFlag(flag) => write!(f, "unsupported flag {flag:?}"),
+            UntranslatedReason::UnsupportedCondition => write!(f, "unsupported condition"),
+            UntranslatedReason::UnsupportedAction => write!(f, "unsupported action"),
+            UntranslatedReason::UnsupportedStatement => write!(f, "unsupported statement"),
+            UntranslatedReason::MissingAction => write!(f, "recipe without action"),
+        }
+    }
+}
+
+impl Display for Untranslated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Line {}: {} ({})", self.line_num, self.line, self.reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use crate::Compiler;
+
+    #[test]
+    fn import_scripts() {
+        let mut test_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        test_dir.push("tests");
+        test_dir.push("import");
+        let compiler = Compiler::new();
+        let mut tests_run = 0;
+
+        for file_name in fs::read_dir(&test_dir).unwrap() {
+            let mut file_name = file_name.unwrap().path();
+            let conversion = match file_name.extension().and_then(|e| e.to_str()) {
+                Some("procmailrc") => {
+                    super::procmail::convert(&fs::read_to_string(&file_name).unwrap())
+                }
+                Some("mailfilter") => {
+                    super::maildrop::convert(&fs::read_to_string(&file_name).unwrap())
+                }
+                _ => continue,
+            };
+            println!("Importing {}", file_name.display());
+            tests_run += 1;
+
+            if let Err(err) = compiler.compile(conversion.script.as_bytes()) {
+                panic!(
+                    "Failed to compile {}: {:?}\n{}",
+                    file_name.display(),
+                    err,
+                    conversion.script
+                );
+            }
+
+            let mut result = conversion.script;
+            result.push_str("\n# Untranslated lines:\n");
+            for untranslated in &conversion.untranslated {
+                result.push_str(&format!("# {untranslated}\n"));
+            }
+
+            let ext = file_name.extension().unwrap().to_str().unwrap().to_string();
+            file_name.set_extension(format!("{ext}.sieve"));
+            let expected_result = fs::read_to_string(&file_name).unwrap_or_default();
+            if result != expected_result {
+                fs::write(file_name.with_extension("failed"), &result).unwrap();
+                panic!("Unexpected import result for {}", file_name.display());
+            }
+        }
+
+        assert!(tests_run > 0, "Did not find any tests to run.");
+    }
+}
@@ -0,0 +1,314 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use super::{Action, Condition, Conversion, ScriptBuilder, Untranslated, UntranslatedReason};
+
+struct Parser {
+    lines: Vec<(usize, String)>,
+    pos: usize,
+    untranslated: Vec<Untranslated>,
+}
+
+struct Flags {
+    header: bool,
+    body: bool,
+    case_sensitive: bool,
+    copy: bool,
+}
+
+pub fn convert(procmailrc: &str) -> Conversion {
+    let mut lines: Vec<(usize, String)> = Vec::new();
+    let mut continues = false;
+    for (line_num, line) in procmailrc.lines().enumerate() {
+        let (line, next_continues) = match line.strip_suffix('\\') {
+            Some(line) => (line, true),
+            None => (line, false),
+        };
+        match lines.last_mut() {
+            Some((_, last)) if continues => last.push_str(line.trim_start()),
+            _ => lines.push((line_num + 1, line.trim().to_string())),
+        }
+        continues = next_continues;
+    }
+
+    let mut parser = Parser {
+        lines,
+        pos: 0,
+        untranslated: Vec::new(),
+    };
+    let mut builder = ScriptBuilder::default();
+    parser.parse_block(&mut builder, false, false);
+
+    Conversion {
+        script: builder.build(),
+        untranslated: parser.untranslated,
+    }
+}
+
+impl Parser {
+    fn next_line(&mut self) -> Option<(usize, String)> {
+        while let Some((line_num, line)) = self.lines.get(self.pos) {
+            self.pos += 1;
+            if !line.is_empty() && !line.starts_with('#') {
+                return Some((*line_num, line.clone()));
+            }
+        }
+        None
+    }
+
+    fn peek_line(&mut self) -> Option<&str> {
+        while let Some((_, line)) = self.lines.get(self.pos) {
+            if line.is_empty() || line.starts_with('#') {
+                self.pos += 1;
+            } else {
+                return self.lines.get(self.pos).map(|(_, line)| line.as_str());
+            }
+        }
+        None
+    }
+
+    fn parse_block(&mut self, builder: &mut ScriptBuilder, is_nested: bool, copy: bool) {
+        while let Some((line_num, line)) = self.next_line() {
+            if line == "}" && is_nested {
+                return;
+            } else if line.starts_with(':') {
+                self.parse_recipe(builder, line_num, &line, copy);
+            } else {
+                let reason = if line.contains('`') {
+                    UntranslatedReason::Backtick
+                } else if let Some((name, _)) = line.split_once('=') {
+                    let name = name.trim();
+                    if name == "INCLUDERC" {
+                        UntranslatedReason::Include
+                    } else if !name.is_empty()
+                        && name
+                            .bytes()
+                            .all(|ch| ch.is_ascii_alphanumeric() || ch == b'_')
+                    {
+                        UntranslatedReason::Assignment
+                    } else {
+                        UntranslatedReason::UnsupportedStatement
+                    }
+                } else {
+                    UntranslatedReason::UnsupportedStatement
+                };
+                self.report(builder, line_num, line, reason);
+            }
+        }
+    }
+
+    fn parse_recipe(
+        &mut self,
+        builder: &mut ScriptBuilder,
+        line_num: usize,
+        line: &str,
+        copy: bool,
+    ) {
+        let mut errors = Vec::new();
+
+        // Parse flags
+        let mut flags = Flags {
+            header: false,
+            body: false,
+            case_sensitive: false,
+            copy,
+        };
+        let mut has_h = false;
+        let mut has_b = false;
+        let header = line[1..].trim_start_matches(|ch: char| ch.is_ascii_digit());
+        for ch in header.split(':').next().unwrap_or_default().chars() {
+            match ch {
+                'H' => flags.header = true,
+                'B' => flags.body = true,
+                'D' => flags.case_sensitive = true,
+                'c' => flags.copy = true,
+                'h' => has_h = true,
+                'b' => has_b = true,
+                'w' | 'W' | 'i' | 'r' => (),
+                ch if ch.is_whitespace() => (),
+                ch => {
+                    errors.push((
+                        line_num,
+                        line.to_string(),
+                        UntranslatedReason::UnsupportedFlag(ch),
+                    ));
+                }
+            }
+        }
+        if has_h != has_b {
+            errors.push((
+                line_num,
+                line.to_string(),
+                UntranslatedReason::UnsupportedFlag(if has_h { 'h' } else { 'b' }),
+            ));
+        }
+        if !flags.header && !flags.body {
+            flags.header = true;
+        }
+
+        // Parse conditions
+        let mut conditions = Vec::new();
+        while self.peek_line().is_some_and(|line| line.starts_with('*')) {
+            let (condition_line_num, condition) = self.next_line().unwrap();
+            match parse_condition(condition[1..].trim(), &flags) {
+                Ok(condition) => conditions.push(condition),
+                Err(reason) => {
+                    errors.push((condition_line_num, condition, reason));
+                }
+            }
+        }
+
+        // Parse action
+        let action = match self.peek_line() {
+            Some("{") => {
+                self.next_line();
+                None
+            }
+            Some("}") | None => {
+                self.report(
+                    builder,
+                    line_num,
+                    line.to_string(),
+                    UntranslatedReason::MissingAction,
+                );
+                return;
+            }
+            Some(_) => {
+                let (action_line_num, action) = self.next_line().unwrap();
+                match Action::parse(&action) {
+                    Ok(action) => Some(action),
+                    Err(reason) => {
+                        errors.push((action_line_num, action, reason));
+                        Some(Action::Discard)
+                    }
+                }
+            }
+        };
+
+        if !errors.is_empty() {
+            if action.is_none() {
+                self.skip_block();
+            }
+            for (line_num, line, reason) in errors {
+                self.report(builder, line_num, line, reason);
+            }
+            return;
+        }
+
+        let has_conditions = !conditions.is_empty();
+        if has_conditions {
+            let test = builder.condition(&Condition::AllOf(conditions));
+            builder.open_block(format!("if {test}"));
+        } else if action.is_none() {
+            // Unconditional nested block, emit its contents inline
+            self.parse_block(builder, true, flags.copy);
+            return;
+        }
+        if let Some(action) = action {
+            builder.action(&action, flags.copy);
+            if !flags.copy {
+                builder.line("stop;");
+            }
+        } else {
+            self.parse_block(builder, true, flags.copy);
+        }
+        if has_conditions {
+            builder.close_block();
+        }
+    }
+
+    fn skip_block(&mut self) {
+        let mut depth = 1;
+        while let Some((_, line)) = self.next_line() {
+            if line == "{" || line.ends_with(" {") {
+                depth += 1;
+            } else if line == "}" {
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn report(
+        &mut self,
+        builder: &mut ScriptBuilder,
+        line_num: usize,
+        line: String,
+        reason: UntranslatedReason,
+    ) {
+        let untranslated = Untranslated {
+            line_num,
+            line,
+            reason,
+        };
+        builder.comment(&untranslated);
+        self.untranslated.push(untranslated);
+    }
+}
+
+fn parse_condition(condition: &str, flags: &Flags) -> Result<Condition, UntranslatedReason> {
+    if let Some(condition) = condition.strip_prefix('!') {
+        return parse_condition(condition.trim_start(), flags).map(Condition::negate);
+    }
+
+    if condition.contains('`') {
+        Err(UntranslatedReason::Backtick)
+    } else if condition.starts_with('?') {
+        Err(UntranslatedReason::Pipe)
+    } else if let Some(limit) = condition.strip_prefix('<') {
+        parse_size(limit, false)
+    } else if let Some(limit) = condition.strip_prefix('>') {
+        parse_size(limit, true)
+    } else if condition.starts_with('$')
+        || condition.contains("??")
+        || condition.starts_with(|ch: char| ch.is_ascii_digit() || ch == '-')
+    {
+        Err(UntranslatedReason::UnsupportedCondition)
+    } else {
+        let header = if flags.header {
+            Condition::from_header_regex(condition, flags.case_sensitive)
+        } else {
+            None
+        };
+        let body = if flags.body {
+            Condition::from_body_regex(condition, flags.case_sensitive)
+        } else {
+            None
+        };
+        match (header, body) {
+            (Some(header), Some(body)) => Ok(Condition::AnyOf(vec![header, body])),
+            (Some(condition), None) | (None, Some(condition)) => Ok(condition),
+            (None, None) => Err(UntranslatedReason::UnsupportedCondition),
+        }
+    }
+}
+
+fn parse_size(limit: &str, over: bool) -> Result<Condition, UntranslatedReason> {
+    limit
+        .trim()
+        .parse::<u64>()
+        .map(|limit| Condition::Size { over, limit })
+        .map_err(|_| UntranslatedReason::UnsupportedCondition)
+}
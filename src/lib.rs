@@ -176,6 +176,9 @@
 //!                     RuntimeError::TooManyIncludes => {
 //!                         eprintln!("Too many included scripts.");
 //!                     }
+//!                     RuntimeError::TooManyNestedCalls => {
+//!                         eprintln!("Too many nested function calls.");
+//!                     }
 //!                     RuntimeError::InvalidInstruction(instruction) => {
 //!                         eprintln!(
 //!                             "Invalid instruction {:?} found at {}:{}.",
@@ -199,6 +202,18 @@
 //!                     RuntimeError::CPULimitReached => {
 //!                         eprintln!("Script exceeded the configured CPU limit.");
 //!                     }
+//!                     RuntimeError::MemoryLimitReached => {
+//!                         eprintln!("Script exceeded the configured memory limit.");
+//!                     }
+//!                     RuntimeError::TimeLimitReached => {
+//!                         eprintln!("Script exceeded the configured time limit.");
+//!                     }
+//!                     RuntimeError::RateLimitExceeded => {
+//!                         eprintln!("Script exceeded the configured rate limit.");
+//!                     }
+//!                     RuntimeError::PolicyViolation(violation) => {
+//!                         eprintln!("Script action {:?} is not allowed.", violation);
+//!                     }
 //!                 }
 //!                 input = true.into();
 //!             }
@@ -264,19 +279,39 @@
 //! Copyright (C) 2020-2023, Stalwart Labs Ltd.
 //!
 
-use std::{borrow::Cow, sync::Arc, vec::IntoIter};
+use std::{
+    borrow::Cow,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+    vec::IntoIter,
+};
 
 use ahash::{AHashMap, AHashSet};
+use chrono_tz::Tz;
 use compiler::grammar::{
     actions::action_redirect::{ByTime, Notify, Ret},
     instruction::Instruction,
     Capability,
 };
 use mail_parser::{HeaderName, Message};
-use runtime::{context::ScriptStack, Variable};
+use runtime::{
+    context::ScriptStack,
+    notify::{NotifyMethod, NotifyPayload},
+    policy::{Policy, PolicyMode, PolicyViolation},
+    ratelimit::{RateLimit, RateLimitMode, RateLimiter},
+    srs::Srs,
+    store::KeyValueStore,
+    template::{Localized, NotifyTemplate, VacationTemplate},
+    Variable,
+};
 use serde::{Deserialize, Serialize};
 
 pub mod compiler;
+pub mod import;
+pub mod maildir;
+#[cfg(feature = "milter")]
+pub mod milter;
 pub mod runtime;
 
 pub(crate) const MAX_MATCH_VARIABLES: usize = 63;
@@ -287,6 +322,7 @@ pub struct Sieve {
     instructions: Vec<Instruction>,
     num_vars: usize,
     num_match_vars: usize,
+    message_refs: Vec<usize>,
 }
 
 #[derive(Clone)]
@@ -303,6 +339,7 @@ pub struct Compiler {
     pub(crate) max_header_size: usize,
     pub(crate) max_includes: usize,
     pub(crate) no_capability_check: bool,
+    pub(crate) notify_schemes: AHashSet<String>,
 
     // Functions
     pub(crate) functions: AHashMap<String, (u32, u32)>,
@@ -320,8 +357,21 @@ pub struct FunctionMap {
 pub struct Runtime {
     pub(crate) allowed_capabilities: AHashSet<Capability>,
     pub(crate) valid_notification_uris: AHashSet<Cow<'static, str>>,
+    pub(crate) notify_methods: AHashMap<String, Arc<dyn NotifyMethod>>,
     pub(crate) valid_ext_lists: AHashSet<Cow<'static, str>>,
     pub(crate) protected_headers: Vec<HeaderName<'static>>,
+    pub(crate) trusted_authserv_ids: AHashSet<String>,
+    pub(crate) trusted_relays: AHashSet<String>,
+    pub(crate) srs: Option<Srs>,
+    pub(crate) store: Option<Arc<dyn KeyValueStore>>,
+    pub(crate) rate_limiter: Arc<dyn RateLimiter>,
+    pub(crate) send_rate_limit: Option<RateLimit>,
+    pub(crate) notify_rate_limit: Option<RateLimit>,
+    pub(crate) rate_limit_mode: RateLimitMode,
+    pub(crate) policy: Option<Arc<dyn Policy>>,
+    pub(crate) policy_mode: PolicyMode,
+    pub(crate) reject_reports: bool,
+    pub(crate) reject_status: Cow<'static, str>,
     pub(crate) environment: AHashMap<Cow<'static, str>, Variable>,
     pub(crate) metadata: Vec<(Metadata<String>, Cow<'static, str>)>,
     pub(crate) include_scripts: AHashMap<String, Arc<Sieve>>,
@@ -330,6 +380,8 @@ pub struct Runtime {
 
     pub(crate) max_nested_includes: usize,
     pub(crate) cpu_limit: usize,
+    pub(crate) memory_limit: usize,
+    pub(crate) time_limit: Option<Duration>,
     pub(crate) max_variable_size: usize,
     pub(crate) max_redirects: usize,
     pub(crate) max_received_headers: usize,
@@ -338,10 +390,14 @@ pub struct Runtime {
 
     pub(crate) default_vacation_expiry: u64,
     pub(crate) default_duplicate_expiry: u64,
+    pub(crate) default_timezone: Tz,
 
     pub(crate) vacation_use_orig_rcpt: bool,
     pub(crate) vacation_default_subject: Cow<'static, str>,
     pub(crate) vacation_subject_prefix: Cow<'static, str>,
+    pub(crate) vacation_templates: Localized<VacationTemplate>,
+    pub(crate) notify_templates: Localized<NotifyTemplate>,
+    pub(crate) domain_languages: AHashMap<String, String>,
 }
 
 #[derive(Clone, Debug)]
@@ -352,10 +408,12 @@ pub struct Context<'x> {
     pub(crate) runtime: &'x Runtime,
     pub(crate) user_address: Cow<'x, str>,
     pub(crate) user_full_name: Cow<'x, str>,
+    pub(crate) vacation_return_date: Cow<'x, str>,
     pub(crate) current_time: i64,
 
     pub(crate) message: Message<'x>,
     pub(crate) message_size: usize,
+    pub(crate) pending_message: Option<&'x [u8]>,
     pub(crate) envelope: Vec<(Envelope, Variable)>,
     pub(crate) metadata: Vec<(Metadata<String>, Cow<'x, str>)>,
 
@@ -383,12 +441,23 @@ pub struct Context<'x> {
     pub(crate) main_message_id: usize,
 
     pub(crate) has_changes: bool,
+    pub(crate) body_changed: bool,
+    pub(crate) original_headers: Option<Vec<u8>>,
+    pub(crate) message_checksum: Option<[u8; 20]>,
     pub(crate) num_redirects: usize,
     pub(crate) num_instructions: usize,
+    pub(crate) memory_used: usize,
+    pub(crate) deadline: Option<Instant>,
     pub(crate) num_out_messages: usize,
+    pub(crate) policy_violations: Vec<PolicyViolation>,
+
+    pub(crate) denied_capabilities: AHashSet<Capability>,
+    pub(crate) chain_pending: bool,
+    pub(crate) has_stopped: bool,
+    pub(crate) final_event_set: bool,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Script {
     Personal(String),
     Global(String),
@@ -414,7 +483,7 @@ pub enum Metadata<T> {
     Mailbox { name: T, annotation: T },
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Event {
     IncludeScript {
         name: Script,
@@ -463,6 +532,7 @@ pub enum Event {
     },
     SendMessage {
         recipient: Recipient,
+        sender: Option<String>,
         notify: Notify,
         return_of_content: Ret,
         by_time: ByTime<i64>,
@@ -474,6 +544,7 @@ pub enum Event {
         options: Vec<String>,
         message: String,
         method: String,
+        payload: Option<Box<NotifyPayload>>,
     },
     CreatedMessage {
         message_id: usize,
@@ -492,21 +563,21 @@ pub(crate) struct FileCarbonCopy<T> {
     pub special_use: Option<T>,
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Importance {
     High,
     Normal,
     Low,
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
 pub enum MatchAs {
     Octet,
     Lowercase,
     Number,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Recipient {
     Address(String),
     List(String),
@@ -521,13 +592,13 @@ pub enum Input {
     Script { name: Script, script: Arc<Sieve> },
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Mailbox {
     Name(String),
     Id(String),
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum SpamStatus {
     Unknown,
     Ham,
@@ -535,7 +606,7 @@ pub enum SpamStatus {
     Spam,
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum VirusStatus {
     Unknown,
     Clean,
@@ -545,11 +616,66 @@ pub enum VirusStatus {
     Virus,
 }
 
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeliveryInfo {
+    pub domain: Option<String>,
+    pub host: Option<String>,
+    pub location: Option<DeliveryLocation>,
+    pub phase: Option<DeliveryPhase>,
+    pub remote_ip: Option<IpAddr>,
+    pub remote_host: Option<String>,
+    pub helo: Option<String>,
+    pub auth_user: Option<String>,
+    pub tls: Option<TlsInfo>,
+    pub queue_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum DeliveryLocation {
+    Mta,
+    Mda,
+    Ms,
+    Mua,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum DeliveryPhase {
+    Pre,
+    During,
+    Post,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsInfo {
+    pub version: String,
+    pub cipher: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageChanges {
+    pub headers: Vec<HeaderChange>,
+    pub body: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderChange {
+    Insert {
+        index: usize,
+        name: String,
+        value: String,
+    },
+    Delete {
+        name: String,
+        occurrence: usize,
+    },
+}
+
 #[cfg(test)]
 mod tests {
     use std::{
         fs,
         path::{Path, PathBuf},
+        sync::Arc,
     };
 
     use ahash::{AHashMap, AHashSet};
@@ -560,9 +686,9 @@ mod tests {
 
     use crate::{
         compiler::grammar::Capability,
-        runtime::{actions::action_mime::reset_test_boundary, Variable},
-        Compiler, Context, Envelope, Event, FunctionMap, Input, Mailbox, Recipient, Runtime,
-        SpamStatus, VirusStatus,
+        runtime::{actions::action_mime::reset_test_boundary, store::MemoryStore, Variable},
+        Compiler, Context, DeliveryInfo, DeliveryLocation, DeliveryPhase, Envelope, Event,
+        FunctionMap, Input, Mailbox, Recipient, Runtime, SpamStatus, TlsInfo, VirusStatus,
     };
 
     impl Variable {
@@ -589,8 +715,15 @@ mod tests {
             {
                 continue;
             }*/
-            println!("===== {} =====", test.display());
-            run_test(&test);
+            // Run each test with the message parsed upfront and lazily
+            for lazy in [false, true] {
+                println!(
+                    "===== {} ({}) =====",
+                    test.display(),
+                    if lazy { "lazy" } else { "eager" }
+                );
+                run_test(&test, lazy);
+            }
         }
     }
 
@@ -610,7 +743,8 @@ mod tests {
         }
     }
 
-    fn run_test(script_path: &Path) {
+    fn run_test(script_path: &Path, lazy: bool) {
+        reset_test_boundary();
         let mut fnc_map = FunctionMap::new()
             .with_function("trim", |_, v| match v.into_iter().next().unwrap() {
                 crate::runtime::Variable::String(s) => s.trim().to_string().into(),
@@ -634,13 +768,7 @@ mod tests {
                     .all(|c| c.is_uppercase())
                     .into()
             })
-            .with_function("is_ascii", |_, v| {
-                v[0].to_string()
-                    .as_ref()
-                    .chars()
-                    .any(|c| !c.is_ascii())
-                    .into()
-            })
+            .with_function("is_ascii", |_, v| (!v[0].to_string().is_ascii()).into())
             .with_function("char_count", |_, v| {
                 v[0].to_string().as_ref().chars().count().into()
             })
@@ -675,7 +803,7 @@ mod tests {
                 "in_array",
                 |_, v| {
                     v[0].as_array()
-                        .map_or(false, |arr| arr.contains(&v[1]))
+                        .is_some_and(|arr| arr.contains(&v[1]))
                         .into()
                 },
                 2,
@@ -685,7 +813,8 @@ mod tests {
             .with_external_function("ext_two", 2, 2)
             .with_external_function("ext_three", 3, 3)
             .with_external_function("ext_true", 4, 0)
-            .with_external_function("ext_false", 5, 0);
+            .with_external_function("ext_false", 5, 0)
+            .with_external_function("ext_reputation", 6, 1);
         let mut compiler = Compiler::new()
             .with_max_string_size(10240)
             .register_functions(&mut fnc_map);
@@ -704,16 +833,23 @@ mod tests {
         let mut mailboxes = Vec::new();
         let mut lists: AHashMap<String, AHashSet<String>> = AHashMap::new();
         let mut duplicated_ids = AHashSet::new();
+        let mut delivery_info = DeliveryInfo::default();
         let mut actions = Vec::new();
+        let store = Arc::new(MemoryStore::new());
 
         'outer: loop {
             let runtime = Runtime::new()
                 .with_protected_header("Auto-Submitted")
                 .with_protected_header("Received")
-                .with_valid_notification_uri("mailto")
+                .with_valid_notification_uris(["mailto", "xmpp", "tel", "sms", "https"])
                 .with_max_out_messages(100)
                 .with_capability(Capability::While)
                 .with_capability(Capability::Expressions)
+                .with_capability(Capability::AuthResults)
+                .with_capability(Capability::Store)
+                .with_capability(Capability::RateLimit)
+                .with_capability(Capability::Functions)
+                .with_store(store.clone())
                 .with_functions(&mut fnc_map.clone());
             let mut instance = Context::new(
                 &runtime,
@@ -732,25 +868,32 @@ mod tests {
                 },
             );
             let raw_message = raw_message_.take().unwrap_or_default();
-            instance.message =
-                MessageParser::new()
-                    .parse(&raw_message)
-                    .unwrap_or_else(|| Message {
-                        html_body: vec![],
-                        text_body: vec![],
-                        attachments: vec![],
-                        parts: vec![MessagePart {
-                            headers: vec![],
-                            is_encoding_problem: false,
-                            body: PartType::Text("".into()),
-                            encoding: Encoding::None,
-                            offset_header: 0,
-                            offset_body: 0,
-                            offset_end: 0,
-                        }],
-                        raw_message: b""[..].into(),
-                    });
+            let parser = MessageParser::new();
+            instance.message = if lazy {
+                parser.parse_headers(&raw_message[..])
+            } else {
+                parser.parse(&raw_message[..])
+            }
+            .unwrap_or_else(|| Message {
+                html_body: vec![],
+                text_body: vec![],
+                attachments: vec![],
+                parts: vec![MessagePart {
+                    headers: vec![],
+                    is_encoding_problem: false,
+                    body: PartType::Text("".into()),
+                    encoding: Encoding::None,
+                    offset_header: 0,
+                    offset_body: 0,
+                    offset_end: 0,
+                }],
+                raw_message: b""[..].into(),
+            });
+            if lazy {
+                instance.pending_message = Some(&raw_message[..]);
+            }
             instance.message_size = raw_message.len();
+            instance.set_delivery_info(&delivery_info);
             if let Some((pos, script_cache, script_stack, vars_global, vars_local, vars_match)) =
                 prev_state.take()
             {
@@ -947,6 +1090,7 @@ mod tests {
                                 "test_assert_message" => {
                                     let expected_message =
                                         params.first().expect("test_set parameter");
+                                    instance.parse_message();
                                     let built_message = instance.build_message();
                                     if expected_message.as_bytes() != built_message {
                                         //fs::write("_deleteme.json", serde_json::to_string_pretty(&Message::parse(&built_message).unwrap()).unwrap()).unwrap();
@@ -1025,6 +1169,73 @@ mod tests {
                                                 value.parse().unwrap(),
                                             ));
                                         }
+                                        "sieve_delivery_domain" => {
+                                            delivery_info.domain = value.into();
+                                            instance.set_delivery_info(&delivery_info);
+                                        }
+                                        "sieve_delivery_host" => {
+                                            delivery_info.host = value.into();
+                                            instance.set_delivery_info(&delivery_info);
+                                        }
+                                        "sieve_delivery_location" => {
+                                            delivery_info.location = match value.as_str() {
+                                                "MTA" => DeliveryLocation::Mta,
+                                                "MDA" => DeliveryLocation::Mda,
+                                                "MS" => DeliveryLocation::Ms,
+                                                _ => DeliveryLocation::Mua,
+                                            }
+                                            .into();
+                                            instance.set_delivery_info(&delivery_info);
+                                        }
+                                        "sieve_delivery_phase" => {
+                                            delivery_info.phase = match value.as_str() {
+                                                "pre" => DeliveryPhase::Pre,
+                                                "during" => DeliveryPhase::During,
+                                                _ => DeliveryPhase::Post,
+                                            }
+                                            .into();
+                                            instance.set_delivery_info(&delivery_info);
+                                        }
+                                        "sieve_delivery_remote_ip" => {
+                                            delivery_info.remote_ip = value.parse().ok();
+                                            instance.set_delivery_info(&delivery_info);
+                                        }
+                                        "sieve_delivery_remote_host" => {
+                                            delivery_info.remote_host = value.into();
+                                            instance.set_delivery_info(&delivery_info);
+                                        }
+                                        "sieve_delivery_helo" => {
+                                            delivery_info.helo = value.into();
+                                            instance.set_delivery_info(&delivery_info);
+                                        }
+                                        "sieve_delivery_auth_user" => {
+                                            delivery_info.auth_user = value.into();
+                                            instance.set_delivery_info(&delivery_info);
+                                        }
+                                        "sieve_delivery_tls" => {
+                                            let (version, cipher) = value.split_once(' ').unwrap();
+                                            delivery_info.tls = TlsInfo {
+                                                version: version.to_string(),
+                                                cipher: cipher.to_string(),
+                                            }
+                                            .into();
+                                            instance.set_delivery_info(&delivery_info);
+                                        }
+                                        "sieve_delivery_queue_id" => {
+                                            delivery_info.queue_id = value.into();
+                                            instance.set_delivery_info(&delivery_info);
+                                        }
+                                        "sieve_trusted_authserv_id" => {
+                                            instance.runtime.set_trusted_authserv_id(value);
+                                        }
+                                        "sieve_trusted_relay" => {
+                                            instance.runtime.set_trusted_relay(value);
+                                        }
+                                        "sieve_default_timezone" => {
+                                            instance
+                                                .runtime
+                                                .set_default_timezone(value.parse().unwrap());
+                                        }
                                         "sieve_editheader_max_header_size" => {
                                             let mhs = if !value.is_empty() {
                                                 value.parse::<usize>().unwrap()
@@ -1041,6 +1252,11 @@ mod tests {
                                                 3
                                             });
                                         }
+                                        "sieve_function_max_nesting_depth" => {
+                                            instance
+                                                .runtime
+                                                .set_max_nested_includes(value.parse().unwrap());
+                                        }
                                         "sieve_include_max_nesting_depth" => {
                                             compiler.set_max_nested_blocks(if !value.is_empty() {
                                                 value.parse::<usize>().unwrap()
@@ -1175,6 +1391,12 @@ mod tests {
                                 )),
                                 4 => true.into(),
                                 5 => false.into(),
+                                6 => std::collections::BTreeMap::from_iter([
+                                    ("sender".to_string(), arguments[0].clone()),
+                                    ("score".to_string(), Variable::Float(7.5)),
+                                    ("category".to_string(), Variable::from("spam")),
+                                ])
+                                .into(),
                                 _ => {
                                     panic!("Unknown external function {id}");
                                 }
@@ -0,0 +1,546 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::SystemTime,
+};
+
+use ahash::AHashMap;
+
+use crate::{Event, Input, Mailbox};
+
+/*
+  Reference Maildir++ delivery backend.
+
+  Folders are stored as `.Folder.Sub` directories below the INBOX, flags are
+  encoded in the `:2,` info suffix and keywords are mapped to the letters
+  listed in each folder's `dovecot-keywords` file. Special-use attributes and
+  mailbox ids are read from the `sieve-special-use` and `sieve-mailbox-id`
+  files of each folder.
+*/
+
+const KEYWORDS_FILE: &str = "dovecot-keywords";
+const SPECIAL_USE_FILE: &str = "sieve-special-use";
+const MAILBOX_ID_FILE: &str = "sieve-mailbox-id";
+const MAX_KEYWORDS: usize = 26;
+
+static DELIVERY_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone)]
+pub struct Maildir {
+    path: PathBuf,
+    hostname: String,
+}
+
+pub struct MaildirDelivery<'x> {
+    maildir: &'x Maildir,
+    message: &'x [u8],
+    messages: AHashMap<usize, Vec<u8>>,
+    delivered: Vec<PathBuf>,
+}
+
+impl Maildir {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Maildir {
+            path: path.into(),
+            hostname: "localhost".to_string(),
+        }
+    }
+
+    pub fn with_hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.hostname = hostname.into().replace('/', "\\057").replace(':', "\\072");
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Starts the delivery of a message, `message` is the raw message
+    // the filter was created with.
+    pub fn delivery<'x>(&'x self, message: &'x [u8]) -> MaildirDelivery<'x> {
+        MaildirDelivery {
+            maildir: self,
+            message,
+            messages: AHashMap::new(),
+            delivered: Vec::new(),
+        }
+    }
+
+    // Returns the directory of a folder, None if the name is not valid.
+    pub fn folder_path(&self, folder: &str) -> Option<PathBuf> {
+        let folder = folder.trim();
+        if folder.is_empty() || folder.eq_ignore_ascii_case("INBOX") {
+            return Some(self.path.clone());
+        }
+        let folder = match folder.get(..6) {
+            Some(prefix)
+                if prefix.eq_ignore_ascii_case("INBOX/")
+                    || prefix.eq_ignore_ascii_case("INBOX.") =>
+            {
+                &folder[6..]
+            }
+            _ => folder,
+        };
+
+        let mut name = String::with_capacity(folder.len() + 1);
+        for component in folder.split(['/', '.']) {
+            if component.is_empty() || component.contains(['\\', '\0', '\r', '\n']) {
+                return None;
+            }
+            name.push('.');
+            name.push_str(component);
+        }
+        Some(self.path.join(name))
+    }
+
+    pub fn folder_exists(&self, folder: &str) -> bool {
+        self.folder_path(folder)
+            .is_some_and(|path| path.join("cur").is_dir())
+    }
+
+    pub fn create_folder(&self, folder: &str) -> io::Result<PathBuf> {
+        let path = self.folder_path(folder).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid folder name {folder:?}"),
+            )
+        })?;
+        for dir in ["cur", "new", "tmp"] {
+            fs::create_dir_all(path.join(dir))?;
+        }
+        if path != self.path {
+            let marker = path.join("maildirfolder");
+            if !marker.exists() {
+                fs::write(marker, b"")?;
+            }
+        }
+        Ok(path)
+    }
+
+    // Returns all folder directories, starting with the INBOX.
+    pub fn folders(&self) -> io::Result<Vec<PathBuf>> {
+        let mut folders = vec![self.path.clone()];
+        if self.path.is_dir() {
+            for entry in fs::read_dir(&self.path)? {
+                let entry = entry?;
+                if entry.file_name().to_string_lossy().starts_with('.')
+                    && entry.path().join("cur").is_dir()
+                {
+                    folders.push(entry.path());
+                }
+            }
+        }
+        folders[1..].sort();
+        Ok(folders)
+    }
+
+    pub fn special_use(&self, path: &Path) -> Vec<String> {
+        fs::read_to_string(path.join(SPECIAL_USE_FILE))
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(|attr| attr.to_string())
+            .collect()
+    }
+
+    pub fn set_special_use(&self, path: &Path, special_use: &str) -> io::Result<()> {
+        let mut attrs = self.special_use(path);
+        if !attrs
+            .iter()
+            .any(|attr| attr.eq_ignore_ascii_case(special_use))
+        {
+            attrs.push(special_use.to_string());
+            fs::write(path.join(SPECIAL_USE_FILE), attrs.join("\n") + "\n")?;
+        }
+        Ok(())
+    }
+
+    pub fn mailbox_id(&self, path: &Path) -> Option<String> {
+        fs::read_to_string(path.join(MAILBOX_ID_FILE))
+            .ok()
+            .map(|id| id.trim().to_string())
+            .filter(|id| !id.is_empty())
+    }
+
+    pub fn find_special_use(&self, special_use: &str) -> io::Result<Option<PathBuf>> {
+        for path in self.folders()? {
+            if self
+                .special_use(&path)
+                .iter()
+                .any(|attr| attr.eq_ignore_ascii_case(special_use))
+            {
+                return Ok(Some(path));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn find_mailbox_id(&self, mailbox_id: &str) -> io::Result<Option<PathBuf>> {
+        for path in self.folders()? {
+            if self.mailbox_id(&path).is_some_and(|id| id == mailbox_id) {
+                return Ok(Some(path));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn mailbox_exists(
+        &self,
+        mailboxes: &[Mailbox],
+        special_use: &[String],
+    ) -> io::Result<bool> {
+        if mailboxes.is_empty() {
+            // Any mailbox with each special-use attribute
+            for attr in special_use {
+                if self.find_special_use(attr)?.is_none() {
+                    return Ok(false);
+                }
+            }
+            return Ok(true);
+        }
+
+        for mailbox in mailboxes {
+            let path = match mailbox {
+                Mailbox::Name(name) => self
+                    .folder_path(name)
+                    .filter(|path| path.join("cur").is_dir()),
+                Mailbox::Id(id) => self.find_mailbox_id(id)?,
+            };
+            match path {
+                Some(path) => {
+                    if !special_use.is_empty() {
+                        let attrs = self.special_use(&path);
+                        if !special_use
+                            .iter()
+                            .all(|attr| attrs.iter().any(|a| a.eq_ignore_ascii_case(attr)))
+                        {
+                            return Ok(false);
+                        }
+                    }
+                }
+                None => return Ok(false),
+            }
+        }
+
+        Ok(true)
+    }
+
+    // Writes a message to a folder directory, returns the path of the new file.
+    pub fn store(&self, path: &Path, message: &[u8], flags: &[String]) -> io::Result<PathBuf> {
+        let mut info = String::new();
+        let mut keywords = Vec::new();
+        for flag in flags {
+            match flag.to_ascii_lowercase().as_str() {
+                "\\draft" => info.push('D'),
+                "\\flagged" => info.push('F'),
+                "$forwarded" => info.push('P'),
+                "\\answered" => info.push('R'),
+                "\\seen" => info.push('S'),
+                "\\deleted" => info.push('T'),
+                "\\recent" => (),
+                _ => keywords.push(flag.as_str()),
+            }
+        }
+        if !keywords.is_empty() {
+            info.extend(self.keyword_letters(path, &keywords)?);
+        }
+        let mut info = info.chars().collect::<Vec<_>>();
+        info.sort_unstable();
+        info.dedup();
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let name = format!(
+            "{}.M{}P{}Q{}.{},S={}",
+            now.as_secs(),
+            now.subsec_micros(),
+            std::process::id(),
+            DELIVERY_COUNTER.fetch_add(1, Ordering::Relaxed),
+            self.hostname,
+            message.len()
+        );
+
+        // Write to tmp/ and move into place
+        let tmp_path = path.join("tmp").join(&name);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)?;
+        file.write_all(message)?;
+        file.sync_all()?;
+
+        let final_path = if info.is_empty() {
+            path.join("new").join(name)
+        } else {
+            path.join("cur")
+                .join(format!("{name}:2,{}", info.into_iter().collect::<String>()))
+        };
+        fs::rename(&tmp_path, &final_path)?;
+        Ok(final_path)
+    }
+
+    // Maps keywords to their letters, adding missing ones to `dovecot-keywords`
+    fn keyword_letters(&self, path: &Path, keywords: &[&str]) -> io::Result<Vec<char>> {
+        let keywords_path = path.join(KEYWORDS_FILE);
+        let mut known = vec![None; MAX_KEYWORDS];
+        for line in fs::read_to_string(&keywords_path)
+            .unwrap_or_default()
+            .lines()
+        {
+            if let Some((pos, keyword)) = line.split_once(' ') {
+                if let Some(slot) = pos.parse::<usize>().ok().and_then(|pos| known.get_mut(pos)) {
+                    *slot = Some(keyword.to_string());
+                }
+            }
+        }
+
+        let mut letters = Vec::with_capacity(keywords.len());
+        let mut has_changes = false;
+        for keyword in keywords {
+            let pos = match known.iter().position(|k| k.as_deref() == Some(*keyword)) {
+                Some(pos) => pos,
+                None => match known.iter().position(|k| k.is_none()) {
+                    Some(pos) => {
+                        known[pos] = Some(keyword.to_string());
+                        has_changes = true;
+                        pos
+                    }
+                    None => continue,
+                },
+            };
+            letters.push((b'a' + pos as u8) as char);
+        }
+
+        if has_changes {
+            let mut contents = String::new();
+            for (pos, keyword) in known.iter().enumerate() {
+                if let Some(keyword) = keyword {
+                    contents.push_str(&format!("{pos} {keyword}\n"));
+                }
+            }
+            fs::write(keywords_path, contents)?;
+        }
+
+        Ok(letters)
+    }
+}
+
+impl MaildirDelivery<'_> {
+    // Handles the delivery related events, returning the input the
+    // runtime expects, or None if the event is left to the caller.
+    pub fn handle(&mut self, event: &Event) -> io::Result<Option<Input>> {
+        match event {
+            Event::Keep { flags, message_id } => {
+                let path = self.maildir.create_folder("INBOX")?;
+                self.deliver(&path, *message_id, flags)?;
+            }
+            Event::FileInto {
+                folder,
+                flags,
+                mailbox_id,
+                special_use,
+                create,
+                message_id,
+            } => {
+                let mut path = None;
+                if let Some(mailbox_id) = mailbox_id {
+                    path = self.maildir.find_mailbox_id(mailbox_id)?;
+                }
+                if let (Some(special_use), None) = (special_use, &path) {
+                    path = self.maildir.find_special_use(special_use)?;
+                }
+                if path.is_none() {
+                    if self.maildir.folder_exists(folder) {
+                        path = self.maildir.folder_path(folder);
+                    } else if *create {
+                        let folder_path = self.maildir.create_folder(folder)?;
+                        if let Some(special_use) = special_use {
+                            self.maildir.set_special_use(&folder_path, special_use)?;
+                        }
+                        path = Some(folder_path);
+                    }
+                }
+                let path = match path {
+                    Some(path) => path,
+                    // Missing folder, deliver to the INBOX
+                    None => self.maildir.create_folder("INBOX")?,
+                };
+                self.deliver(&path, *message_id, flags)?;
+            }
+            Event::MailboxExists {
+                mailboxes,
+                special_use,
+            } => {
+                return self
+                    .maildir
+                    .mailbox_exists(mailboxes, special_use)
+                    .map(|exists| Some(exists.into()));
+            }
+            Event::CreatedMessage {
+                message_id,
+                message,
+            } => {
+                self.messages.insert(*message_id, message.clone());
+            }
+            _ => return Ok(None),
+        }
+
+        Ok(Some(Input::True))
+    }
+
+    // Paths of the messages delivered so far
+    pub fn delivered(&self) -> &[PathBuf] {
+        &self.delivered
+    }
+
+    fn deliver(&mut self, path: &Path, message_id: usize, flags: &[String]) -> io::Result<()> {
+        let message = if message_id == 0 {
+            self.message
+        } else {
+            self.messages
+                .get(&message_id)
+                .map(|message| message.as_slice())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("Message {message_id} not found"),
+                    )
+                })?
+        };
+        let file = self.maildir.store(path, message, flags)?;
+        self.delivered.push(file);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use mail_parser::MessageParser;
+
+    use crate::{Compiler, Context, Input, Runtime};
+
+    use super::Maildir;
+
+    const SCRIPT: &str = r#"require ["fileinto", "mailbox", "mailboxid", "special-use", "imap4flags", "editheader"];
+if mailboxexists "Lists" {
+    fileinto :flags ["\\Seen", "$label1"] "Lists";
+}
+fileinto :create :specialuse "\\Junk" "Spam/Probable";
+if specialuse_exists "\\Junk" {
+    fileinto :specialuse "\\Junk" "Missing";
+}
+if mailboxidexists "F123" {
+    fileinto :mailboxid "F123" "Missing";
+}
+fileinto "Missing";
+addheader "X-Sieve" "delivered";
+keep :flags ["\\Flagged", "$label2", "\\Answered"];
+"#;
+
+    const MESSAGE: &str = "From: john@example.org\r\nSubject: Hello\r\n\r\nHello world\r\n";
+
+    #[test]
+    fn maildir_delivery() {
+        let root = std::env::temp_dir().join(format!("sieve-maildir-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let maildir = Maildir::new(&root).with_hostname("mx.example.org");
+        let lists = maildir.create_folder("Lists").unwrap();
+        fs::write(lists.join("sieve-mailbox-id"), "F123\n").unwrap();
+
+        let script = Compiler::new().compile(SCRIPT.as_bytes()).unwrap();
+        let runtime = Runtime::new();
+        let mut instance = Context::new(
+            &runtime,
+            MessageParser::new().parse(MESSAGE.as_bytes()).unwrap(),
+        );
+        let mut delivery = maildir.delivery(MESSAGE.as_bytes());
+        let mut input = Input::script("test", script);
+        while let Some(result) = instance.run(input) {
+            input = delivery
+                .handle(&result.unwrap())
+                .unwrap()
+                .unwrap_or(Input::True);
+        }
+
+        let delivered = delivery
+            .delivered()
+            .iter()
+            .map(|path| {
+                (
+                    path.strip_prefix(&root)
+                        .unwrap()
+                        .parent()
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned(),
+                    path.file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .rsplit_once(":2,")
+                        .map(|(_, info)| info.to_string())
+                        .unwrap_or_default(),
+                    fs::read_to_string(path).unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            delivered,
+            vec![
+                (".Lists/cur".into(), "Sa".into(), MESSAGE.into()),
+                (".Spam.Probable/new".into(), "".into(), MESSAGE.into()),
+                (".Spam.Probable/new".into(), "".into(), MESSAGE.into()),
+                (".Lists/new".into(), "".into(), MESSAGE.into()),
+                ("new".into(), "".into(), MESSAGE.into()),
+                (
+                    "cur".into(),
+                    "FRa".into(),
+                    format!("X-Sieve: delivered\r\n{MESSAGE}")
+                ),
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(root.join(".Lists").join("dovecot-keywords")).unwrap(),
+            "0 $label1\n"
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("dovecot-keywords")).unwrap(),
+            "0 $label2\n"
+        );
+        assert_eq!(
+            maildir.special_use(&root.join(".Spam.Probable")),
+            vec!["\\Junk".to_string()]
+        );
+        assert!(root.join(".Spam.Probable").join("maildirfolder").exists());
+        assert!(maildir.folder_path("../etc").is_none());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,529 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+pub mod protocol;
+
+use std::{
+    io::{self, Read, Write},
+    sync::Arc,
+};
+
+use ahash::AHashMap;
+
+use crate::{
+    runtime::parse_message, Context, DeliveryInfo, DeliveryLocation, DeliveryPhase, Envelope,
+    Event, HeaderChange, Input, Recipient, Runtime, Sieve, TlsInfo,
+};
+
+use self::protocol::*;
+
+/*
+  Runs a Sieve script at the end of DATA on behalf of an MTA speaking the
+  milter protocol. Each connection is served by `Milter::handle`, which
+  blocks until the MTA closes the session.
+*/
+
+pub struct Milter {
+    runtime: Runtime,
+    script: Arc<Sieve>,
+}
+
+#[derive(Default)]
+struct Session {
+    delivery_info: DeliveryInfo,
+    macros: AHashMap<String, String>,
+    mail_from: Option<String>,
+    rcpt_to: Vec<String>,
+    headers: Vec<u8>,
+    body: Vec<u8>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Outcome {
+    keep: bool,
+    reject: Option<String>,
+    redirects: Vec<String>,
+}
+
+impl Milter {
+    pub fn new(runtime: Runtime, script: impl Into<Arc<Sieve>>) -> Self {
+        Milter {
+            runtime,
+            script: script.into(),
+        }
+    }
+
+    pub fn handle(&self, mut stream: impl Read + Write) -> io::Result<()> {
+        let mut session = Session::default();
+
+        while let Some((command, data)) = read_packet(&mut stream)? {
+            match command {
+                SMFIC_OPTNEG => {
+                    let actions = read_u32(&data, 4)
+                        & (SMFIF_ADDHDRS
+                            | SMFIF_CHGHDRS
+                            | SMFIF_CHGBODY
+                            | SMFIF_ADDRCPT
+                            | SMFIF_DELRCPT);
+                    let mut response = Vec::with_capacity(12);
+                    response.extend_from_slice(&MILTER_VERSION.to_be_bytes());
+                    response.extend_from_slice(&actions.to_be_bytes());
+                    response.extend_from_slice(&0u32.to_be_bytes());
+                    write_packet(&mut stream, SMFIR_OPTNEG, &response)?;
+                }
+                SMFIC_MACRO => {
+                    let mut values = split_strings(data.get(1..).unwrap_or_default()).into_iter();
+                    while let (Some(name), Some(value)) = (values.next(), values.next()) {
+                        session
+                            .macros
+                            .insert(name.trim_matches(['{', '}']).to_string(), value);
+                    }
+                }
+                SMFIC_CONNECT => {
+                    let (hostname, address) = data
+                        .iter()
+                        .position(|ch| *ch == 0)
+                        .map(|pos| {
+                            (
+                                String::from_utf8_lossy(&data[..pos]).into_owned(),
+                                // Skip the family and port
+                                data.get(pos + 4..)
+                                    .and_then(|address| split_strings(address).into_iter().next()),
+                            )
+                        })
+                        .unwrap_or_default();
+                    session.delivery_info = DeliveryInfo {
+                        location: Some(DeliveryLocation::Mta),
+                        phase: Some(DeliveryPhase::During),
+                        remote_host: Some(hostname),
+                        remote_ip: address.and_then(|address| {
+                            address
+                                .trim_start_matches("IPv6:")
+                                .trim_matches(['[', ']'])
+                                .parse()
+                                .ok()
+                        }),
+                        ..Default::default()
+                    };
+                    write_packet(&mut stream, SMFIR_CONTINUE, &[])?;
+                }
+                SMFIC_HELO => {
+                    session.delivery_info.helo = split_strings(&data).into_iter().next();
+                    write_packet(&mut stream, SMFIR_CONTINUE, &[])?;
+                }
+                SMFIC_MAIL => {
+                    session.reset();
+                    session.mail_from = split_strings(&data).into_iter().next();
+                    write_packet(&mut stream, SMFIR_CONTINUE, &[])?;
+                }
+                SMFIC_RCPT => {
+                    if let Some(rcpt) = split_strings(&data).into_iter().next() {
+                        session.rcpt_to.push(rcpt);
+                    }
+                    write_packet(&mut stream, SMFIR_CONTINUE, &[])?;
+                }
+                SMFIC_HEADER => {
+                    let mut values = split_strings(&data).into_iter();
+                    if let (Some(name), Some(value)) = (values.next(), values.next()) {
+                        session.add_header(&name, &value);
+                    }
+                    write_packet(&mut stream, SMFIR_CONTINUE, &[])?;
+                }
+                SMFIC_BODY => {
+                    session.body.extend_from_slice(&data);
+                    write_packet(&mut stream, SMFIR_CONTINUE, &[])?;
+                }
+                SMFIC_BODYEOB => {
+                    session.body.extend_from_slice(&data);
+                    self.end_of_message(&mut stream, &mut session)?;
+                    session.reset();
+                }
+                SMFIC_ABORT => {
+                    session.reset();
+                }
+                SMFIC_QUIT_NC => {
+                    session = Session::default();
+                }
+                SMFIC_QUIT => {
+                    break;
+                }
+                SMFIC_DATA | SMFIC_EOH | SMFIC_UNKNOWN => {
+                    write_packet(&mut stream, SMFIR_CONTINUE, &[])?;
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Unsupported milter command {:?}", command as char),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn end_of_message(&self, stream: &mut impl Write, session: &mut Session) -> io::Result<()> {
+        let mut raw_message = std::mem::take(&mut session.headers);
+        raw_message.extend_from_slice(b"\r\n");
+        raw_message.extend_from_slice(&session.body);
+
+        let mut ctx = Context::new(&self.runtime, parse_message(&raw_message));
+        session.set_delivery_info();
+        ctx.set_delivery_info(&session.delivery_info);
+        if let Some(mail_from) = &session.mail_from {
+            ctx.set_envelope(Envelope::From, mail_from.as_str());
+        }
+        for rcpt_to in &session.rcpt_to {
+            ctx.set_envelope(Envelope::To, rcpt_to.as_str());
+        }
+
+        let outcome = match run_script(&mut ctx, self.script.clone()) {
+            Some(outcome) => outcome,
+            None => {
+                return write_packet(stream, SMFIR_TEMPFAIL, &[]);
+            }
+        };
+
+        // Reject at SMTP time, using the same reply as LMTP
+        if let Some(reason) = outcome.reject {
+            let reply = ctx.reject_reply(&reason).trim_end().replace('%', "%%");
+            return write_packet(stream, SMFIR_REPLYCODE, &encode_strings(&[&reply]));
+        } else if !outcome.keep && outcome.redirects.is_empty() {
+            return write_packet(stream, SMFIR_DISCARD, &[]);
+        }
+
+        // Message modifications
+        let changes = ctx.message_changes();
+        for change in &changes.headers {
+            match change {
+                HeaderChange::Delete { name, occurrence } => {
+                    let mut data = (*occurrence as u32).to_be_bytes().to_vec();
+                    data.extend_from_slice(&encode_strings(&[name, ""]));
+                    write_packet(stream, SMFIR_CHGHEADER, &data)?;
+                }
+                HeaderChange::Insert { index, name, value } => {
+                    let mut data = (*index as u32).to_be_bytes().to_vec();
+                    data.extend_from_slice(&encode_strings(&[name, value]));
+                    write_packet(stream, SMFIR_INSHEADER, &data)?;
+                }
+            }
+        }
+        if let Some(body) = &changes.body {
+            for chunk in body.chunks(MAX_BODY_CHUNK) {
+                write_packet(stream, SMFIR_REPLBODY, chunk)?;
+            }
+        }
+
+        // Recipient changes
+        for rcpt in &outcome.redirects {
+            write_packet(
+                stream,
+                SMFIR_ADDRCPT,
+                &encode_strings(&[&format!("<{rcpt}>")]),
+            )?;
+        }
+        if !outcome.keep {
+            for rcpt in &session.rcpt_to {
+                write_packet(stream, SMFIR_DELRCPT, &encode_strings(&[rcpt]))?;
+            }
+        }
+
+        write_packet(stream, SMFIR_CONTINUE, &[])
+    }
+}
+
+fn run_script(ctx: &mut Context, script: Arc<Sieve>) -> Option<Outcome> {
+    let mut outcome = Outcome::default();
+    let mut input = Input::script("milter", script);
+
+    while let Some(result) = ctx.run(input) {
+        input = Input::True;
+        match result.ok()? {
+            Event::IncludeScript { .. }
+            | Event::MailboxExists { .. }
+            | Event::ListContains { .. }
+            | Event::DuplicateId { .. } => {
+                input = Input::False;
+            }
+            Event::Function { .. } => {
+                input = Input::result(Default::default());
+            }
+            Event::Keep { .. } | Event::FileInto { .. } => {
+                outcome.keep = true;
+            }
+            Event::Reject { reason, .. } => {
+                outcome.reject = reason.into();
+            }
+            Event::SendMessage {
+                recipient: Recipient::Address(address),
+                message_id,
+                ..
+            } if message_id == ctx.main_message_id => {
+                outcome.redirects.push(address);
+            }
+            _ => (),
+        }
+    }
+
+    Some(outcome)
+}
+
+impl Session {
+    fn reset(&mut self) {
+        self.mail_from = None;
+        self.rcpt_to.clear();
+        self.headers.clear();
+        self.body.clear();
+    }
+
+    fn add_header(&mut self, name: &str, value: &str) {
+        self.headers.extend_from_slice(name.as_bytes());
+        self.headers.extend_from_slice(b": ");
+        // Folded values are sent with bare line feeds
+        let mut last_ch = 0;
+        for &ch in value.trim_start().as_bytes() {
+            if ch == b'\n' && last_ch != b'\r' {
+                self.headers.push(b'\r');
+            }
+            self.headers.push(ch);
+            last_ch = ch;
+        }
+        self.headers.extend_from_slice(b"\r\n");
+    }
+
+    fn set_delivery_info(&mut self) {
+        let info = &mut self.delivery_info;
+        if let Some(host) = self.macros.get("j") {
+            info.host = host.clone().into();
+        }
+        if let Some(queue_id) = self.macros.get("i") {
+            info.queue_id = queue_id.clone().into();
+        }
+        if let Some(auth_user) = self.macros.get("auth_authen") {
+            info.auth_user = auth_user.clone().into();
+        }
+        if let Some(version) = self.macros.get("tls_version") {
+            info.tls = TlsInfo {
+                version: version.clone(),
+                cipher: self.macros.get("cipher").cloned().unwrap_or_default(),
+            }
+            .into();
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{os::unix::net::UnixStream, thread};
+
+    use crate::{Compiler, Runtime};
+
+    use super::{protocol::*, Milter};
+
+    const SCRIPT: &str = r#"require ["editheader", "reject", "envelope", "environment"];
+if header :contains "subject" "spam" {
+    reject "Go away, 100% spam";
+    stop;
+}
+if header :contains "subject" "closed" {
+    reject "Mailbox closed.
+Try again later.";
+    stop;
+}
+if header :contains "subject" "discard" {
+    discard;
+    stop;
+}
+if envelope :is "to" "forward@example.org" {
+    redirect "other@example.com";
+    stop;
+}
+if environment :is "vnd.stalwart.helo" "client.example.net" {
+    addheader "X-Helo" "verified";
+}
+deleteheader "X-Remove";
+addheader :last "X-Queue-Id" "checked";
+"#;
+
+    struct FakeMta(UnixStream);
+
+    impl FakeMta {
+        fn send(&mut self, command: u8, data: &[u8]) {
+            write_packet(&mut self.0, command, data).unwrap();
+        }
+
+        fn send_strings(&mut self, command: u8, values: &[&str]) {
+            self.send(command, &encode_strings(values));
+        }
+
+        fn recv(&mut self) -> (char, Vec<u8>) {
+            let (command, data) = read_packet(&mut self.0).unwrap().unwrap();
+            (command as char, data)
+        }
+
+        fn expect_continue(&mut self) {
+            assert_eq!(self.recv(), ('c', vec![]));
+        }
+
+        fn send_message(&mut self, rcpt: &str, headers: &[(&str, &str)], body: &str) {
+            self.send_strings(SMFIC_MAIL, &["<sender@example.net>", "SIZE=100"]);
+            self.expect_continue();
+            self.send_strings(SMFIC_RCPT, &[rcpt]);
+            self.expect_continue();
+            self.send(SMFIC_DATA, &[]);
+            self.expect_continue();
+            for (name, value) in headers {
+                self.send_strings(SMFIC_HEADER, &[name, value]);
+                self.expect_continue();
+            }
+            self.send(SMFIC_EOH, &[]);
+            self.expect_continue();
+            self.send(SMFIC_BODY, body.as_bytes());
+            self.expect_continue();
+            self.send(SMFIC_BODYEOB, &[]);
+        }
+
+        fn responses(&mut self) -> Vec<(char, Vec<u8>)> {
+            let mut responses = Vec::new();
+            loop {
+                let response = self.recv();
+                let is_final = "cdyta".contains(response.0);
+                responses.push(response);
+                if is_final {
+                    return responses;
+                }
+            }
+        }
+    }
+
+    fn header_response(command: char, index: u32, name: &str, value: &str) -> (char, Vec<u8>) {
+        let mut data = index.to_be_bytes().to_vec();
+        data.extend_from_slice(&encode_strings(&[name, value]));
+        (command, data)
+    }
+
+    #[test]
+    fn milter_session() {
+        let script = Compiler::new().compile(SCRIPT.as_bytes()).unwrap();
+        let milter = Milter::new(Runtime::new().with_reject_status("554 5.7.0"), script);
+        let (mta, filter) = UnixStream::pair().unwrap();
+        let handle = thread::spawn(move || milter.handle(filter));
+        let mut mta = FakeMta(mta);
+
+        // Negotiate options
+        let mut optneg = Vec::new();
+        optneg.extend_from_slice(&6u32.to_be_bytes());
+        optneg.extend_from_slice(&0x1ffu32.to_be_bytes());
+        optneg.extend_from_slice(&0x1fffffu32.to_be_bytes());
+        mta.send(SMFIC_OPTNEG, &optneg);
+        let (command, data) = mta.recv();
+        assert_eq!(command, 'O');
+        assert_eq!(read_u32(&data, 0), 6);
+        assert_eq!(
+            read_u32(&data, 4),
+            SMFIF_ADDHDRS | SMFIF_CHGHDRS | SMFIF_CHGBODY | SMFIF_ADDRCPT | SMFIF_DELRCPT
+        );
+
+        // Connection
+        mta.send_strings(SMFIC_MACRO, &["Cj", "mx.example.org"]);
+        let mut connect = b"mail.example.net\x004\x00\x19".to_vec();
+        connect.extend_from_slice(b"192.0.2.1\x00");
+        mta.send(SMFIC_CONNECT, &connect);
+        mta.expect_continue();
+        mta.send_strings(SMFIC_HELO, &["client.example.net"]);
+        mta.expect_continue();
+
+        // Header modifications
+        mta.send_message(
+            "<user@example.org>",
+            &[
+                ("From", "sender@example.net"),
+                ("X-Remove", "yes"),
+                ("Subject", "Hello"),
+            ],
+            "Hello world\r\n",
+        );
+        assert_eq!(
+            mta.responses(),
+            vec![
+                header_response('m', 1, "X-Remove", ""),
+                header_response('i', 0, "X-Helo", "verified"),
+                header_response('i', 3, "X-Queue-Id", "checked"),
+                ('c', vec![]),
+            ]
+        );
+
+        // Reject
+        mta.send_message(
+            "<user@example.org>",
+            &[("Subject", "Buy spam")],
+            "Hello\r\n",
+        );
+        assert_eq!(
+            mta.responses(),
+            vec![('y', encode_strings(&["554 5.7.0 Go away, 100%% spam"]))]
+        );
+        mta.send_message(
+            "<user@example.org>",
+            &[("Subject", "Mailbox closed")],
+            "Hello\r\n",
+        );
+        assert_eq!(
+            mta.responses(),
+            vec![(
+                'y',
+                encode_strings(&["554-5.7.0 Mailbox closed.\r\n554 5.7.0 Try again later."])
+            )]
+        );
+
+        // Discard
+        mta.send_message(
+            "<user@example.org>",
+            &[("Subject", "Please discard")],
+            "Hello\r\n",
+        );
+        assert_eq!(mta.responses(), vec![('d', vec![])]);
+
+        // Redirect
+        mta.send_message(
+            "<forward@example.org>",
+            &[("Subject", "Forward me")],
+            "Hello\r\n",
+        );
+        assert_eq!(
+            mta.responses(),
+            vec![
+                ('+', encode_strings(&["<other@example.com>"])),
+                ('-', encode_strings(&["<forward@example.org>"])),
+                ('c', vec![]),
+            ]
+        );
+
+        // Aborted transaction followed by quit
+        mta.send_strings(SMFIC_MAIL, &["<sender@example.net>"]);
+        mta.expect_continue();
+        mta.send(SMFIC_ABORT, &[]);
+        mta.send(SMFIC_QUIT, &[]);
+        handle.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::io::{self, Read, Write};
+
+/*
+  Sendmail milter protocol version 6
+*/
+
+pub const MILTER_VERSION: u32 = 6;
+pub const MAX_PACKET_SIZE: usize = 16 * 1024 * 1024;
+pub const MAX_BODY_CHUNK: usize = 65535;
+
+// Commands sent by the MTA
+pub const SMFIC_ABORT: u8 = b'A';
+pub const SMFIC_BODY: u8 = b'B';
+pub const SMFIC_CONNECT: u8 = b'C';
+pub const SMFIC_MACRO: u8 = b'D';
+pub const SMFIC_BODYEOB: u8 = b'E';
+pub const SMFIC_HELO: u8 = b'H';
+pub const SMFIC_QUIT_NC: u8 = b'K';
+pub const SMFIC_HEADER: u8 = b'L';
+pub const SMFIC_MAIL: u8 = b'M';
+pub const SMFIC_EOH: u8 = b'N';
+pub const SMFIC_OPTNEG: u8 = b'O';
+pub const SMFIC_QUIT: u8 = b'Q';
+pub const SMFIC_RCPT: u8 = b'R';
+pub const SMFIC_DATA: u8 = b'T';
+pub const SMFIC_UNKNOWN: u8 = b'U';
+
+// Responses sent by the filter
+pub const SMFIR_ADDRCPT: u8 = b'+';
+pub const SMFIR_DELRCPT: u8 = b'-';
+pub const SMFIR_REPLBODY: u8 = b'b';
+pub const SMFIR_CONTINUE: u8 = b'c';
+pub const SMFIR_DISCARD: u8 = b'd';
+pub const SMFIR_INSHEADER: u8 = b'i';
+pub const SMFIR_CHGHEADER: u8 = b'm';
+pub const SMFIR_TEMPFAIL: u8 = b't';
+pub const SMFIR_REPLYCODE: u8 = b'y';
+pub const SMFIR_OPTNEG: u8 = b'O';
+
+// Actions requested by the filter
+pub const SMFIF_ADDHDRS: u32 = 0x01;
+pub const SMFIF_CHGBODY: u32 = 0x02;
+pub const SMFIF_ADDRCPT: u32 = 0x04;
+pub const SMFIF_DELRCPT: u32 = 0x08;
+pub const SMFIF_CHGHDRS: u32 = 0x10;
+
+pub fn read_packet(reader: &mut impl Read) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(_) => (),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 || len > MAX_PACKET_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid milter packet length {len}"),
+        ));
+    }
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data)?;
+    let command = data.remove(0);
+    Ok(Some((command, data)))
+}
+
+pub fn write_packet(writer: &mut impl Write, command: u8, data: &[u8]) -> io::Result<()> {
+    writer.write_all(&(data.len() as u32 + 1).to_be_bytes())?;
+    writer.write_all(&[command])?;
+    writer.write_all(data)?;
+    writer.flush()
+}
+
+// Splits a packet into its NUL terminated strings
+pub fn split_strings(data: &[u8]) -> Vec<String> {
+    let data = data.strip_suffix(&[0]).unwrap_or(data);
+    if !data.is_empty() {
+        data.split(|ch| *ch == 0)
+            .map(|value| String::from_utf8_lossy(value).into_owned())
+            .collect()
+    } else {
+        Vec::new()
+    }
+}
+
+pub fn read_u32(data: &[u8], pos: usize) -> u32 {
+    data.get(pos..pos + 4)
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+        .unwrap_or(0)
+}
+
+pub fn encode_strings(values: &[&str]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for value in values {
+        data.extend_from_slice(value.as_bytes());
+        data.push(0);
+    }
+    data
+}
@@ -80,7 +80,7 @@ impl Convert {
                     if part
                         .content_type()
                         .and_then(|ct| ct.c_subtype.as_ref())
-                        .map_or(false, |st| st.eq_ignore_ascii_case("plain")) =>
+                        .is_some_and(|st| st.eq_ignore_ascii_case("plain")) =>
                 {
                     (
                         PartType::Html(text_to_html(text.as_ref()).into()),
@@ -112,6 +112,7 @@ impl Convert {
 
         if did_convert {
             ctx.has_changes = true;
+            ctx.body_changed = true;
         }
 
         TestResult::Bool(did_convert ^ self.is_not)
@@ -143,6 +143,9 @@ impl DeleteHeader {
         if !deleted_headers.is_empty() {
             ctx.has_changes = true;
             for (part_id, header_pos) in deleted_headers.iter().rev() {
+                if *part_id != 0 {
+                    ctx.body_changed = true;
+                }
                 ctx.message.parts[*part_id].headers.remove(*header_pos);
             }
         }
@@ -180,6 +183,9 @@ impl<'x> Context<'x> {
         last: bool,
     ) {
         let header_value = header_value.into();
+        if part_id != 0 {
+            self.body_changed = true;
+        }
         self.message_size += header_name.len() + header_value.len() + 4;
         let header = Header {
             name: header_name,
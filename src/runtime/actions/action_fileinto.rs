@@ -21,11 +21,19 @@
  * for more details.
 */
 
-use crate::{compiler::grammar::actions::action_fileinto::FileInto, Context, Event};
+use crate::{
+    compiler::{grammar::actions::action_fileinto::FileInto, Value},
+    runtime::{policy::PolicyAction, RuntimeError},
+    Context, Event, FileCarbonCopy,
+};
 
 impl FileInto {
-    pub(crate) fn exec(&self, ctx: &mut Context) {
+    pub(crate) fn exec(&self, ctx: &mut Context) -> Result<(), RuntimeError> {
         let folder = ctx.eval_value(&self.folder).to_string().into_owned();
+        if !ctx.check_policy(PolicyAction::FileInto, &folder)? {
+            return Ok(());
+        }
+
         let mut events = Vec::with_capacity(2);
         if let Some(event) = ctx.build_message_id() {
             events.push(event);
@@ -53,5 +61,37 @@ impl FileInto {
         });
 
         ctx.queued_events = events.into_iter();
+
+        Ok(())
+    }
+}
+
+impl Context<'_> {
+    // Returns the FileInto event for a file carbon copy of the given message,
+    // or None if the policy does not allow filing into the mailbox.
+    pub(crate) fn fcc_event(
+        &mut self,
+        fcc: &FileCarbonCopy<Value>,
+        message_id: usize,
+    ) -> Result<Option<Event>, RuntimeError> {
+        let folder = self.eval_value(&fcc.mailbox).to_string().into_owned();
+        if !self.check_policy(PolicyAction::FileInto, &folder)? {
+            return Ok(None);
+        }
+
+        Ok(Some(Event::FileInto {
+            folder,
+            flags: self.get_local_flags(&fcc.flags),
+            mailbox_id: fcc
+                .mailbox_id
+                .as_ref()
+                .map(|m| self.eval_value(m).to_string().into_owned()),
+            special_use: fcc
+                .special_use
+                .as_ref()
+                .map(|s| self.eval_value(s).to_string().into_owned()),
+            create: fcc.create,
+            message_id,
+        }))
     }
 }
@@ -0,0 +1,186 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use crate::{
+    runtime::{context::ScriptStack, RuntimeError, Variable},
+    Context,
+};
+
+impl Context<'_> {
+    // Enters a script function, the caller's locals and partially evaluated
+    // expression are kept on the stack until the function returns.
+    pub(crate) fn call_function(
+        &mut self,
+        pos: usize,
+        arguments: Vec<Variable>,
+    ) -> Result<(), RuntimeError> {
+        // Calls share the nesting limit with includes
+        let script = match self.script_stack.last() {
+            Some(stack) if self.script_stack.len() < self.runtime.max_nested_includes => {
+                stack.script.clone()
+            }
+            _ => return Err(RuntimeError::TooManyNestedCalls),
+        };
+
+        let mut vars_local = vec![Variable::default(); script.num_vars];
+        for (var, argument) in vars_local.iter_mut().zip(arguments) {
+            *var = argument;
+        }
+        let vars_match = vec![Variable::default(); script.num_match_vars];
+
+        self.script_stack.push(ScriptStack {
+            script,
+            prev_pos: self.pos - 1,
+            prev_vars_local: std::mem::replace(&mut self.vars_local, vars_local),
+            prev_vars_match: std::mem::replace(&mut self.vars_match, vars_match),
+            prev_expr: Some((std::mem::take(&mut self.expr_stack), self.expr_pos)),
+        });
+        self.expr_pos = 0;
+        self.pos = pos;
+
+        Ok(())
+    }
+
+    // Resumes the caller's expression with the returned value
+    pub(crate) fn return_function(&mut self, value: Variable) {
+        if let Some(stack) = self.script_stack.pop() {
+            debug_assert!(stack.prev_expr.is_some(), "Return outside of a function.");
+            self.pos = stack.prev_pos;
+            self.vars_local = stack.prev_vars_local;
+            self.vars_match = stack.prev_vars_match;
+            if let Some((expr_stack, expr_pos)) = stack.prev_expr {
+                self.expr_stack = expr_stack;
+                self.expr_pos = expr_pos;
+            }
+            self.expr_stack.push(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::MessageParser;
+
+    use crate::{
+        compiler::{grammar::Capability, ErrorType},
+        runtime::RuntimeError,
+        Compiler, Context, Event, Runtime,
+    };
+
+    fn run_script(runtime: &Runtime, script: &str) -> Result<Vec<Event>, RuntimeError> {
+        let (events, error) = Context::new(
+            runtime,
+            MessageParser::new()
+                .parse(b"Subject: hi\r\n\r\nhi\r\n")
+                .unwrap(),
+        )
+        .run_script(script);
+        error.map_or(Ok(events), Err)
+    }
+
+    #[test]
+    fn nesting_limit() {
+        let script = concat!(
+            "require [\"vnd.stalwart.functions\", \"vnd.stalwart.expressions\", \"fileinto\"];\n",
+            "function \"depth\" [\"n\"] {\n",
+            "  if eval \"n <= 0\" { return \"0\"; }\n",
+            "  return \"1 + depth(n - 1)\";\n",
+            "}\n",
+            "if eval \"depth(${1}) == ${1}\" { fileinto \"Depth\"; }\n",
+        );
+        let runtime = Runtime::new()
+            .with_capability(Capability::Functions)
+            .with_capability(Capability::Expressions);
+
+        // The top-level script takes one frame of the default limit of 3
+        let events = run_script(&runtime, &script.replace("${1}", "1")).unwrap();
+        assert!(matches!(&events[0], Event::FileInto { folder, .. } if folder == "Depth"));
+        assert!(matches!(
+            run_script(&runtime, &script.replace("${1}", "2")),
+            Err(RuntimeError::TooManyNestedCalls)
+        ));
+
+        let runtime = runtime.with_max_nested_includes(10);
+        let events = run_script(&runtime, &script.replace("${1}", "8")).unwrap();
+        assert!(matches!(&events[0], Event::FileInto { folder, .. } if folder == "Depth"));
+    }
+
+    #[test]
+    fn compile_errors() {
+        let header = "require [\"vnd.stalwart.functions\", \"vnd.stalwart.expressions\"];\n";
+        for (script, expected) in [
+            (
+                "function \"add\" [\"a\", \"b\"] { return \"a + b\"; }\nif eval \"add(1) == 1\" {}",
+                None,
+            ),
+            (
+                "function \"is_set\" [\"a\"] { return \"a\"; }\nif is_set \"x\" \"y\" {}",
+                None,
+            ),
+            (
+                "if true { function \"inner\" { return; } }",
+                Some(ErrorType::FunctionNotTopLevel),
+            ),
+            (
+                "function \"twice\" { return; }\nfunction \"twice\" { return; }",
+                Some(ErrorType::FunctionAlreadyDefined("twice".to_string())),
+            ),
+            (
+                "function \"if\" { return; }",
+                Some(ErrorType::InvalidFunctionName("if".to_string())),
+            ),
+            (
+                "function \"no spaces\" { return; }",
+                Some(ErrorType::InvalidFunctionName("no spaces".to_string())),
+            ),
+        ] {
+            let err = Compiler::new()
+                .compile(format!("{header}{script}").as_bytes())
+                .unwrap_err();
+            match expected {
+                Some(expected) => assert_eq!(
+                    format!("{:?}", err.error_type()),
+                    format!("{expected:?}"),
+                    "{script}"
+                ),
+                None => assert!(
+                    matches!(err.error_type(), ErrorType::InvalidExpression(_)),
+                    "{script}: {err:?}"
+                ),
+            }
+        }
+
+        // Variables of the caller are not visible inside the function
+        let script = Compiler::new()
+            .compile(
+                format!(
+                    "{header}require \"variables\";\nset \"outer\" \"1\";\nfunction \"peek\" {{ return \"outer\"; }}"
+                )
+                .as_bytes(),
+            );
+        assert!(matches!(
+            script.unwrap_err().error_type(),
+            ErrorType::InvalidExpression(_)
+        ));
+    }
+}
@@ -32,6 +32,7 @@ use crate::{
         grammar::actions::action_mime::{Enclose, ExtractText, Replace},
         VariableType,
     },
+    runtime::{suspend::checksum, RuntimeError},
     Context, Event,
 };
 
@@ -49,6 +50,7 @@ impl Replace {
             ctx.message.parts.remove(part_id);
         }
         ctx.has_changes = true;
+        ctx.body_changed = true;
 
         // Update part
         let body = ctx.eval_value(&self.replacement).to_string().into_owned();
@@ -196,6 +198,21 @@ impl Enclose {
             .or_else(|| ctx.message.subject().map(|s| s.to_string()))
             .unwrap_or_default();
 
+        if ctx.original_headers.is_none() {
+            // Keep the original header block, the message is about to be nested
+            let raw_message = &ctx.message.raw_message;
+            let header_end = ctx
+                .message
+                .parts
+                .first()
+                .map(|part| part.offset_body)
+                .filter(|offset| *offset > 0 && *offset <= raw_message.len())
+                .unwrap_or(raw_message.len());
+            ctx.original_headers = raw_message[..header_end].to_vec().into();
+        }
+        if ctx.message_checksum.is_none() {
+            ctx.message_checksum = checksum(&ctx.message.raw_message).into();
+        }
         let message = std::mem::take(&mut ctx.message);
         #[cfg(test)]
         let boundary = make_test_boundary();
@@ -205,6 +222,7 @@ impl Enclose {
         ctx.message_size += ((boundary.len() + 6) * 3) + body.len() + 2;
         ctx.part = 0;
         ctx.has_changes = true;
+        ctx.body_changed = true;
         ctx.message = Message {
             html_body: Vec::with_capacity(0),
             text_body: Vec::with_capacity(0),
@@ -341,10 +359,15 @@ impl Enclose {
 }
 
 impl ExtractText {
-    pub(crate) fn exec(&self, ctx: &mut Context) {
+    pub(crate) fn exec(&self, ctx: &mut Context) -> Result<(), RuntimeError> {
         let mut value = String::new();
 
         if !ctx.part_iter_stack.is_empty() {
+            // Converting large HTML parts can take a while
+            if ctx.is_past_deadline() {
+                return Err(RuntimeError::TimeLimitReached);
+            }
+
             match ctx.message.parts.get(ctx.part).map(|p| &p.body) {
                 Some(PartType::Text(text)) => {
                     value = if let Some(first) = &self.first {
@@ -365,6 +388,9 @@ impl ExtractText {
 
             if !self.modifiers.is_empty() && !value.is_empty() {
                 for modifier in &self.modifiers {
+                    if ctx.is_past_deadline() {
+                        return Err(RuntimeError::TimeLimitReached);
+                    }
                     value = modifier.apply(&value, ctx);
                 }
             }
@@ -391,6 +417,8 @@ impl ExtractText {
             }
             _ => (),
         }
+
+        Ok(())
     }
 }
 
@@ -403,10 +431,12 @@ enum StackItem<'x> {
 impl<'x> Context<'x> {
     pub(crate) fn build_message_id(&mut self) -> Option<Event> {
         if self.has_changes {
+            self.parse_message();
             self.last_message_id += 1;
             self.main_message_id = self.last_message_id;
             self.has_changes = false;
             let message = self.build_message();
+            self.use_memory(message.len());
             Some(Event::CreatedMessage {
                 message_id: self.main_message_id,
                 message,
@@ -416,7 +446,7 @@ impl<'x> Context<'x> {
         }
     }
 
-    pub(crate) fn build_message(&mut self) -> Vec<u8> {
+    pub(crate) fn build_message(&self) -> Vec<u8> {
         let mut current_message = &self.message;
         let mut current_boundary = "";
         let mut message = Vec::with_capacity(self.message_size);
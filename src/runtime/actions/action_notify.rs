@@ -29,53 +29,127 @@ use crate::{
         action_notify::Notify,
         action_redirect::{ByTime, Ret},
     },
+    runtime::{
+        notify::Notification,
+        policy::PolicyAction,
+        ratelimit::{NOTIFY_POLICY_KEY, SEND_POLICY_KEY},
+        template::render,
+        RuntimeError,
+    },
     Context, Event, Importance, Recipient,
 };
 
-use super::action_vacation::MAX_SUBJECT_LEN;
+use super::action_vacation::{write_encoded_header, MAX_SUBJECT_LEN};
 
 impl Notify {
-    pub(crate) fn exec(&self, ctx: &mut Context) {
+    pub(crate) fn exec(&self, ctx: &mut Context) -> Result<(), RuntimeError> {
         // Do not notify on Auto-Submitted messages
-        for header in &ctx.message.parts[0].headers {
-            if matches!(&header.name, HeaderName::Other(name) if name.eq_ignore_ascii_case("Auto-Submitted"))
-                && header
-                    .value
-                    .as_text()
-                    .map_or(true, |v| !v.eq_ignore_ascii_case("no"))
-            {
-                return;
-            }
+        if ctx.is_auto_submitted() {
+            return Ok(());
         }
 
         let uri = ctx.eval_value(&self.method).to_string().into_owned();
         let (scheme, params) = if let Some(parts) = parse_uri(&uri) {
             parts
         } else {
-            return;
+            return Ok(());
         };
 
         let has_fcc = self.fcc.is_some();
         let is_mailto = scheme.eq_ignore_ascii_case("mailto")
             && ctx.num_out_messages < ctx.runtime.max_out_messages;
-        let mut events = Vec::with_capacity(3);
+        let mailto = if is_mailto {
+            if let Some(mailto) = parse_mailto(params) {
+                mailto
+            } else {
+                return Ok(());
+            }
+        } else {
+            MailtoMessage::default()
+        };
 
-        if is_mailto || has_fcc {
-            let params = if is_mailto {
-                if let Some(params) = parse_mailto(params) {
-                    params
-                } else {
-                    return;
+        // Apply the destination policy to each recipient or to the URI
+        if is_mailto {
+            for address in mailto.to.iter().chain(&mailto.cc).chain(&mailto.bcc) {
+                let address = address
+                    .rsplit_once('<')
+                    .and_then(|(_, addr)| addr.rsplit_once('>'))
+                    .map_or(address.as_str(), |(addr, _)| addr);
+                if !ctx.check_policy(PolicyAction::Notify, &format!("mailto:{address}"))? {
+                    return Ok(());
                 }
-            } else {
-                MailtoMessage {
-                    to: Vec::new(),
-                    cc: Vec::new(),
-                    bcc: Vec::new(),
-                    body: None,
-                    headers: Vec::new(),
+            }
+        } else if !ctx.check_policy(PolicyAction::Notify, &uri)? {
+            return Ok(());
+        }
+
+        let policy = if is_mailto {
+            ctx.check_rate_limit(SEND_POLICY_KEY, ctx.runtime.send_rate_limit)?
+        } else {
+            ctx.check_rate_limit(NOTIFY_POLICY_KEY, ctx.runtime.notify_rate_limit)?
+        };
+        if !policy {
+            return Ok(());
+        }
+
+        // Other methods build their payload before any message is created,
+        // as invalid URIs or options cancel the notification
+        let notify = if !is_mailto {
+            let from = self
+                .from
+                .as_ref()
+                .map(|f| ctx.eval_value(f).to_string().into_owned());
+            let importance = self.importance.as_ref().map_or(Importance::Normal, |i| {
+                match ctx.eval_value(i).to_string().as_ref() {
+                    "1" => Importance::High,
+                    "3" => Importance::Low,
+                    _ => Importance::Normal,
                 }
+            });
+            let options = ctx.eval_values_owned(&self.options);
+            let message = self
+                .message
+                .as_ref()
+                .map(|m| ctx.eval_value(m).to_string().into_owned())
+                .or_else(|| ctx.message.subject().map(|s| s.to_string()))
+                .unwrap_or_default();
+            let payload = if let Some(method) = ctx.runtime.notify_method(scheme) {
+                let (Some(options), true) =
+                    (method.parse_options(&options), method.validate_uri(&uri))
+                else {
+                    return Ok(());
+                };
+                let Some(payload) = method.build(&Notification {
+                    uri: &uri,
+                    from: from.as_deref(),
+                    recipient: ctx.user_address.as_ref(),
+                    importance,
+                    options: &options,
+                    message: &message,
+                    subject: ctx.message.subject().unwrap_or_default(),
+                }) else {
+                    return Ok(());
+                };
+                Some(Box::new(payload))
+            } else {
+                None
             };
+
+            Some(Event::Notify {
+                method: uri.clone(),
+                from,
+                importance,
+                options,
+                message,
+                payload,
+            })
+        } else {
+            None
+        };
+        let mut events = Vec::with_capacity(3);
+
+        if is_mailto || has_fcc {
+            let params = mailto;
             let from = if let Some(from) = &self.from {
                 let from = ctx.eval_value(from).to_string().into_owned();
                 if from
@@ -93,6 +167,31 @@ impl Notify {
                 .message
                 .as_ref()
                 .map(|m| ctx.eval_value(m).to_string().into_owned());
+
+            // Localized templates apply unless the URI sets a subject or body
+            let template = ctx.runtime.notify_templates.get(&ctx.reply_languages());
+            let orig_subject = ctx.message.subject().unwrap_or_default();
+            let values = [
+                ("message", notify_message.as_deref().unwrap_or(orig_subject)),
+                (
+                    "name",
+                    ctx.message
+                        .from()
+                        .and_then(|from| from.first())
+                        .and_then(|from| from.name().or_else(|| from.address()))
+                        .unwrap_or_default(),
+                ),
+                ("subject", orig_subject),
+            ];
+            let template_subject = template
+                .subject
+                .as_ref()
+                .map(|subject| render(subject, &values, false));
+            let template_body = template
+                .body
+                .as_ref()
+                .map(|body| render(body, &values, false));
+
             let message_len = params
                 .to
                 .iter()
@@ -106,6 +205,7 @@ impl Notify {
                     .sum::<usize>()
                 + params.body.as_ref().map_or(0, |b| b.len())
                 + notify_message.as_ref().map_or(0, |b| b.len())
+                + template_body.as_ref().map_or(0, |b| b.len())
                 + from.len()
                 + 200;
 
@@ -189,36 +289,38 @@ impl Notify {
             message.extend_from_slice(priority.as_bytes());
             message.extend_from_slice(b"\r\n");
 
-            message.extend_from_slice(b"Subject: ");
             let subject = if let Some(subject) = has_subject {
                 subject.as_str()
+            } else if let Some(subject) = &template_subject {
+                subject.as_str()
             } else if let Some(subject) = &notify_message {
                 subject.as_ref()
-            } else if let Some(subject) = ctx.message.subject() {
-                subject
             } else {
-                ""
+                ctx.message.subject().unwrap_or_default()
             };
-            let mut iter = subject.chars().enumerate();
-            let mut buf = [0; 4];
-            #[allow(clippy::while_let_on_iterator)]
-            while let Some((pos, char)) = iter.next() {
-                if pos < MAX_SUBJECT_LEN {
-                    message.extend_from_slice(char.encode_utf8(&mut buf).as_bytes());
-                } else {
-                    break;
-                }
+            let mut subject = subject
+                .chars()
+                .take(MAX_SUBJECT_LEN + 1)
+                .collect::<String>();
+            if subject.chars().count() > MAX_SUBJECT_LEN {
+                subject.pop();
+                subject.push('…');
             }
-            if iter.next().is_some() {
-                message.extend_from_slice('…'.encode_utf8(&mut buf).as_bytes());
+            if subject.is_ascii() {
+                message.extend_from_slice(b"Subject: ");
+                message.extend_from_slice(subject.as_bytes());
+                message.extend_from_slice(b"\r\n");
+            } else {
+                write_encoded_header(&mut message, "Subject", &subject, None);
             }
-            message.extend_from_slice(b"\r\n");
 
             message.extend_from_slice(b"Auto-Submitted: auto-notified\r\n");
             message.extend_from_slice(b"X-Sieve: yes\r\n");
             message.extend_from_slice(b"Content-type: text/plain; charset=utf-8\r\n\r\n");
             if let Some(body) = params.body {
                 message.extend_from_slice(body.as_bytes());
+            } else if let Some(body) = &template_body {
+                message.extend_from_slice(body.as_bytes());
             } else if let Some(subject) = &notify_message {
                 message.extend_from_slice(subject.as_bytes());
             } else if let Some(subject) = ctx.message.subject() {
@@ -226,6 +328,7 @@ impl Notify {
             }
 
             ctx.last_message_id += 1;
+            ctx.use_memory(message.len());
             events.push(Event::CreatedMessage {
                 message_id: ctx.last_message_id,
                 message,
@@ -251,6 +354,7 @@ impl Notify {
                             })
                             .collect(),
                     ),
+                    sender: ctx.srs_forward(ctx.user_address.as_ref()),
                     notify: crate::compiler::grammar::actions::action_redirect::Notify::Never,
                     return_of_content: Ret::Default,
                     by_time: ByTime::None,
@@ -259,49 +363,32 @@ impl Notify {
             }
         }
 
-        if !is_mailto {
-            events.push(Event::Notify {
-                method: uri,
-                from: self
-                    .from
-                    .as_ref()
-                    .map(|f| ctx.eval_value(f).to_string().into_owned()),
-                importance: self.importance.as_ref().map_or(Importance::Normal, |i| {
-                    match ctx.eval_value(i).to_string().as_ref() {
-                        "1" => Importance::High,
-                        "3" => Importance::Low,
-                        _ => Importance::Normal,
-                    }
-                }),
-                options: ctx.eval_values_owned(&self.options),
-                message: self
-                    .message
-                    .as_ref()
-                    .map(|m| ctx.eval_value(m).to_string().into_owned())
-                    .or_else(|| ctx.message.subject().map(|s| s.to_string()))
-                    .unwrap_or_default(),
-            });
+        if let Some(notify) = notify {
+            events.push(notify);
             ctx.num_out_messages += 1;
         }
 
         if let Some(fcc) = &self.fcc {
             // File carbon copy
-            events.push(Event::FileInto {
-                folder: ctx.eval_value(&fcc.mailbox).to_string().into_owned(),
-                flags: ctx.get_local_flags(&fcc.flags),
-                mailbox_id: fcc
-                    .mailbox_id
-                    .as_ref()
-                    .map(|m| ctx.eval_value(m).to_string().into_owned()),
-                special_use: fcc
-                    .special_use
-                    .as_ref()
-                    .map(|s| ctx.eval_value(s).to_string().into_owned()),
-                create: fcc.create,
-                message_id: ctx.last_message_id,
-            });
+            events.extend(ctx.fcc_event(fcc, ctx.last_message_id)?);
         }
         ctx.queued_events = events.into_iter();
+
+        Ok(())
+    }
+}
+
+impl Context<'_> {
+    // Returns true if the message has an Auto-Submitted header with any value
+    // other than "no" (RFC 3834).
+    pub(crate) fn is_auto_submitted(&self) -> bool {
+        self.message.parts[0].headers.iter().any(|header| {
+            matches!(&header.name, HeaderName::Other(name) if name.eq_ignore_ascii_case("Auto-Submitted"))
+                && header
+                    .value
+                    .as_text()
+                    .is_none_or(|v| !v.eq_ignore_ascii_case("no"))
+        })
     }
 }
 
@@ -314,10 +401,8 @@ pub fn validate_from(addr: &str) -> bool {
 
     for &ch in addr.as_bytes().iter() {
         match ch {
-            b'\"' => {
-                if last_ch != b'\\' {
-                    in_quote = !in_quote;
-                }
+            b'\"' if last_ch != b'\\' => {
+                in_quote = !in_quote;
             }
             b'<' if !in_quote => {
                 if !in_angle {
@@ -358,7 +443,7 @@ pub fn validate_uri(uri: &str) -> Option<&str> {
     if scheme.eq_ignore_ascii_case("mailto") {
         parse_mailto(uri)?;
         scheme.into()
-    } else if ["xmpp", "tel", "http", "https"].contains(&scheme) {
+    } else if ["xmpp", "tel", "sms", "http", "https"].contains(&scheme) {
         scheme.into()
     } else {
         None
@@ -388,7 +473,7 @@ enum State {
 }
 
 #[derive(Default)]
-struct MailtoMessage {
+pub(crate) struct MailtoMessage {
     to: Vec<String>,
     cc: Vec<String>,
     bcc: Vec<String>,
@@ -396,7 +481,7 @@ struct MailtoMessage {
     headers: Vec<(HeaderName<'static>, String)>,
 }
 
-fn parse_mailto(uri: &str) -> Option<MailtoMessage> {
+pub(crate) fn parse_mailto(uri: &str) -> Option<MailtoMessage> {
     let mut params = MailtoMessage::default();
 
     let mut state = State::Address((HeaderName::To, false));
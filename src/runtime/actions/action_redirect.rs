@@ -25,11 +25,12 @@ use mail_parser::{DateTime, HeaderName};
 
 use crate::{
     compiler::grammar::actions::action_redirect::{ByTime, Redirect},
+    runtime::{policy::PolicyAction, ratelimit::SEND_POLICY_KEY, RuntimeError},
     Context, Envelope, Event, Recipient,
 };
 
 impl Redirect {
-    pub(crate) fn exec(&self, ctx: &mut Context) {
+    pub(crate) fn exec(&self, ctx: &mut Context) -> Result<(), RuntimeError> {
         if let Some(address) = sanitize_address(ctx.eval_value(&self.address).to_string().as_ref())
         {
             if ctx.num_redirects < ctx.runtime.max_redirects
@@ -49,7 +50,15 @@ impl Redirect {
                                 && v.to_string().eq_ignore_ascii_case(address.as_str())
                         }))
                 {
-                    return;
+                    return Ok(());
+                }
+
+                // Apply the destination and outgoing message policies
+                if !ctx.check_policy(PolicyAction::Redirect, &address)? {
+                    return Ok(());
+                }
+                if !ctx.check_rate_limit(SEND_POLICY_KEY, ctx.runtime.send_rate_limit)? {
+                    return Ok(());
                 }
 
                 if !self.copy && matches!(&ctx.final_event, Some(Event::Keep { .. })) {
@@ -62,12 +71,20 @@ impl Redirect {
                 }
                 ctx.num_redirects += 1;
                 ctx.num_out_messages += 1;
+                let sender = ctx.envelope.iter().find_map(|(e, v)| {
+                    if matches!(e, Envelope::From) {
+                        ctx.srs_forward(v.to_string().as_ref())
+                    } else {
+                        None
+                    }
+                });
                 events.push(Event::SendMessage {
                     recipient: if !self.list {
                         Recipient::Address(address)
                     } else {
                         Recipient::List(address)
                     },
+                    sender,
                     notify: self.notify.clone(),
                     return_of_content: self.return_of_content.clone(),
                     by_time: match &self.by_time {
@@ -106,6 +123,22 @@ impl Redirect {
                 ctx.queued_events = events.into_iter();
             }
         }
+
+        Ok(())
+    }
+}
+
+impl Context<'_> {
+    // Rewrites the envelope sender of forwarded messages using SRS
+    pub(crate) fn srs_forward(&self, address: &str) -> Option<String> {
+        if !address.is_empty() {
+            self.runtime
+                .srs
+                .as_ref()?
+                .forward(address, self.current_time as u64)
+        } else {
+            None
+        }
     }
 }
 
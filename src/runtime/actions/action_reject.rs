@@ -0,0 +1,441 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use mail_builder::{
+    headers::{
+        address::Address, content_type::ContentType, date::Date,
+        message_id::generate_message_id_header, raw::Raw, text::Text,
+    },
+    mime::MimePart,
+    MessageBuilder,
+};
+use mail_parser::HeaderName;
+
+use crate::{
+    compiler::grammar::actions::{
+        action_redirect::{ByTime, Notify, Ret},
+        action_reject::Reject,
+    },
+    runtime::{
+        actions::action_vacation::{write_encoded_header, MAX_SUBJECT_LEN},
+        ratelimit::SEND_POLICY_KEY,
+    },
+    Context, DeliveryPhase, Envelope, Event, Recipient,
+};
+
+impl Reject {
+    pub(crate) fn exec(&self, ctx: &mut Context) -> Event {
+        let reason = ctx.eval_value(&self.reason).to_string().into_owned();
+
+        // Queue an MDN (reject) or DSN (ereject) addressed to the envelope sender,
+        // returned after the reject event. Rejections before or during the SMTP/LMTP
+        // transaction are reported in-protocol with reject_reply, and no reports are
+        // sent for Auto-Submitted messages (RFC 3834).
+        if ctx.runtime.reject_reports
+            && !ctx.is_in_protocol_phase()
+            && !ctx.is_auto_submitted()
+            && ctx.num_out_messages < ctx.runtime.max_out_messages
+            && ctx
+                .check_rate_limit(SEND_POLICY_KEY, ctx.runtime.send_rate_limit)
+                .unwrap_or(false)
+        {
+            if let Some(events) = ctx.build_reject_report(self.ereject, &reason) {
+                ctx.queued_events = events.into_iter();
+            }
+        }
+
+        Event::Reject {
+            extended: self.ereject,
+            reason,
+        }
+    }
+}
+
+impl Context<'_> {
+    // Returns the SMTP/LMTP reply for a rejected message, using the configured
+    // reply and enhanced status codes.
+    pub fn reject_reply(&self, reason: &str) -> String {
+        let (code, status) = self.reject_status();
+        let lines = reason
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>();
+        let mut reply = String::with_capacity(reason.len() + 16);
+
+        if lines.is_empty() {
+            reply.push_str(&format!("{code} {status} Message rejected\r\n"));
+        } else {
+            for (pos, line) in lines.iter().enumerate() {
+                let sep = if pos + 1 < lines.len() { '-' } else { ' ' };
+                reply.push_str(&format!("{code}{sep}{status} {line}\r\n"));
+            }
+        }
+
+        reply
+    }
+
+    fn is_in_protocol_phase(&self) -> bool {
+        self.vars_env.get("phase").is_some_and(|phase| {
+            let phase = phase.to_string();
+            phase == DeliveryPhase::Pre.as_str() || phase == DeliveryPhase::During.as_str()
+        })
+    }
+
+    fn reject_status(&self) -> (&str, &str) {
+        self.runtime
+            .reject_status
+            .split_once(' ')
+            .map(|(code, status)| (code.trim(), status.trim()))
+            .unwrap_or((self.runtime.reject_status.as_ref(), "5.7.1"))
+    }
+
+    fn build_reject_report(&mut self, ereject: bool, reason: &str) -> Option<Vec<Event>> {
+        let report_to = self.envelope.iter().find_map(|(name, value)| {
+            if name == &Envelope::From && !value.is_empty() {
+                Some(value.to_string().into_owned())
+            } else {
+                None
+            }
+        })?;
+        let recipient = if !self.user_address.is_empty() {
+            self.user_address.to_string()
+        } else {
+            self.envelope
+                .iter()
+                .find_map(|(name, value)| {
+                    if name == &Envelope::To {
+                        Some(value.to_string().into_owned())
+                    } else {
+                        None
+                    }
+                })
+                .unwrap_or_default()
+        };
+
+        let mut orig_subject = "";
+        let mut orig_message_id = None;
+        for header in &self.message.parts[0].headers {
+            match &header.name {
+                HeaderName::Subject => {
+                    orig_subject = header.value.as_text().unwrap_or_default();
+                }
+                HeaderName::MessageId => {
+                    orig_message_id = header.value.as_text();
+                }
+                _ => (),
+            }
+        }
+        let subject = if !orig_subject.is_empty() {
+            let mut subject = String::from("Rejected: ");
+            subject.extend(orig_subject.chars().take(MAX_SUBJECT_LEN));
+            subject
+        } else {
+            "Message rejected".to_string()
+        };
+
+        // Original headers, as modified by the script
+        let mut headers = self.build_message();
+        if let Some(pos) = headers.windows(4).position(|w| w == b"\r\n\r\n") {
+            headers.truncate(pos + 2);
+        } else if let Some(pos) = headers.windows(2).position(|w| w == b"\n\n") {
+            headers.truncate(pos + 1);
+        }
+        let headers_encoding = if headers.is_ascii() { "7bit" } else { "8bit" };
+
+        let hostname = self.runtime.local_hostname.as_ref();
+        let (text, report_type, report_ct, report) = if ereject {
+            let (code, status) = self.reject_status();
+            let diagnostic = reason
+                .split(['\r', '\n'])
+                .map(|line| line.trim())
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<_>>()
+                .join(" ");
+            (
+                format!(
+                    concat!(
+                        "Your message could not be delivered to {}.\n",
+                        "The recipient's mail system rejected it with the following reason:\n\n",
+                        "{}\n"
+                    ),
+                    recipient, reason
+                ),
+                "delivery-status",
+                "message/delivery-status",
+                format!(
+                    concat!(
+                        "Reporting-MTA: dns;{}\r\n",
+                        "\r\n",
+                        "Final-Recipient: rfc822;{}\r\n",
+                        "Action: failed\r\n",
+                        "Status: {}\r\n",
+                        "Diagnostic-Code: smtp;{} {} {}\r\n"
+                    ),
+                    hostname, recipient, status, code, status, diagnostic
+                ),
+            )
+        } else {
+            let mut report = format!(
+                concat!(
+                    "Reporting-UA: {}; Stalwart Sieve\r\n",
+                    "Final-Recipient: rfc822;{}\r\n",
+                ),
+                hostname, recipient
+            );
+            if let Some(message_id) = orig_message_id {
+                report.push_str(&format!("Original-Message-ID: <{message_id}>\r\n"));
+            }
+            report.push_str("Disposition: automatic-action/MDN-sent-automatically; deleted\r\n");
+            (
+                format!(
+                    concat!(
+                        "Your message to {} was automatically rejected ",
+                        "for the following reason:\n\n{}\n"
+                    ),
+                    recipient, reason
+                ),
+                "disposition-notification",
+                "message/disposition-notification",
+                report,
+            )
+        };
+
+        // Build message
+        let mut message_id = Vec::with_capacity(64);
+        generate_message_id_header(&mut message_id, hostname).unwrap();
+        let mut message = Vec::with_capacity(headers.len() + text.len() + report.len() + 512);
+        let mut builder = MessageBuilder::new()
+            .date(Date::now())
+            .header(
+                "Message-ID",
+                Raw::new(String::from_utf8(message_id).unwrap_or_default()),
+            )
+            .header("Auto-Submitted", Text::new("auto-replied"))
+            .header("To", Raw::new(report_to.as_str()))
+            .body(MimePart::new(
+                ContentType::new("multipart/report").attribute("report-type", report_type),
+                vec![
+                    MimePart::new("text/plain", text),
+                    MimePart::new(report_ct, report.into_bytes()).transfer_encoding("7bit"),
+                    MimePart::new("text/rfc822-headers", headers)
+                        .transfer_encoding(headers_encoding),
+                ],
+            ));
+        if ereject {
+            builder = builder.from(Address::new_address(
+                "Mail Delivery System".into(),
+                format!("MAILER-DAEMON@{hostname}"),
+            ));
+        } else if !self.user_address.is_empty() {
+            builder = builder.header("From", Raw::new(self.user_from_field()));
+        } else {
+            builder = builder.header("From", Raw::new(recipient.as_str()));
+        }
+        if subject.is_ascii() {
+            builder = builder.subject(subject);
+        } else {
+            write_encoded_header(&mut message, "Subject", &subject, None);
+        }
+        builder.write_to(&mut message).ok()?;
+
+        self.last_message_id += 1;
+        self.num_out_messages += 1;
+        self.use_memory(message.len());
+
+        Some(vec![
+            Event::CreatedMessage {
+                message_id: self.last_message_id,
+                message,
+            },
+            Event::SendMessage {
+                recipient: Recipient::Address(report_to),
+                sender: None,
+                notify: Notify::Never,
+                return_of_content: Ret::Default,
+                by_time: ByTime::None,
+                message_id: self.last_message_id,
+            },
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::{MessageParser, MimeHeaders};
+
+    use crate::{Context, DeliveryInfo, DeliveryPhase, Envelope, Event, Recipient, Runtime};
+
+    fn run_reject(runtime: &Runtime, script: &str) -> Vec<Event> {
+        run_reject_with(runtime, script, "", None)
+    }
+
+    fn run_reject_with(
+        runtime: &Runtime,
+        script: &str,
+        headers: &str,
+        phase: Option<DeliveryPhase>,
+    ) -> Vec<Event> {
+        let raw_message = format!(
+            concat!(
+                "From: Joe <joe@example.org>\r\n",
+                "To: jane@example.org\r\n",
+                "Message-ID: <abc@example.org>\r\n",
+                "{}Subject: Buy now\r\n",
+                "\r\n",
+                "Limited offer.\r\n",
+            ),
+            headers
+        );
+        let (events, error) = Context::new(
+            runtime,
+            MessageParser::new().parse(raw_message.as_bytes()).unwrap(),
+        )
+        .with_user_address("jane@example.org")
+        .with_envelope(Envelope::From, "joe@example.org")
+        .with_envelope(Envelope::To, "jane@example.org")
+        .with_delivery_info(&DeliveryInfo {
+            phase,
+            ..Default::default()
+        })
+        .run_script(script);
+        assert!(error.is_none(), "{error:?}");
+        events
+    }
+
+    #[test]
+    fn reject_reports() {
+        // Disabled by default
+        let runtime = Runtime::new();
+        let events = run_reject(&runtime, "require \"reject\"; reject \"No spam\";");
+        assert_eq!(
+            events,
+            vec![Event::Reject {
+                extended: false,
+                reason: "No spam".to_string()
+            }]
+        );
+
+        // MDN for reject
+        let runtime = Runtime::new()
+            .with_reject_reports(true)
+            .with_local_hostname("mx.example.org");
+        let events = run_reject(&runtime, "require \"reject\"; reject \"No spam\";");
+        assert_eq!(events.len(), 3);
+        assert!(matches!(
+            &events[0],
+            Event::Reject {
+                extended: false,
+                ..
+            }
+        ));
+        assert!(matches!(
+            &events[2],
+            Event::SendMessage { recipient: Recipient::Address(addr), message_id: 1, .. }
+                if addr == "joe@example.org"
+        ));
+        let raw = match &events[1] {
+            Event::CreatedMessage { message, .. } => message,
+            event => panic!("unexpected event {event:?}"),
+        };
+        let message = MessageParser::new().parse(raw).unwrap();
+        let ct = message.content_type().unwrap();
+        assert_eq!(ct.ctype(), "multipart");
+        assert_eq!(ct.subtype(), Some("report"));
+        assert_eq!(
+            ct.attribute("report-type"),
+            Some("disposition-notification")
+        );
+        assert_eq!(message.subject(), Some("Rejected: Buy now"));
+        assert_eq!(message.header_raw("To").unwrap().trim(), "joe@example.org");
+        assert!(message.body_text(0).unwrap().contains("No spam"));
+        let report = String::from_utf8_lossy(message.parts[2].contents());
+        assert!(report.contains("Reporting-UA: mx.example.org; Stalwart Sieve\r\n"));
+        assert!(report.contains("Final-Recipient: rfc822;jane@example.org\r\n"));
+        assert!(report.contains("Original-Message-ID: <abc@example.org>\r\n"));
+        assert!(report.contains("Disposition: automatic-action/MDN-sent-automatically; deleted"));
+        let headers = String::from_utf8_lossy(message.parts[3].contents());
+        assert!(headers.starts_with("From: Joe <joe@example.org>\r\n"));
+        assert!(headers.contains("Subject: Buy now\r\n"));
+        assert!(!headers.contains("Limited offer"));
+
+        // DSN for ereject
+        let events = run_reject(
+            &runtime.clone().with_reject_status("554 5.7.0"),
+            "require \"ereject\"; ereject \"No spam\";",
+        );
+        assert_eq!(events.len(), 3);
+        let raw = match &events[1] {
+            Event::CreatedMessage { message, .. } => message,
+            event => panic!("unexpected event {event:?}"),
+        };
+        let message = MessageParser::new().parse(raw).unwrap();
+        assert_eq!(
+            message.content_type().unwrap().attribute("report-type"),
+            Some("delivery-status")
+        );
+        let report = String::from_utf8_lossy(message.parts[2].contents());
+        assert!(report.contains("Reporting-MTA: dns;mx.example.org\r\n"));
+        assert!(report.contains("Status: 5.7.0\r\n"));
+        assert!(report.contains("Diagnostic-Code: smtp;554 5.7.0 No spam\r\n"));
+
+        // No reports for in-protocol rejections or Auto-Submitted messages
+        let script = "require \"reject\"; reject \"No spam\";";
+        for (headers, phase) in [
+            ("", Some(DeliveryPhase::Pre)),
+            ("", Some(DeliveryPhase::During)),
+            ("Auto-Submitted: auto-replied\r\n", None),
+            (
+                "Auto-Submitted: auto-generated\r\n",
+                Some(DeliveryPhase::Post),
+            ),
+        ] {
+            let events = run_reject_with(&runtime, script, headers, phase);
+            assert_eq!(events.len(), 1, "{headers} {phase:?}");
+        }
+        let events = run_reject_with(
+            &runtime,
+            script,
+            "Auto-Submitted: no\r\n",
+            Some(DeliveryPhase::Post),
+        );
+        assert_eq!(events.len(), 3);
+    }
+
+    #[test]
+    fn reject_reply() {
+        let runtime = Runtime::new();
+        let ctx = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(b"Subject: test\r\n\r\n")
+                .unwrap(),
+        );
+        assert_eq!(ctx.reject_reply("No spam"), "550 5.7.1 No spam\r\n");
+        assert_eq!(
+            ctx.reject_reply("Mailbox closed.\nTry later.\n"),
+            "550-5.7.1 Mailbox closed.\r\n550 5.7.1 Try later.\r\n"
+        );
+        assert_eq!(ctx.reject_reply(""), "550 5.7.1 Message rejected\r\n");
+    }
+}
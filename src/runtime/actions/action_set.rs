@@ -55,6 +55,7 @@ impl<'x> Context<'x> {
             }
             variable = new_variable.into();
         }
+        self.use_memory(variable.len());
 
         match var_name {
             VariableType::Local(var_id) => {
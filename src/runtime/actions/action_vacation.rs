@@ -21,10 +21,19 @@
  * for more details.
 */
 
-use std::borrow::Cow;
-
-use mail_builder::headers::{date::Date, message_id::generate_message_id_header};
-use mail_parser::{HeaderName, HeaderValue};
+use mail_builder::{
+    encoders::base64::base64_encode,
+    headers::{
+        address::Address, date::Date, message_id::generate_message_id_header,
+        message_id::MessageId, raw::Raw, text::Text,
+    },
+    mime::MimePart,
+    MessageBuilder,
+};
+use mail_parser::{
+    decoders::html::html_to_text, parsers::MessageStream, HeaderName, HeaderValue, MessageParser,
+    PartType,
+};
 
 use crate::{
     compiler::grammar::{
@@ -34,11 +43,18 @@ use crate::{
         },
         AddressPart,
     },
-    runtime::tests::TestResult,
+    runtime::{
+        policy::PolicyAction,
+        ratelimit::SEND_POLICY_KEY,
+        template::{escape_html, is_html, render, Quote, VacationTemplate},
+        tests::TestResult,
+        RuntimeError,
+    },
     Context, Envelope, Event, Recipient,
 };
 
 pub(crate) const MAX_SUBJECT_LEN: usize = 256;
+const MAX_ENCODED_WORD_LEN: usize = 40;
 
 impl TestVacation {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
@@ -55,15 +71,11 @@ impl TestVacation {
                     Envelope::From => {
                         from = value.to_string().to_ascii_lowercase();
                     }
-                    Envelope::To => {
-                        if !ctx.runtime.vacation_use_orig_rcpt {
-                            user_addresses.push(value.to_string());
-                        }
+                    Envelope::To if !ctx.runtime.vacation_use_orig_rcpt => {
+                        user_addresses.push(value.to_string());
                     }
-                    Envelope::Orcpt => {
-                        if ctx.runtime.vacation_use_orig_rcpt {
-                            user_addresses.push(value.to_string());
-                        }
+                    Envelope::Orcpt if ctx.runtime.vacation_use_orig_rcpt => {
+                        user_addresses.push(value.to_string());
                     }
                     _ => (),
                 }
@@ -127,12 +139,12 @@ impl TestVacation {
                         if header
                             .value
                             .as_text()
-                            .map_or(true, |v| !v.eq_ignore_ascii_case("no"))
+                            .is_none_or(|v| !v.eq_ignore_ascii_case("no"))
                         {
                             return TestResult::Bool(false);
                         }
                     } else if header_name.eq_ignore_ascii_case("X-Auto-Response-Suppress") {
-                        if header.value.as_text().map_or(false, |v| {
+                        if header.value.as_text().is_some_and(|v| {
                             v.to_ascii_lowercase()
                                 .split(',')
                                 .any(|v| ["all", "oof"].contains(&v.trim()))
@@ -143,7 +155,7 @@ impl TestVacation {
                         && header
                             .value
                             .as_text()
-                            .map_or(false, |v| v.eq_ignore_ascii_case("bulk"))
+                            .is_some_and(|v| v.eq_ignore_ascii_case("bulk"))
                     {
                         return TestResult::Bool(false);
                     }
@@ -177,155 +189,196 @@ impl TestVacation {
 }
 
 impl Vacation {
-    pub(crate) fn exec(&self, ctx: &mut Context) {
-        let mut vacation_to = Cow::from("");
+    pub(crate) fn exec(&self, ctx: &mut Context) -> Result<(), RuntimeError> {
+        let vacation_to = ctx
+            .envelope
+            .iter()
+            .find_map(|(name, value)| {
+                if !value.is_empty() && name == &Envelope::From {
+                    Some(value.to_string().into_owned())
+                } else {
+                    None
+                }
+            })
+            .unwrap_or_default();
 
-        for (name, value) in &ctx.envelope {
-            if !value.is_empty() && name == &Envelope::From {
-                vacation_to = value.to_string();
-                break;
-            }
+        if !ctx.check_policy(PolicyAction::Vacation, vacation_to.as_str())?
+            || !ctx.check_rate_limit(SEND_POLICY_KEY, ctx.runtime.send_rate_limit)?
+        {
+            return Ok(());
+        }
+
+        // Quoting lines needs the body, which is not parsed yet in lazy mode
+        let languages = ctx.reply_languages();
+        if matches!(
+            ctx.runtime.vacation_templates.get(&languages).quote,
+            Quote::Lines(_)
+        ) {
+            ctx.parse_message();
         }
 
         // Check headers
+        let template = ctx.runtime.vacation_templates.get(&languages);
         let mut vacation_subject = if let Some(subject) = &self.subject {
-            ctx.eval_value(subject)
+            ctx.eval_value(subject).to_string().into_owned()
         } else {
-            "".into()
+            String::new()
         };
-
-        // Check headers
+        let mut orig_subject = "";
         let mut message_id = None;
-        let mut vacation_to_full = None;
-        let mut references = None;
+        let mut references = Vec::new();
+        let mut vacation_to_name = None;
         for header in &ctx.message.parts[0].headers {
             match &header.name {
-                HeaderName::Subject if vacation_subject.is_empty() => {
-                    if let Some(subject) = header.value.as_text() {
-                        let mut vacation_subject_ = String::with_capacity(MAX_SUBJECT_LEN);
-                        let mut iter = ctx
-                            .runtime
-                            .vacation_subject_prefix
-                            .chars()
-                            .chain(subject.chars())
-                            .enumerate();
-
-                        #[allow(clippy::while_let_on_iterator)]
-                        while let Some((pos, char)) = iter.next() {
-                            if pos < MAX_SUBJECT_LEN {
-                                vacation_subject_.push(char);
-                            } else {
-                                break;
-                            }
-                        }
-                        if iter.next().is_some() {
-                            vacation_subject_.push('…');
-                        }
-                        vacation_subject = vacation_subject_.into();
-                    }
+                HeaderName::Subject => {
+                    orig_subject = header.value.as_text().unwrap_or_default();
                 }
                 HeaderName::MessageId => {
                     message_id = header.value.as_text();
                 }
                 HeaderName::References => {
-                    if header.offset_start > 0 {
-                        references = (&ctx.message.raw_message
-                            [header.offset_start..header.offset_end])
-                            .into();
-                    }
+                    references = header.value.as_text_list().unwrap_or_default();
                 }
-                HeaderName::From | HeaderName::Sender => {
-                    if matches!(&header.value, HeaderValue::Address(address) if address.contains(vacation_to.as_ref()))
-                        && header.offset_start > 0
-                    {
-                        vacation_to_full = (&ctx.message.raw_message
-                            [header.offset_start..header.offset_end])
-                            .into();
+                HeaderName::From | HeaderName::Sender if vacation_to_name.is_none() => {
+                    if let HeaderValue::Address(address) = &header.value {
+                        vacation_to_name = address
+                            .iter()
+                            .find(|addr| {
+                                addr.address
+                                    .as_ref()
+                                    .is_some_and(|a| a.eq_ignore_ascii_case(&vacation_to))
+                            })
+                            .and_then(|addr| addr.name.as_deref());
                     }
                 }
                 _ => (),
             }
         }
+        if vacation_subject.is_empty() && !orig_subject.is_empty() {
+            let mut iter = template
+                .subject_prefix
+                .as_deref()
+                .unwrap_or(&ctx.runtime.vacation_subject_prefix)
+                .chars()
+                .chain(orig_subject.chars())
+                .enumerate();
+
+            #[allow(clippy::while_let_on_iterator)]
+            while let Some((pos, char)) = iter.next() {
+                if pos < MAX_SUBJECT_LEN {
+                    vacation_subject.push(char);
+                } else {
+                    break;
+                }
+            }
+            if iter.next().is_some() {
+                vacation_subject.push('…');
+            }
+        }
+        if vacation_subject.is_empty() {
+            vacation_subject = template
+                .default_subject
+                .as_deref()
+                .unwrap_or(&ctx.runtime.vacation_default_subject)
+                .to_string();
+        }
 
         // Build message
         let vacation_from = if let Some(from) = &self.from {
-            ctx.eval_value(from)
+            ctx.eval_value(from).to_string().into_owned()
         } else if !ctx.user_address.is_empty() {
-            ctx.user_from_field().into()
+            ctx.user_from_field()
         } else if let Some(addr) =
             ctx.envelope
                 .iter()
                 .find_map(|(n, v)| if n == &Envelope::To { Some(v) } else { None })
         {
-            addr.to_string().into()
+            addr.to_string().into_owned()
         } else {
-            "".into()
+            String::new()
         };
-        if vacation_subject.is_empty() {
-            vacation_subject = ctx.runtime.vacation_default_subject.as_ref().into();
-        }
-        let vacation_body = ctx.eval_value(&self.reason);
-        let message_len = vacation_body.len()
-            + vacation_from.len()
-            + vacation_to_full
-                .as_ref()
-                .map_or(vacation_to.len(), |t| t.len())
-            + vacation_subject.len()
-            + message_id.as_ref().map_or(0, |m| m.len() * 2)
-            + references.as_ref().map_or(0, |m| m.len())
-            + 160;
-
-        let mut message = Vec::with_capacity(message_len);
-        write_header(&mut message, "From: ", vacation_from.to_string().as_ref());
-        if let Some(vacation_to_full) = vacation_to_full {
-            message.extend_from_slice(b"To:");
-            message.extend_from_slice(vacation_to_full);
-        } else {
-            write_header(&mut message, "To: ", vacation_to.to_string().as_ref());
-        }
-        write_header(
-            &mut message,
-            "Subject: ",
-            vacation_subject.to_string().as_ref(),
-        );
+        let reason = ctx.eval_value(&self.reason).to_string().into_owned();
+        let mut vacation_message_id = Vec::with_capacity(64);
+        generate_message_id_header(&mut vacation_message_id, &ctx.runtime.local_hostname).unwrap();
+        let mut message = Vec::with_capacity(reason.len() * 2 + 512);
+        let mut builder = MessageBuilder::new()
+            .date(Date::now())
+            .header(
+                "Message-ID",
+                Raw::new(String::from_utf8(vacation_message_id).unwrap_or_default()),
+            )
+            .header("Auto-Submitted", Text::new("auto-replied"));
         if let Some(message_id) = message_id {
-            message.extend_from_slice(b"In-Reply-To: <");
-            message.extend_from_slice(message_id.as_bytes());
-            message.extend_from_slice(b">\r\n");
-
-            message.extend_from_slice(b"References: <");
-            message.extend_from_slice(message_id.as_bytes());
-            if let Some(references) = references {
-                message.extend_from_slice(b"> ");
-                message.extend_from_slice(references);
-            } else {
-                message.extend_from_slice(b">\r\n");
-            }
+            builder = builder
+                .in_reply_to(message_id)
+                .references(MessageId::new_list(
+                    references.into_iter().chain([message_id]),
+                ));
         }
-        message.extend_from_slice(b"Date: ");
-        message.extend_from_slice(Date::now().to_rfc822().as_bytes());
-        message.extend_from_slice(b"\r\n");
 
-        message.extend_from_slice(b"Message-ID: ");
-        generate_message_id_header(&mut message, &ctx.runtime.local_hostname).unwrap();
-        message.extend_from_slice(b"\r\n");
+        let values = [
+            ("name", vacation_to_name.unwrap_or(vacation_to.as_str())),
+            ("subject", orig_subject),
+            ("return_date", ctx.vacation_return_date.as_ref()),
+        ];
+        let (text, html) = match self.body(ctx, template, &reason, &values) {
+            Some(body) => body,
+            None => {
+                // MIME entity that is not a single text part
+                builder = builder.body(MimePart::raw(reason.as_str()));
+                (None, None)
+            }
+        };
+        if let Some(text) = text {
+            builder = builder.text_body(text);
+        }
+        if let Some(html) = html {
+            builder = builder.html_body(html);
+        }
 
-        write_header(&mut message, "Auto-Submitted: ", "auto-replied");
-        if !self.mime {
-            message.extend_from_slice(b"Content-type: text/plain; charset=utf-8\r\n\r\n");
+        // Non-ASCII text is encoded here as mail_builder may split characters
+        // across encoded-words
+        match parse_address(&vacation_from) {
+            Some((Some(name), email)) if !name.is_ascii() => {
+                write_encoded_header(&mut message, "From", &name, Some(&email));
+            }
+            Some((name, email)) => {
+                builder = builder.from(Address::new_address(name, email));
+            }
+            None => {
+                builder = builder.header("From", Raw::new(vacation_from));
+            }
         }
-        message.extend_from_slice(vacation_body.to_string().as_bytes());
+        match vacation_to_name {
+            Some(name) if !name.is_ascii() => {
+                write_encoded_header(&mut message, "To", name, Some(&vacation_to));
+            }
+            Some(name) => {
+                builder = builder.to(Address::new_address(name.into(), vacation_to.as_str()));
+            }
+            None => {
+                builder = builder.header("To", Raw::new(vacation_to.as_str()));
+            }
+        }
+        if vacation_subject.is_ascii() {
+            builder = builder.subject(vacation_subject);
+        } else {
+            write_encoded_header(&mut message, "Subject", &vacation_subject, None);
+        }
+        builder.write_to(&mut message).unwrap_or_default();
 
         // Add action
         let mut events = Vec::with_capacity(3);
         ctx.last_message_id += 1;
         ctx.num_out_messages += 1;
+        ctx.use_memory(message.len());
         events.push(Event::CreatedMessage {
             message_id: ctx.last_message_id,
             message,
         });
         events.push(Event::SendMessage {
             recipient: Recipient::Address(vacation_to.to_string()),
+            sender: None,
             notify: Notify::Never,
             return_of_content: Ret::Default,
             by_time: ByTime::None,
@@ -334,27 +387,295 @@ impl Vacation {
 
         // File carbon copy
         if let Some(fcc) = &self.fcc {
-            events.push(Event::FileInto {
-                folder: ctx.eval_value(&fcc.mailbox).to_string().into_owned(),
-                flags: ctx.get_local_flags(&fcc.flags),
-                mailbox_id: fcc
-                    .mailbox_id
-                    .as_ref()
-                    .map(|m| ctx.eval_value(m).to_string().into_owned()),
-                special_use: fcc
-                    .special_use
-                    .as_ref()
-                    .map(|s| ctx.eval_value(s).to_string().into_owned()),
-                create: fcc.create,
-                message_id: ctx.last_message_id,
-            });
+            events.extend(ctx.fcc_event(fcc, ctx.last_message_id)?);
         }
         ctx.queued_events = events.into_iter();
+
+        Ok(())
+    }
+
+    // Returns the text and HTML bodies of the reply after applying the
+    // configured template and quoting, or None for MIME entities that
+    // have to be sent unmodified.
+    fn body(
+        &self,
+        ctx: &Context,
+        template: &VacationTemplate,
+        reason: &str,
+        values: &[(&str, &str)],
+    ) -> Option<(Option<String>, Option<String>)> {
+        let (content, mut html_body) = if self.mime {
+            let entity = MessageParser::new().parse(reason.as_bytes())?;
+            match (entity.parts.len(), &entity.parts[0].body) {
+                (1, PartType::Html(html)) => (html.to_string(), true),
+                (1, PartType::Text(text))
+                    if template.body.is_some() || template.quote != Quote::None =>
+                {
+                    (text.to_string(), false)
+                }
+                _ => return None,
+            }
+        } else {
+            (reason.to_string(), is_html(reason))
+        };
+
+        let mut body = if let Some(template) = &template.body {
+            let template_is_html = is_html(template);
+            if template_is_html || html_body {
+                let reason = if html_body {
+                    content
+                } else {
+                    escape_html(&content)
+                };
+                let values = values
+                    .iter()
+                    .copied()
+                    .chain([("reason", reason.as_str())])
+                    .collect::<Vec<_>>();
+                html_body = true;
+                if template_is_html {
+                    render(template, &values, true)
+                } else {
+                    render(&escape_html(template), &values, true)
+                }
+            } else {
+                let values = values
+                    .iter()
+                    .copied()
+                    .chain([("reason", content.as_str())])
+                    .collect::<Vec<_>>();
+                render(template, &values, false)
+            }
+        } else {
+            content
+        };
+
+        // Quote the original message
+        let quote = match template.quote {
+            Quote::None => String::new(),
+            Quote::Subject => values
+                .iter()
+                .find_map(|(name, value)| (*name == "subject").then_some(*value))
+                .unwrap_or_default()
+                .to_string(),
+            Quote::Lines(lines) => ctx
+                .message
+                .text_body
+                .first()
+                .and_then(|part| ctx.message.parts.get(*part))
+                .and_then(|part| match &part.body {
+                    PartType::Text(text) => Some(text.to_string()),
+                    PartType::Html(html) => Some(html_to_text(html)),
+                    _ => None,
+                })
+                .unwrap_or_default()
+                .lines()
+                .take(lines)
+                .collect::<Vec<_>>()
+                .join("\n"),
+        };
+        if !quote.trim().is_empty() {
+            if html_body {
+                let quote = format!("<blockquote>{}</blockquote>", escape_html(quote.trim_end()));
+                if let Some(pos) = body.to_ascii_lowercase().rfind("</body>") {
+                    body.insert_str(pos, &quote);
+                } else {
+                    body.push_str(&quote);
+                }
+            } else {
+                body.push_str("\r\n");
+                for line in quote.trim_end().lines() {
+                    body.push_str("\r\n> ");
+                    body.push_str(line);
+                }
+                body.push_str("\r\n");
+            }
+        }
+
+        Some(if html_body {
+            (html_to_text(&body).into(), body.into())
+        } else {
+            (body.into(), None)
+        })
     }
 }
 
-fn write_header(buf: &mut Vec<u8>, name: &str, value: &str) {
+// Writes a header as RFC 2047 encoded-words, splitting only at character
+// boundaries and folding before each word.
+pub(crate) fn write_encoded_header(
+    buf: &mut Vec<u8>,
+    name: &str,
+    text: &str,
+    address: Option<&str>,
+) {
     buf.extend_from_slice(name.as_bytes());
-    buf.extend_from_slice(value.as_bytes());
+    buf.push(b':');
+    let mut word_start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((pos, ch)) = chars.next() {
+        let word_end = pos + ch.len_utf8();
+        if chars
+            .peek()
+            .is_none_or(|(next, ch)| next + ch.len_utf8() - word_start > MAX_ENCODED_WORD_LEN)
+        {
+            if word_start > 0 {
+                buf.extend_from_slice(b"\r\n");
+            }
+            buf.extend_from_slice(b" =?utf-8?B?");
+            buf.extend_from_slice(
+                &base64_encode(&text.as_bytes()[word_start..word_end]).unwrap_or_default(),
+            );
+            buf.extend_from_slice(b"?=");
+            word_start = word_end;
+        }
+    }
+    if let Some(address) = address {
+        buf.extend_from_slice(b"\r\n <");
+        buf.extend_from_slice(address.as_bytes());
+        buf.push(b'>');
+    }
     buf.extend_from_slice(b"\r\n");
 }
+
+fn parse_address(value: &str) -> Option<(Option<String>, String)> {
+    if let HeaderValue::Address(address) =
+        MessageStream::new(format!("{value}\n").as_bytes()).parse_address()
+    {
+        let addr = address.first()?;
+        Some((
+            addr.name.as_ref().map(|name| name.to_string()),
+            addr.address.as_ref()?.to_string(),
+        ))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::{MessageParser, MimeHeaders};
+
+    use crate::{
+        runtime::template::{Quote, VacationTemplate},
+        Context, Envelope, Event, Runtime,
+    };
+
+    fn run_vacation(runtime: &Runtime, script: &str, headers: &str) -> String {
+        run_vacation_with(runtime, script, headers, false)
+    }
+
+    fn run_vacation_with(runtime: &Runtime, script: &str, headers: &str, lazy: bool) -> String {
+        let raw_message = format!(
+            concat!(
+                "From: =?utf-8?q?J=C3=BCrgen?= <jurgen@example.org>\r\n",
+                "To: jane@example.org\r\n",
+                "{}Subject: Quarterly figures\r\n",
+                "\r\n",
+                "Hi Jane,\r\n",
+                "please review the figures.\r\n",
+                "Thanks\r\n",
+            ),
+            headers
+        );
+        let parser = MessageParser::new();
+        let mut instance = Context::new(
+            runtime,
+            if lazy {
+                parser.parse_headers(raw_message.as_bytes())
+            } else {
+                parser.parse(raw_message.as_bytes())
+            }
+            .unwrap(),
+        )
+        .with_user_address("jane@example.org")
+        .with_vacation_return_date("March 3rd")
+        .with_envelope(Envelope::From, "jurgen@example.org")
+        .with_envelope(Envelope::To, "jane@example.org");
+        if lazy {
+            instance.pending_message = Some(raw_message.as_bytes());
+        }
+
+        let (events, error) = instance.run_script(script);
+        assert!(error.is_none(), "{error:?}");
+        let message = events.into_iter().find_map(|event| match event {
+            Event::CreatedMessage { message, .. } => Some(message),
+            _ => None,
+        });
+        String::from_utf8(message.expect("no vacation reply")).unwrap()
+    }
+
+    #[test]
+    fn vacation_template() {
+        let runtime = Runtime::new().with_vacation_template(
+            VacationTemplate::new()
+                .with_body("Dear {name},\n\n{reason}\n\nBack on {return_date}.")
+                .with_quote(Quote::Lines(2)),
+        );
+        // Quoted lines are also available when the message is parsed lazily
+        for lazy in [false, true] {
+            let reply = run_vacation_with(
+                &runtime,
+                "require \"vacation\"; vacation \"I am away.\";",
+                "",
+                lazy,
+            );
+            let reply = MessageParser::new().parse(reply.as_bytes()).unwrap();
+            assert_eq!(reply.subject(), Some("Auto: Quarterly figures"));
+            assert_eq!(reply.to().unwrap().first().unwrap().name(), Some("Jürgen"));
+            assert_eq!(
+                reply.body_text(0).unwrap().replace("\r\n", "\n"),
+                concat!(
+                    "Dear Jürgen,\n\nI am away.\n\nBack on March 3rd.\n\n",
+                    "> Hi Jane,\n> please review the figures.\n"
+                )
+            );
+            assert!(reply.content_type().unwrap().subtype() == Some("plain"));
+        }
+
+        // HTML templates produce multipart/alternative
+        let runtime = Runtime::new().with_vacation_template(
+            VacationTemplate::new()
+                .with_body("<html><body><p>Dear {name},</p>{reason}</body></html>")
+                .with_quote(Quote::Subject),
+        );
+        let reply = run_vacation(
+            &runtime,
+            "require \"vacation\"; vacation \"I am away <until> Monday.\";",
+            "",
+        );
+        let reply = MessageParser::new().parse(reply.as_bytes()).unwrap();
+        assert_eq!(reply.content_type().unwrap().subtype(), Some("alternative"));
+        let html = reply.body_html(0).unwrap();
+        assert!(
+            html.contains("<p>Dear Jürgen,</p>I am away &lt;until&gt; Monday.")
+                && html.contains("<blockquote>Quarterly figures</blockquote></body>"),
+            "{html}"
+        );
+        assert!(reply
+            .body_text(0)
+            .unwrap()
+            .contains("I am away <until> Monday."));
+
+        // Localized templates override the subject prefix
+        let runtime = Runtime::new().with_localized_vacation_template(
+            "de",
+            VacationTemplate::new()
+                .with_subject_prefix("Automatische Antwort: ")
+                .with_body("Hallo {name},\n\n{reason}"),
+        );
+        let script = "require \"vacation\"; vacation \"Ich bin nicht da.\";";
+        let reply = run_vacation(&runtime, script, "Accept-Language: de-DE, en;q=0.5\r\n");
+        let reply = MessageParser::new().parse(reply.as_bytes()).unwrap();
+        assert_eq!(
+            reply.subject(),
+            Some("Automatische Antwort: Quarterly figures")
+        );
+        assert_eq!(
+            reply.body_text(0).unwrap().trim(),
+            "Hallo Jürgen,\r\n\r\nIch bin nicht da."
+        );
+        let reply = run_vacation(&runtime, script, "Content-Language: en\r\n");
+        let reply = MessageParser::new().parse(reply.as_bytes()).unwrap();
+        assert_eq!(reply.subject(), Some("Auto: Quarterly figures"));
+    }
+}
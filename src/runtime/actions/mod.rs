@@ -25,9 +25,12 @@ pub mod action_convert;
 pub mod action_editheader;
 pub mod action_fileinto;
 pub mod action_flags;
+pub mod action_function;
 pub mod action_include;
 pub mod action_mime;
 pub mod action_notify;
 pub mod action_redirect;
+pub mod action_reject;
 pub mod action_set;
+pub mod action_store;
 pub mod action_vacation;
@@ -0,0 +1,214 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{borrow::Cow, sync::Arc};
+
+use crate::{Context, Envelope, Event, Input, Runtime, Sieve};
+
+use super::{parse_message, RuntimeError};
+
+pub struct FilterMany<'x, I, F> {
+    ctx: Context<'x>,
+    script: Arc<Sieve>,
+    messages: I,
+    handler: F,
+}
+
+impl Runtime {
+    /*
+     Runs a script over each message and its envelope, reusing a single
+     Context. The handler receives every event and returns the input expected
+     by the interpreter, its return value is ignored for actions that require
+     no input.
+    */
+    pub fn filter_many<'z: 'x, 'x, I, E, V, F>(
+        &'z self,
+        script: impl Into<Arc<Sieve>>,
+        messages: I,
+        handler: F,
+    ) -> FilterMany<'x, I::IntoIter, F>
+    where
+        I: IntoIterator<Item = (&'x [u8], E)>,
+        E: IntoIterator<Item = (Envelope, V)>,
+        V: Into<Cow<'x, str>>,
+        F: FnMut(&mut Context<'x>, &Event) -> Input,
+    {
+        FilterMany {
+            ctx: Context::new(self, parse_message(b"")),
+            script: script.into(),
+            messages: messages.into_iter(),
+            handler,
+        }
+    }
+}
+
+impl<'x, I, F> FilterMany<'x, I, F> {
+    pub fn context(&self) -> &Context<'x> {
+        &self.ctx
+    }
+
+    pub fn context_mut(&mut self) -> &mut Context<'x> {
+        &mut self.ctx
+    }
+}
+
+impl<'x, I, E, V, F> Iterator for FilterMany<'x, I, F>
+where
+    I: Iterator<Item = (&'x [u8], E)>,
+    E: IntoIterator<Item = (Envelope, V)>,
+    V: Into<Cow<'x, str>>,
+    F: FnMut(&mut Context<'x>, &Event) -> Input,
+{
+    type Item = Result<Vec<Event>, RuntimeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (raw_message, envelope) = self.messages.next()?;
+        self.ctx.reset(raw_message);
+        for (name, value) in envelope {
+            self.ctx.set_envelope(name, value);
+        }
+
+        let mut events = Vec::new();
+        let mut input = Input::script("filter", self.script.clone());
+        while let Some(result) = self.ctx.run(input) {
+            match result {
+                Ok(event) => {
+                    input = (self.handler)(&mut self.ctx, &event);
+                    events.push(event);
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        }
+
+        Some(Ok(events))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Compiler, Envelope, Event, Input, Mailbox, Runtime};
+
+    #[test]
+    fn filter_many() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["fileinto", "mailbox", "editheader", "variables"];
+set "folder" "";
+if header :matches "subject" "*-*" {
+    set "folder" "${1}";
+}
+if mailboxexists "${folder}" {
+    addheader "X-Folder" "${folder}";
+    fileinto "${folder}";
+}
+"#,
+            )
+            .unwrap();
+        let messages = [
+            &b"Subject: Work-report\r\n\r\nHello\r\n"[..],
+            &b"Subject: Hello\r\n\r\nHello\r\n"[..],
+            &b"Subject: Family-photos\r\n\r\nHello\r\n"[..],
+            &b"Subject: Other\r\n\r\nHello\r\n"[..],
+        ]
+        .map(|message| (message, [(Envelope::To, "jane@example.org")]));
+        let runtime = Runtime::new();
+        let mut lookups = 0;
+
+        let results = runtime
+            .filter_many(script, messages, |_, event| match event {
+                Event::MailboxExists { mailboxes, .. } => {
+                    lookups += 1;
+                    matches!(&mailboxes[..], [Mailbox::Name(name)] if !name.is_empty()).into()
+                }
+                _ => Input::True,
+            })
+            .map(|result| {
+                result
+                    .unwrap()
+                    .into_iter()
+                    .filter_map(|event| match event {
+                        Event::Keep { message_id, .. } => Some(format!("keep:{message_id}")),
+                        Event::FileInto {
+                            folder, message_id, ..
+                        } => Some(format!("{folder}:{message_id}")),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            results,
+            vec![
+                vec!["Work:1".to_string()],
+                vec!["keep:0".to_string()],
+                vec!["Family:1".to_string()],
+                vec!["keep:0".to_string()],
+            ]
+        );
+        assert_eq!(lookups, 4);
+    }
+
+    #[test]
+    fn filter_many_envelope() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["envelope", "fileinto"];
+if envelope :is "from" "boss@example.org" {
+    fileinto "Priority";
+}
+"#,
+            )
+            .unwrap();
+        let messages = [
+            (
+                &b"Subject: Report\r\n\r\nHello\r\n"[..],
+                vec![
+                    (Envelope::From, "boss@example.org"),
+                    (Envelope::To, "jane@example.org"),
+                ],
+            ),
+            (
+                &b"Subject: Hello\r\n\r\nHello\r\n"[..],
+                vec![(Envelope::From, "john@example.org")],
+            ),
+            (
+                &b"Subject: Status\r\n\r\nHello\r\n"[..],
+                vec![(Envelope::From, "boss@example.org")],
+            ),
+            (&b"Subject: Other\r\n\r\nHello\r\n"[..], vec![]),
+        ];
+        let runtime = Runtime::new();
+
+        let results = runtime
+            .filter_many(script, messages, |_, _| Input::True)
+            .map(|result| {
+                result.unwrap().into_iter().any(
+                    |event| matches!(event, Event::FileInto { folder, .. } if folder == "Priority"),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(results, vec![true, false, true, false]);
+    }
+}
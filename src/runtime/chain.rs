@@ -0,0 +1,308 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use ahash::AHashSet;
+
+use crate::{compiler::grammar::Capability, Context, Event, Input, Sieve};
+
+use super::RuntimeError;
+
+/*
+ Runs an ordered list of scripts (for example sieve_before, the personal
+ script and sieve_after) on a single Context. Actions accumulate across
+ scripts, "stop" ends the whole chain and the implicit keep is decided
+ once the last script has finished.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct ScriptChain {
+    scripts: Vec<ChainScript>,
+}
+
+#[derive(Debug, Clone)]
+struct ChainScript {
+    name: String,
+    script: Arc<Sieve>,
+    denied_capabilities: AHashSet<Capability>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainAction {
+    // None when the action is the implicit keep
+    pub script: Option<String>,
+    pub event: Event,
+}
+
+pub struct ChainRun<'y, 'x> {
+    chain: &'y ScriptChain,
+    ctx: &'y mut Context<'x>,
+    next_script: usize,
+    current_script: Option<usize>,
+    final_event_script: Option<usize>,
+    actions: Vec<ChainAction>,
+}
+
+impl ScriptChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_script(&mut self, name: impl Into<String>, script: impl Into<Arc<Sieve>>) {
+        self.add_restricted_script(name, script, Vec::<Capability>::new());
+    }
+
+    pub fn with_script(mut self, name: impl Into<String>, script: impl Into<Arc<Sieve>>) -> Self {
+        self.add_script(name, script);
+        self
+    }
+
+    // Adds a script that may not use the specified capabilities, in addition
+    // to those disabled in the Runtime.
+    pub fn add_restricted_script(
+        &mut self,
+        name: impl Into<String>,
+        script: impl Into<Arc<Sieve>>,
+        without_capabilities: impl IntoIterator<Item = impl Into<Capability>>,
+    ) {
+        self.scripts.push(ChainScript {
+            name: name.into(),
+            script: script.into(),
+            denied_capabilities: without_capabilities.into_iter().map(Into::into).collect(),
+        });
+    }
+
+    pub fn with_restricted_script(
+        mut self,
+        name: impl Into<String>,
+        script: impl Into<Arc<Sieve>>,
+        without_capabilities: impl IntoIterator<Item = impl Into<Capability>>,
+    ) -> Self {
+        self.add_restricted_script(name, script, without_capabilities);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.scripts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scripts.is_empty()
+    }
+
+    pub fn run<'y, 'x>(&'y self, ctx: &'y mut Context<'x>) -> ChainRun<'y, 'x> {
+        ChainRun {
+            chain: self,
+            ctx,
+            next_script: 0,
+            current_script: None,
+            final_event_script: None,
+            actions: Vec::new(),
+        }
+    }
+}
+
+impl<'y, 'x> ChainRun<'y, 'x> {
+    /*
+     Works like Context::run, the input passed to the first call is ignored.
+    */
+    pub fn run(&mut self, input: Input) -> Option<Result<Event, RuntimeError>> {
+        let mut input = input;
+
+        loop {
+            if self.current_script.is_none() {
+                let idx = self.next_script;
+                let script = self.chain.scripts.get(idx)?;
+                self.next_script += 1;
+                self.current_script = Some(idx);
+
+                self.ctx.denied_capabilities = script.denied_capabilities.clone();
+                self.ctx.chain_pending = self.next_script < self.chain.scripts.len();
+                self.ctx.final_event_set = false;
+                input = Input::script(script.name.clone(), script.script.clone());
+            }
+
+            match self.ctx.run(input) {
+                Some(Ok(event)) => {
+                    self.record(&event);
+                    return Some(Ok(event));
+                }
+                Some(Err(err)) => {
+                    // Any pending events are still returned, no further scripts are run
+                    self.next_script = self.chain.scripts.len();
+                    return Some(Err(err));
+                }
+                None => {
+                    if self.ctx.final_event_set {
+                        self.final_event_script = self.current_script;
+                    }
+                    self.current_script = None;
+                    if self.ctx.has_stopped {
+                        self.next_script = self.chain.scripts.len();
+                    }
+                    if self.next_script >= self.chain.scripts.len() {
+                        self.ctx.denied_capabilities.clear();
+                        return None;
+                    }
+                    input = Input::True;
+                }
+            }
+        }
+    }
+
+    fn record(&mut self, event: &Event) {
+        let script = match event {
+            Event::Keep { .. } | Event::Discard => {
+                if self.ctx.final_event_set {
+                    self.current_script
+                } else {
+                    self.final_event_script
+                }
+            }
+            Event::Reject { .. }
+            | Event::FileInto { .. }
+            | Event::SendMessage { .. }
+            | Event::Notify { .. } => self.current_script,
+            _ => return,
+        };
+
+        self.actions.push(ChainAction {
+            script: script.map(|idx| self.chain.scripts[idx].name.clone()),
+            event: event.clone(),
+        });
+    }
+
+    pub fn context(&self) -> &Context<'x> {
+        self.ctx
+    }
+
+    pub fn context_mut(&mut self) -> &mut Context<'x> {
+        self.ctx
+    }
+
+    // Actions executed so far along with the script that caused them
+    pub fn actions(&self) -> &[ChainAction] {
+        &self.actions
+    }
+
+    pub fn into_actions(self) -> Vec<ChainAction> {
+        self.actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::MessageParser;
+
+    use crate::{
+        compiler::grammar::Capability, runtime::RuntimeError, Compiler, Context, Event, Input,
+        Runtime,
+    };
+
+    use super::ScriptChain;
+
+    fn run_chain(chain: &ScriptChain, message: &str) -> (Vec<String>, Option<RuntimeError>) {
+        let runtime = Runtime::new();
+        let mut ctx = Context::new(
+            &runtime,
+            MessageParser::new().parse(message.as_bytes()).unwrap(),
+        );
+        let mut run = chain.run(&mut ctx);
+        let mut error = None;
+        while let Some(result) = run.run(Input::True) {
+            if let Err(err) = result {
+                error = Some(err);
+            }
+        }
+
+        (
+            run.actions()
+                .iter()
+                .map(|action| {
+                    let event = match &action.event {
+                        Event::Keep { .. } => "keep".to_string(),
+                        Event::Discard => "discard".to_string(),
+                        Event::FileInto { folder, .. } => format!("fileinto:{folder}"),
+                        event => format!("{event:?}"),
+                    };
+                    format!("{}:{event}", action.script.as_deref().unwrap_or("implicit"))
+                })
+                .collect(),
+            error,
+        )
+    }
+
+    #[test]
+    fn script_chain() {
+        let compiler = Compiler::new();
+        let before = compiler
+            .compile(
+                br#"require "fileinto";
+if header :contains "subject" "spam" { fileinto "Junk"; stop; }
+if header :contains "subject" "trash" { discard; }
+"#,
+            )
+            .unwrap();
+        let personal = compiler
+            .compile(
+                br#"require "fileinto";
+if header :contains "subject" "report" { fileinto "Work"; }
+if header :contains "subject" "rescue" { keep; }
+"#,
+            )
+            .unwrap();
+        let after = compiler
+            .compile(br#"if header :contains "subject" "copy" { keep; }"#)
+            .unwrap();
+        let chain = ScriptChain::new()
+            .with_script("before", before.clone())
+            .with_script("personal", personal.clone())
+            .with_script("after", after.clone());
+
+        for (subject, expected) in [
+            ("hello", vec!["implicit:keep"]),
+            ("spam report copy", vec!["before:fileinto:Junk"]),
+            ("report", vec!["personal:fileinto:Work"]),
+            ("report copy", vec!["personal:fileinto:Work", "after:keep"]),
+            ("trash", vec!["before:discard"]),
+            ("trash rescue", vec!["personal:keep"]),
+        ] {
+            let (actions, error) =
+                run_chain(&chain, &format!("Subject: {subject}\r\n\r\nHello\r\n"));
+            assert_eq!(actions, expected, "subject: {subject}");
+            assert!(error.is_none());
+        }
+
+        // Capabilities can be restricted per script
+        let chain = ScriptChain::new()
+            .with_script("before", before)
+            .with_restricted_script("personal", personal, [Capability::FileInto])
+            .with_script("after", after);
+        let (actions, error) = run_chain(&chain, "Subject: report copy\r\n\r\nHello\r\n");
+        assert_eq!(actions, vec!["implicit:keep"]);
+        assert!(matches!(
+            error,
+            Some(RuntimeError::CapabilityNotAllowed(Capability::FileInto))
+        ));
+    }
+}
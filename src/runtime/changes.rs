@@ -0,0 +1,347 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use mail_parser::{Header, HeaderValue, MessageParser};
+
+use crate::{Context, HeaderChange, MessageChanges};
+
+impl<'x> Context<'x> {
+    /*
+     Returns the differences between the original and the current message
+     as a list of header insertions and deletions plus, only when the body
+     was modified, the new body. Deletions are listed first, in reverse order
+     so occurrence numbers remain valid, followed by insertions in ascending
+     index order, which maps directly to milter CHGHEADER/INSHEADER/REPLBODY.
+    */
+    pub fn message_changes(&self) -> MessageChanges {
+        let mut changes = MessageChanges::default();
+
+        // Obtain original headers
+        let original_raw = self
+            .original_headers
+            .as_deref()
+            .unwrap_or(self.message.raw_message.as_ref());
+        let original = MessageParser::new()
+            .parse_headers(original_raw)
+            .and_then(|message| message.parts.into_iter().next())
+            .map(|part| {
+                part.headers
+                    .iter()
+                    .map(|header| raw_header(original_raw, header))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        // Obtain current headers, the message is only rebuilt if the body changed
+        let current = if self.body_changed {
+            let message = self.build_message();
+            let mut body_offset = 0;
+            let headers = MessageParser::new()
+                .parse_headers(&message[..])
+                .and_then(|message| message.parts.into_iter().next())
+                .map(|part| {
+                    part.headers
+                        .iter()
+                        .map(|header| {
+                            body_offset = header.offset_end;
+                            raw_header(&message, header)
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+            let body = &message[body_offset..];
+            changes.body = body
+                .strip_prefix(b"\r\n")
+                .or_else(|| body.strip_prefix(b"\n"))
+                .unwrap_or(body)
+                .to_vec()
+                .into();
+            headers
+        } else {
+            self.message
+                .parts
+                .first()
+                .map(|part| {
+                    part.headers
+                        .iter()
+                        .map(|header| {
+                            if header.offset_end != 0 {
+                                (
+                                    header.name.as_str().to_string(),
+                                    unfold(
+                                        &self.message.raw_message
+                                            [header.offset_start..header.offset_end],
+                                    ),
+                                )
+                            } else {
+                                (
+                                    header.name.as_str().to_string(),
+                                    match &header.value {
+                                        HeaderValue::Text(text) => text.trim().to_string(),
+                                        _ => String::new(),
+                                    },
+                                )
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default()
+        };
+
+        // Match unchanged headers
+        let (original_matched, current_matched) = match_headers(&original, &current);
+
+        for (pos, (name, _)) in original.iter().enumerate().rev() {
+            if !original_matched[pos] {
+                changes.headers.push(HeaderChange::Delete {
+                    name: name.clone(),
+                    occurrence: original[..=pos]
+                        .iter()
+                        .filter(|(other, _)| other.eq_ignore_ascii_case(name))
+                        .count(),
+                });
+            }
+        }
+        for (index, (name, value)) in current.into_iter().enumerate() {
+            if !current_matched[index] {
+                changes
+                    .headers
+                    .push(HeaderChange::Insert { index, name, value });
+            }
+        }
+
+        changes
+    }
+}
+
+impl MessageChanges {
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty() && self.body.is_none()
+    }
+}
+
+fn raw_header(raw_message: &[u8], header: &Header) -> (String, String) {
+    (
+        String::from_utf8_lossy(
+            raw_message
+                .get(header.offset_field..header.offset_start.saturating_sub(1))
+                .unwrap_or_default(),
+        )
+        .trim()
+        .to_string(),
+        unfold(
+            raw_message
+                .get(header.offset_start..header.offset_end)
+                .unwrap_or_default(),
+        ),
+    )
+}
+
+fn unfold(value: &[u8]) -> String {
+    let value = String::from_utf8_lossy(value);
+    let mut result = String::with_capacity(value.len());
+    for ch in value.trim().chars() {
+        if !['\r', '\n'].contains(&ch) {
+            result.push(ch);
+        }
+    }
+    result
+}
+
+// Longest common subsequence between the original and current header lists
+fn match_headers(
+    original: &[(String, String)],
+    current: &[(String, String)],
+) -> (Vec<bool>, Vec<bool>) {
+    let mut original_matched = vec![false; original.len()];
+    let mut current_matched = vec![false; current.len()];
+    let is_eq =
+        |a: &(String, String), b: &(String, String)| a.0.eq_ignore_ascii_case(&b.0) && a.1 == b.1;
+
+    // Skip common prefix and suffix
+    let mut start = 0;
+    while start < original.len()
+        && start < current.len()
+        && is_eq(&original[start], &current[start])
+    {
+        original_matched[start] = true;
+        current_matched[start] = true;
+        start += 1;
+    }
+    let mut original_end = original.len();
+    let mut current_end = current.len();
+    while original_end > start
+        && current_end > start
+        && is_eq(&original[original_end - 1], &current[current_end - 1])
+    {
+        original_end -= 1;
+        current_end -= 1;
+        original_matched[original_end] = true;
+        current_matched[current_end] = true;
+    }
+
+    let original = &original[start..original_end];
+    let current = &current[start..current_end];
+    if original.is_empty() || current.is_empty() {
+        return (original_matched, current_matched);
+    }
+
+    let cols = current.len() + 1;
+    let mut lcs = vec![0u32; (original.len() + 1) * cols];
+    for i in (0..original.len()).rev() {
+        for j in (0..current.len()).rev() {
+            lcs[i * cols + j] = if is_eq(&original[i], &current[j]) {
+                lcs[(i + 1) * cols + j + 1] + 1
+            } else {
+                lcs[(i + 1) * cols + j].max(lcs[i * cols + j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < original.len() && j < current.len() {
+        if is_eq(&original[i], &current[j]) {
+            original_matched[start + i] = true;
+            current_matched[start + j] = true;
+            i += 1;
+            j += 1;
+        } else if lcs[(i + 1) * cols + j] >= lcs[i * cols + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+
+    (original_matched, current_matched)
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::MessageParser;
+
+    use crate::{Context, HeaderChange, MessageChanges, Runtime};
+
+    const MESSAGE: &str = concat!(
+        "Received: from host-a\r\n",
+        "Received: from host-b\r\n",
+        "From: john@example.org\r\n",
+        "Subject: Hello\r\n",
+        "\r\n",
+        "Body text\r\n"
+    );
+
+    fn message_changes(script: &str) -> MessageChanges {
+        let runtime = Runtime::new();
+        let mut instance = Context::new(
+            &runtime,
+            MessageParser::new().parse(MESSAGE.as_bytes()).unwrap(),
+        );
+        assert!(instance.run_script(script).1.is_none());
+        instance.message_changes()
+    }
+
+    #[test]
+    fn header_changes() {
+        assert!(message_changes("keep;").is_empty());
+
+        let changes = message_changes(concat!(
+            "require \"editheader\";\n",
+            "addheader \"X-Spam\" \"yes\";\n",
+            "addheader :last \"X-Last\" \"1\";\n",
+            "deleteheader :index 2 \"Received\";\n",
+        ));
+        assert_eq!(changes.body, None);
+        assert_eq!(
+            changes.headers,
+            vec![
+                HeaderChange::Delete {
+                    name: "Received".into(),
+                    occurrence: 2
+                },
+                HeaderChange::Insert {
+                    index: 0,
+                    name: "X-Spam".into(),
+                    value: "yes".into()
+                },
+                HeaderChange::Insert {
+                    index: 4,
+                    name: "X-Last".into(),
+                    value: "1".into()
+                },
+            ]
+        );
+
+        let changes = message_changes(concat!(
+            "require \"editheader\";\n",
+            "deleteheader \"Received\";\n",
+        ));
+        assert_eq!(
+            changes.headers,
+            vec![
+                HeaderChange::Delete {
+                    name: "Received".into(),
+                    occurrence: 2
+                },
+                HeaderChange::Delete {
+                    name: "Received".into(),
+                    occurrence: 1
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn body_changes() {
+        let changes = message_changes(concat!(
+            "require \"mime\";\n",
+            "require \"replace\";\n",
+            "replace :subject \"New subject\" \"New body\";\n",
+        ));
+        assert_eq!(changes.body.as_deref(), Some(&b"New body"[..]));
+        assert!(changes.headers.contains(&HeaderChange::Delete {
+            name: "Subject".into(),
+            occurrence: 1
+        }));
+        assert!(changes.headers.contains(&HeaderChange::Insert {
+            index: 3,
+            name: "Original-Subject".into(),
+            value: "Hello".into()
+        }));
+
+        let changes = message_changes(concat!(
+            "require \"enclose\";\n",
+            "enclose :subject \"Enclosed\" \"See attached\";\n",
+        ));
+        let body = String::from_utf8(changes.body.unwrap()).unwrap();
+        assert!(body.starts_with("--"), "{body}");
+        assert!(body.contains(MESSAGE), "{body}");
+        assert_eq!(
+            changes
+                .headers
+                .iter()
+                .filter(|change| matches!(change, HeaderChange::Delete { .. }))
+                .count(),
+            4
+        );
+    }
+}
@@ -21,29 +21,43 @@
  * for more details.
 */
 
-use std::{borrow::Cow, sync::Arc, time::SystemTime};
+use std::{
+    borrow::Cow,
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
 
-use ahash::AHashMap;
-use mail_parser::Message;
+use ahash::{AHashMap, AHashSet};
+use mail_parser::{Message, MessageParser};
 
 use crate::{
-    compiler::grammar::{instruction::Instruction, Capability},
+    compiler::{
+        grammar::{instruction::Instruction, Capability, ForEach},
+        VariableType,
+    },
     Context, Envelope, Event, Input, Metadata, Runtime, Sieve, SpamStatus, VirusStatus,
     MAX_LOCAL_VARIABLES, MAX_MATCH_VARIABLES,
 };
 
 use super::{
     actions::action_include::IncludeResult,
+    expression::Interrupt,
+    parse_message,
     tests::{test_envelope::parse_envelope_address, TestResult},
     RuntimeError, Variable,
 };
 
+const REGEX_COST: usize = 10;
+const BODY_SCAN_COST: usize = 10;
+const BODY_SCAN_BLOCK_SIZE: usize = 64 * 1024;
+
 #[derive(Clone, Debug)]
 pub(crate) struct ScriptStack {
     pub(crate) script: Arc<Sieve>,
     pub(crate) prev_pos: usize,
     pub(crate) prev_vars_local: Vec<Variable>,
     pub(crate) prev_vars_match: Vec<Variable>,
+    pub(crate) prev_expr: Option<(Vec<Variable>, usize)>,
 }
 
 impl<'x> Context<'x> {
@@ -71,6 +85,7 @@ impl<'x> Context<'x> {
             envelope: Vec::new(),
             metadata: Vec::new(),
             message_size: usize::MAX,
+            pending_message: None,
             final_event: Event::Keep {
                 flags: Vec::with_capacity(0),
                 message_id: 0,
@@ -78,15 +93,26 @@ impl<'x> Context<'x> {
             .into(),
             queued_events: vec![].into_iter(),
             has_changes: false,
+            body_changed: false,
+            original_headers: None,
+            message_checksum: None,
             user_address: "".into(),
             user_full_name: "".into(),
+            vacation_return_date: "".into(),
             current_time: SystemTime::now()
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .map(|d| d.as_secs())
                 .unwrap_or(0) as i64,
             num_redirects: 0,
             num_instructions: 0,
+            memory_used: 0,
+            deadline: None,
             num_out_messages: 0,
+            policy_violations: Vec::new(),
+            denied_capabilities: AHashSet::new(),
+            chain_pending: false,
+            has_stopped: false,
+            final_event_set: false,
             last_message_id: 0,
             main_message_id: 0,
             virus_status: VirusStatus::Unknown,
@@ -110,6 +136,9 @@ impl<'x> Context<'x> {
                     if self.message_size == usize::MAX {
                         self.message_size = self.message.raw_message.len();
                     }
+                    if self.deadline.is_none() {
+                        self.deadline = self.runtime.time_limit.map(|limit| Instant::now() + limit);
+                    }
 
                     self.script_cache.insert(name, script.clone());
                     self.script_stack.push(ScriptStack {
@@ -123,6 +152,7 @@ impl<'x> Context<'x> {
                             &mut self.vars_match,
                             vec![Variable::default(); num_match_vars],
                         ),
+                        prev_expr: None,
                     });
                     self.pos = 0;
                     self.test_result = false;
@@ -140,13 +170,28 @@ impl<'x> Context<'x> {
 
         'outer: loop {
             while let Some(instruction) = iter.next() {
-                self.num_instructions += 1;
+                self.num_instructions += self.instruction_cost(instruction);
                 if self.num_instructions > self.runtime.cpu_limit {
                     self.finish_loop();
                     return Some(Err(RuntimeError::CPULimitReached));
+                } else if self.memory_used > self.runtime.memory_limit {
+                    self.finish_loop();
+                    return Some(Err(RuntimeError::MemoryLimitReached));
+                } else if self.is_past_deadline() {
+                    self.finish_loop();
+                    return Some(Err(RuntimeError::TimeLimitReached));
                 }
                 self.pos += 1;
 
+                if self.pending_message.is_some()
+                    && current_script
+                        .message_refs
+                        .binary_search(&(self.pos - 1))
+                        .is_ok()
+                {
+                    self.parse_message();
+                }
+
                 match instruction {
                     Instruction::Jz(jmp_pos) => {
                         if !self.test_result {
@@ -187,9 +232,17 @@ impl<'x> Context<'x> {
                         Ok(result) => {
                             self.test_result = result.to_bool();
                         }
-                        Err(event) => {
+                        Err(Interrupt::Call) => {
+                            iter = current_script.instructions.get(self.pos..)?.iter();
+                            continue;
+                        }
+                        Err(Interrupt::Event(event)) => {
                             return Some(Ok(event));
                         }
+                        Err(Interrupt::Error(err)) => {
+                            self.finish_loop();
+                            return Some(Err(err));
+                        }
                     },
                     Instruction::Clear(clear) => {
                         if clear.local_vars_num > 0 {
@@ -217,35 +270,42 @@ impl<'x> Context<'x> {
                             message_id: self.main_message_id,
                         }
                         .into();
+                        self.final_event_set = true;
                         if let Some(next_event) = next_event {
                             return Some(Ok(next_event));
                         }
                     }
                     Instruction::FileInto(fi) => {
-                        fi.exec(self);
+                        if let Err(err) = fi.exec(self) {
+                            self.finish_loop();
+                            return Some(Err(err));
+                        }
                         if let Some(event) = self.queued_events.next() {
                             return Some(Ok(event));
                         }
                     }
                     Instruction::Redirect(redirect) => {
-                        redirect.exec(self);
+                        if let Err(err) = redirect.exec(self) {
+                            self.finish_loop();
+                            return Some(Err(err));
+                        }
                         if let Some(event) = self.queued_events.next() {
                             return Some(Ok(event));
                         }
                     }
                     Instruction::Discard => {
                         self.final_event = Event::Discard.into();
+                        self.final_event_set = true;
                     }
                     Instruction::Stop => {
                         self.script_stack.clear();
+                        self.chain_pending = false;
+                        self.has_stopped = true;
                         break 'outer;
                     }
                     Instruction::Reject(reject) => {
                         self.final_event = None;
-                        return Some(Ok(Event::Reject {
-                            extended: reject.ereject,
-                            reason: self.eval_value(&reject.reason).to_string().into_owned(),
-                        }));
+                        return Some(Ok(reject.exec(self)));
                     }
                     Instruction::ForEveryPart(fep) => {
                         if let Some(next_part) = self.part_iter.next() {
@@ -296,23 +356,62 @@ impl<'x> Context<'x> {
                                 continue;
                             }
                         }
-                        Err(event) => {
+                        Err(Interrupt::Call) => {
+                            iter = current_script.instructions.get(self.pos..)?.iter();
+                            continue;
+                        }
+                        Err(Interrupt::Event(event)) => {
+                            return Some(Ok(event));
+                        }
+                        Err(Interrupt::Error(err)) => {
+                            self.finish_loop();
+                            return Some(Err(err));
+                        }
+                    },
+                    Instruction::ForEach(foreach) => match self.foreach_next(foreach) {
+                        Ok(true) => (),
+                        Ok(false) => {
+                            debug_assert!(foreach.jz_pos > self.pos - 1);
+                            self.pos = foreach.jz_pos;
+                            iter = current_script.instructions.get(self.pos..)?.iter();
+                            continue;
+                        }
+                        Err(Interrupt::Call) => {
+                            iter = current_script.instructions.get(self.pos..)?.iter();
+                            continue;
+                        }
+                        Err(Interrupt::Event(event)) => {
                             return Some(Ok(event));
                         }
+                        Err(Interrupt::Error(err)) => {
+                            self.finish_loop();
+                            return Some(Err(err));
+                        }
                     },
                     Instruction::Let(let_) => match self.eval_expression(&let_.expr) {
                         Ok(result) => {
                             self.set_variable(&let_.name, result);
                         }
-                        Err(event) => {
+                        Err(Interrupt::Call) => {
+                            iter = current_script.instructions.get(self.pos..)?.iter();
+                            continue;
+                        }
+                        Err(Interrupt::Event(event)) => {
                             return Some(Ok(event));
                         }
+                        Err(Interrupt::Error(err)) => {
+                            self.finish_loop();
+                            return Some(Err(err));
+                        }
                     },
 
                     Instruction::Replace(replace) => replace.exec(self),
                     Instruction::Enclose(enclose) => enclose.exec(self),
                     Instruction::ExtractText(extract) => {
-                        extract.exec(self);
+                        if let Err(err) = extract.exec(self) {
+                            self.finish_loop();
+                            return Some(Err(err));
+                        }
                         if let Some(event) = self.queued_events.next() {
                             return Some(Ok(event));
                         }
@@ -326,18 +425,25 @@ impl<'x> Context<'x> {
                         }
                     }
                     Instruction::Notify(notify) => {
-                        notify.exec(self);
+                        if let Err(err) = notify.exec(self) {
+                            self.finish_loop();
+                            return Some(Err(err));
+                        }
                         if let Some(event) = self.queued_events.next() {
                             return Some(Ok(event));
                         }
                     }
                     Instruction::Vacation(vacation) => {
-                        vacation.exec(self);
+                        if let Err(err) = vacation.exec(self) {
+                            self.finish_loop();
+                            return Some(Err(err));
+                        }
                         if let Some(event) = self.queued_events.next() {
                             return Some(Ok(event));
                         }
                     }
                     Instruction::EditFlags(flags) => flags.exec(self),
+                    Instruction::Store(store) => store.exec(self),
                     Instruction::Include(include) => match include.exec(self) {
                         IncludeResult::Cached(script) => {
                             self.script_stack.push(ScriptStack {
@@ -351,6 +457,7 @@ impl<'x> Context<'x> {
                                     &mut self.vars_match,
                                     vec![Variable::default(); script.num_match_vars],
                                 ),
+                                prev_expr: None,
                             });
                             self.pos = 0;
                             current_script = script;
@@ -372,9 +479,27 @@ impl<'x> Context<'x> {
                     Instruction::Return => {
                         break;
                     }
+                    Instruction::FncReturn(expr) => match self.eval_expression(expr) {
+                        Ok(result) => {
+                            self.return_function(result);
+                            iter = current_script.instructions.get(self.pos..)?.iter();
+                            continue;
+                        }
+                        Err(Interrupt::Call) => {
+                            iter = current_script.instructions.get(self.pos..)?.iter();
+                            continue;
+                        }
+                        Err(Interrupt::Event(event)) => {
+                            return Some(Ok(event));
+                        }
+                        Err(Interrupt::Error(err)) => {
+                            self.finish_loop();
+                            return Some(Err(err));
+                        }
+                    },
                     Instruction::Require(capabilities) => {
                         for capability in capabilities {
-                            if !self.runtime.allowed_capabilities.contains(capability) {
+                            if !self.is_capability_allowed(capability) {
                                 self.finish_loop();
                                 return Some(Err(
                                     if let Capability::Other(not_supported) = capability {
@@ -423,6 +548,11 @@ impl<'x> Context<'x> {
             }
         }
 
+        // More scripts follow in a chain, the final action is decided later
+        if self.chain_pending {
+            return None;
+        }
+
         match self.final_event.take() {
             Some(Event::Keep {
                 mut flags,
@@ -557,6 +687,16 @@ impl<'x> Context<'x> {
         self
     }
 
+    // Value of the {return_date} placeholder in vacation templates.
+    pub fn set_vacation_return_date(&mut self, date: impl Into<Cow<'x, str>>) {
+        self.vacation_return_date = date.into();
+    }
+
+    pub fn with_vacation_return_date(mut self, date: impl Into<Cow<'x, str>>) -> Self {
+        self.set_vacation_return_date(date);
+        self
+    }
+
     pub fn set_env_variable(
         &mut self,
         name: impl Into<Cow<'static, str>>,
@@ -627,9 +767,155 @@ impl<'x> Context<'x> {
     }
 
     pub fn take_message(&mut self) -> Message<'x> {
+        self.parse_message();
         std::mem::take(&mut self.message)
     }
 
+    // Parses the MIME structure of a message that was loaded lazily,
+    // keeping any changes made to the top-level headers.
+    pub(crate) fn parse_message(&mut self) {
+        if let Some(raw_message) = self.pending_message.take() {
+            if let Some(mut message) = MessageParser::new().parse(raw_message) {
+                if let (Some(part), Some(headers)) =
+                    (message.parts.first_mut(), self.message.parts.first_mut())
+                {
+                    part.headers = std::mem::take(&mut headers.headers);
+                }
+                self.message = message;
+            }
+        }
+    }
+
+    /*
+     Prepares the context for filtering another message. Script caches,
+     environment variables, user settings and metadata are kept, all per
+     message state is cleared without releasing allocated buffers.
+    */
+    pub fn reset(&mut self, raw_message: &'x [u8]) {
+        self.message = parse_message(raw_message);
+        self.message_size = usize::MAX;
+        self.pending_message = None;
+        self.envelope.clear();
+        self.part = 0;
+        self.part_iter = Vec::new().into_iter();
+        self.part_iter_stack.clear();
+        self.spam_status = SpamStatus::Unknown;
+        self.virus_status = VirusStatus::Unknown;
+        self.pos = usize::MAX;
+        self.test_result = false;
+        self.script_stack.clear();
+        self.vars_global.clear();
+        self.vars_local.clear();
+        self.vars_match.clear();
+        self.expr_stack.clear();
+        self.expr_pos = 0;
+        self.queued_events = Vec::new().into_iter();
+        self.final_event = Event::Keep {
+            flags: Vec::with_capacity(0),
+            message_id: 0,
+        }
+        .into();
+        self.last_message_id = 0;
+        self.main_message_id = 0;
+        self.has_changes = false;
+        self.body_changed = false;
+        self.original_headers = None;
+        self.message_checksum = None;
+        self.num_redirects = 0;
+        self.num_instructions = 0;
+        self.memory_used = 0;
+        self.deadline = None;
+        self.num_out_messages = 0;
+        self.policy_violations.clear();
+        self.denied_capabilities.clear();
+        self.chain_pending = false;
+        self.has_stopped = false;
+        self.final_event_set = false;
+        self.current_time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0) as i64;
+    }
+
+    fn instruction_cost(&self, instruction: &Instruction) -> usize {
+        if instruction.is_body_scan() {
+            // Scanning the body costs more as the message grows
+            let message_size = if self.message_size != usize::MAX {
+                self.message_size
+            } else {
+                self.message.raw_message.len()
+            };
+            BODY_SCAN_COST + message_size / BODY_SCAN_BLOCK_SIZE
+        } else if instruction.uses_regex() {
+            REGEX_COST
+        } else {
+            1
+        }
+    }
+
+    // Assigns the next item of a foreach loop, evaluating the list on entry.
+    // Returns false once all items have been visited.
+    fn foreach_next(&mut self, foreach: &ForEach) -> Result<bool, Interrupt> {
+        let pos = self
+            .variable(&foreach.counter)
+            .map_or(0, |counter| counter.to_integer());
+        if pos < 0 {
+            let list = Variable::Array(self.eval_expression(&foreach.expr)?.into_array());
+            self.use_memory(list.len());
+            if let VariableType::Local(list_id) = &foreach.list {
+                if let Some(var) = self.vars_local.get_mut(*list_id) {
+                    *var = list;
+                }
+            }
+        }
+
+        let pos = pos.max(0);
+        let item = self
+            .variable(&foreach.list)
+            .and_then(|list| list.as_array()?.get(pos as usize).cloned());
+        if let Some(item) = item {
+            self.set_variable(&foreach.item, item);
+            if let Some(index) = &foreach.index {
+                self.set_variable(index, Variable::Integer(pos));
+            }
+            self.set_variable(&foreach.counter, Variable::Integer(pos + 1));
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    pub(crate) fn use_memory(&mut self, size: usize) {
+        self.memory_used = self.memory_used.saturating_add(size);
+    }
+
+    pub(crate) fn is_past_deadline(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = deadline.into();
+    }
+
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.set_deadline(deadline);
+        self
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.memory_used
+    }
+
+    pub(crate) fn is_capability_allowed(&self, capability: &Capability) -> bool {
+        self.runtime.allowed_capabilities.contains(capability)
+            && !self.denied_capabilities.contains(capability)
+    }
+
+    pub fn is_message_parsed(&self) -> bool {
+        self.pending_message.is_none()
+    }
+
     pub fn has_message_changed(&self) -> bool {
         self.main_message_id > 0
     }
@@ -681,6 +967,7 @@ impl<'x> Context<'x> {
             envelope: Vec::new(),
             metadata: Vec::new(),
             message_size: usize::MAX,
+            pending_message: None,
             final_event: Event::Keep {
                 flags: Vec::with_capacity(0),
                 message_id: 0,
@@ -688,15 +975,26 @@ impl<'x> Context<'x> {
             .into(),
             queued_events: vec![].into_iter(),
             has_changes: false,
+            body_changed: false,
+            original_headers: None,
+            message_checksum: None,
             user_address: "".into(),
             user_full_name: "".into(),
+            vacation_return_date: "".into(),
             current_time: SystemTime::now()
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .map(|d| d.as_secs())
                 .unwrap_or(0) as i64,
             num_redirects: 0,
             num_instructions: 0,
+            memory_used: 0,
+            deadline: None,
             num_out_messages: 0,
+            policy_violations: Vec::new(),
+            denied_capabilities: AHashSet::new(),
+            chain_pending: false,
+            has_stopped: false,
+            final_event_set: false,
             last_message_id: 0,
             main_message_id: 0,
             virus_status: VirusStatus::Unknown,
@@ -704,3 +1002,215 @@ impl<'x> Context<'x> {
         }
     }
 }
+
+#[cfg(test)]
+impl Context<'_> {
+    // Compiles and runs a script to completion for unit tests
+    pub(crate) fn run_script(&mut self, script: &str) -> (Vec<Event>, Option<RuntimeError>) {
+        self.run_input(Input::script(
+            "test",
+            crate::Compiler::new().compile(script.as_bytes()).unwrap(),
+        ))
+    }
+
+    // Runs until the script finishes, answering duplicate checks with false
+    // and any other event with true. Returns all events and the first error.
+    pub(crate) fn run_input(&mut self, mut input: Input) -> (Vec<Event>, Option<RuntimeError>) {
+        let mut events = Vec::new();
+        let mut error = None;
+        while let Some(result) = self.run(input) {
+            input = Input::True;
+            match result {
+                Ok(event) => {
+                    if matches!(event, Event::DuplicateId { .. }) {
+                        input = Input::False;
+                    }
+                    events.push(event);
+                }
+                Err(err) => {
+                    error.get_or_insert(err);
+                }
+            }
+        }
+        (events, error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use mail_parser::MessageParser;
+
+    use crate::{
+        compiler::grammar::{instruction::Instruction, Capability},
+        runtime::{tests::TestResult, RuntimeError},
+        Compiler, Context, Event, Runtime,
+    };
+
+    const MESSAGE: &[u8] = concat!(
+        "From: john@example.org\r\n",
+        "Subject: Report\r\n",
+        "Content-Type: multipart/mixed; boundary=\"b\"\r\n",
+        "\r\n",
+        "--b\r\n",
+        "Content-Type: text/plain\r\n",
+        "\r\n",
+        "Quarterly figures\r\n",
+        "--b--\r\n"
+    )
+    .as_bytes();
+
+    fn run_lazy(script: &str) -> (bool, Vec<Event>) {
+        let runtime = Runtime::new();
+        let mut instance = Context::new(
+            &runtime,
+            MessageParser::new().parse_headers(MESSAGE).unwrap(),
+        );
+        instance.pending_message = Some(MESSAGE);
+        let (events, error) = instance.run_script(script);
+        assert!(error.is_none());
+        (instance.is_message_parsed(), events)
+    }
+
+    #[test]
+    fn lazy_message_parsing() {
+        // Header tests do not parse the body
+        let (is_parsed, events) = run_lazy(concat!(
+            "require [\"fileinto\", \"editheader\", \"body\"];\n",
+            "if header :contains \"subject\" \"report\" {\n",
+            "    addheader \"X-Report\" \"yes\";\n",
+            "    fileinto \"Reports\";\n",
+            "    stop;\n",
+            "}\n",
+            "if body :contains \"figures\" { discard; }\n",
+        ));
+        assert!(!events.is_empty());
+        assert!(is_parsed, "building the modified message requires parsing");
+
+        let (is_parsed, _) = run_lazy(concat!(
+            "require [\"fileinto\", \"body\"];\n",
+            "if header :contains \"subject\" \"report\" {\n",
+            "    fileinto \"Reports\";\n",
+            "    stop;\n",
+            "}\n",
+            "if body :contains \"figures\" { discard; }\n",
+        ));
+        assert!(!is_parsed);
+
+        // Body tests and part variables parse the message on demand
+        let (is_parsed, events) = run_lazy(concat!(
+            "require [\"body\", \"fileinto\"];\n",
+            "if body :contains \"figures\" { fileinto \"Figures\"; }\n",
+        ));
+        assert!(is_parsed);
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Event::FileInto { folder, .. } if folder == "Figures")));
+
+        let (is_parsed, events) = run_lazy(concat!(
+            "require [\"variables\", \"fileinto\"];\n",
+            "if string :contains \"${body.text}\" \"figures\" { fileinto \"Figures\"; }\n",
+        ));
+        assert!(is_parsed);
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Event::FileInto { folder, .. } if folder == "Figures")));
+    }
+
+    fn run_limited(
+        runtime: &Runtime,
+        script: &str,
+        deadline: Option<Instant>,
+    ) -> Option<RuntimeError> {
+        let mut instance = Context::new(runtime, MessageParser::new().parse(MESSAGE).unwrap());
+        if let Some(deadline) = deadline {
+            instance.set_deadline(deadline);
+        }
+        instance.run_script(script).1
+    }
+
+    #[test]
+    fn runtime_limits() {
+        // Memory used by variables is cumulative
+        let script = concat!(
+            "require \"variables\";\n",
+            "set \"a\" \"0123456789012345678901234567890123456789\";\n",
+            "set \"b\" \"${a}${a}\";\n",
+            "set \"c\" \"${b}\";\n",
+        );
+        assert!(run_limited(&Runtime::new().with_memory_limit(200), script, None).is_none());
+        assert!(matches!(
+            run_limited(&Runtime::new().with_memory_limit(100), script, None),
+            Some(RuntimeError::MemoryLimitReached)
+        ));
+
+        // Regular expressions and body scans are weighted
+        let script = concat!(
+            "require [\"body\", \"regex\"];\n",
+            "if body :regex \"fig.*es\" { discard; }\n",
+        );
+        assert!(run_limited(&Runtime::new().with_cpu_limit(30), script, None).is_none());
+        assert!(matches!(
+            run_limited(&Runtime::new().with_cpu_limit(10), script, None),
+            Some(RuntimeError::CPULimitReached)
+        ));
+        let script = "if header :contains \"subject\" \"report\" { discard; }";
+        assert!(run_limited(&Runtime::new().with_cpu_limit(10), script, None).is_none());
+        let script = concat!(
+            "require \"vnd.stalwart.expressions\";\n",
+            "if eval \"regex_match('report', 'r.*t')\" { discard; }\n",
+        );
+        let runtime = Runtime::new()
+            .with_capability(Capability::Expressions)
+            .with_cpu_limit(5);
+        assert!(matches!(
+            run_limited(&runtime, script, None),
+            Some(RuntimeError::CPULimitReached)
+        ));
+        let script = concat!(
+            "require \"vnd.stalwart.expressions\";\n",
+            "if eval \"matches('report', 'r*t')\" { discard; }\n",
+        );
+        assert!(run_limited(&runtime, script, None).is_none());
+
+        // Wall-clock deadline
+        assert!(matches!(
+            run_limited(&Runtime::new(), script, Some(Instant::now())),
+            Some(RuntimeError::TimeLimitReached)
+        ));
+    }
+
+    #[test]
+    fn deadline_during_instruction() {
+        let runtime = Runtime::new();
+        for script in [
+            "require \"regex\";\nif header :regex \"subject\" \"r.*t\" { discard; }\n",
+            "require [\"variables\", \"regex\"];\nif string :regex \"${header.subject}\" \"r.*t\" { discard; }\n",
+            "require [\"foreverypart\", \"extracttext\", \"variables\"];\nforeverypart { extracttext :lower \"text\"; }\n",
+        ] {
+            let script = Compiler::new().compile(script.as_bytes()).unwrap();
+            let mut instance = Context::new(&runtime, MessageParser::new().parse(MESSAGE).unwrap());
+            instance.set_deadline(Instant::now());
+
+            // The run loop checks the deadline between instructions, evaluate
+            // a single instruction to make sure long running ones check it too
+            let result = script.instructions.iter().find_map(|instruction| match instruction {
+                Instruction::Test(test) => Some(matches!(
+                    test.exec(&mut instance),
+                    TestResult::Error(RuntimeError::TimeLimitReached)
+                )),
+                Instruction::ExtractText(extract) => {
+                    instance.part = 1;
+                    instance.part_iter_stack.push((0, Vec::new().into_iter()));
+                    Some(matches!(
+                        extract.exec(&mut instance),
+                        Err(RuntimeError::TimeLimitReached)
+                    ))
+                }
+                _ => None,
+            });
+            assert_eq!(result, Some(true));
+        }
+    }
+}
@@ -0,0 +1,160 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::borrow::Cow;
+
+use crate::{Context, DeliveryInfo, DeliveryLocation, DeliveryPhase};
+
+use super::Variable;
+
+/*
+ Every environment item that can be populated from a DeliveryInfo
+*/
+const DELIVERY_ENV_ITEMS: &[&str] = &[
+    "domain",
+    "host",
+    "location",
+    "phase",
+    "remote-host",
+    "remote-ip",
+    "vnd.stalwart.helo",
+    "vnd.stalwart.auth-user",
+    "vnd.stalwart.tls",
+    "vnd.stalwart.tls-version",
+    "vnd.stalwart.tls-cipher",
+    "vnd.stalwart.queue-id",
+];
+
+impl DeliveryInfo {
+    /*
+     RFC 5183 standard items followed by vendor specific items
+    */
+    pub fn env_items(&self) -> Vec<(Cow<'static, str>, Variable)> {
+        let mut items: Vec<(Cow<'static, str>, Variable)> = Vec::new();
+
+        for (name, value) in [
+            ("domain", self.domain.as_deref()),
+            ("host", self.host.as_deref()),
+            ("location", self.location.map(|l| l.as_str())),
+            ("phase", self.phase.map(|p| p.as_str())),
+            ("remote-host", self.remote_host.as_deref()),
+            ("vnd.stalwart.helo", self.helo.as_deref()),
+            ("vnd.stalwart.auth-user", self.auth_user.as_deref()),
+            (
+                "vnd.stalwart.tls-version",
+                self.tls.as_ref().map(|tls| tls.version.as_str()),
+            ),
+            (
+                "vnd.stalwart.tls-cipher",
+                self.tls.as_ref().map(|tls| tls.cipher.as_str()),
+            ),
+            ("vnd.stalwart.queue-id", self.queue_id.as_deref()),
+        ] {
+            if let Some(value) = value {
+                items.push((name.into(), value.to_string().into()));
+            }
+        }
+        if let Some(remote_ip) = &self.remote_ip {
+            items.push(("remote-ip".into(), remote_ip.to_string().into()));
+        }
+        items.push((
+            "vnd.stalwart.tls".into(),
+            Variable::Integer(self.tls.is_some() as i64),
+        ));
+
+        items
+    }
+}
+
+impl DeliveryLocation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryLocation::Mta => "MTA",
+            DeliveryLocation::Mda => "MDA",
+            DeliveryLocation::Ms => "MS",
+            DeliveryLocation::Mua => "MUA",
+        }
+    }
+}
+
+impl DeliveryPhase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryPhase::Pre => "pre",
+            DeliveryPhase::During => "during",
+            DeliveryPhase::Post => "post",
+        }
+    }
+}
+
+impl Context<'_> {
+    pub fn set_delivery_info(&mut self, info: &DeliveryInfo) {
+        // Drop items left over from a previous delivery before adding the new ones
+        for name in DELIVERY_ENV_ITEMS {
+            self.vars_env.remove(*name);
+        }
+        self.vars_env.extend(info.env_items());
+    }
+
+    pub fn with_delivery_info(mut self, info: &DeliveryInfo) -> Self {
+        self.set_delivery_info(info);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::MessageParser;
+
+    use crate::{Context, DeliveryInfo, Runtime};
+
+    #[test]
+    fn replaces_previous_delivery_info() {
+        let runtime = Runtime::new();
+        let mut ctx = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(b"Subject: test\r\n\r\nTest\r\n")
+                .unwrap(),
+        );
+        ctx.set_delivery_info(&DeliveryInfo {
+            remote_host: "mx.example.org".to_string().into(),
+            helo: "mx.example.org".to_string().into(),
+            queue_id: "ABC123".to_string().into(),
+            ..Default::default()
+        });
+        ctx.set_env_variable("name", "sieve");
+        ctx.set_delivery_info(&DeliveryInfo {
+            domain: "example.com".to_string().into(),
+            ..Default::default()
+        });
+
+        let mut names = ctx
+            .vars_env
+            .keys()
+            .map(|name| name.as_ref())
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(names, ["domain", "name", "vnd.stalwart.tls"]);
+    }
+}
@@ -31,13 +31,13 @@ use mail_parser::{
 
 use crate::{
     compiler::{
-        ContentTypePart, HeaderPart, HeaderVariable, MessagePart, ReceivedHostname, ReceivedPart,
-        Value, VariableType,
+        AuthResultsPart, ContentTypePart, HeaderPart, HeaderVariable, MessagePart,
+        ReceivedHostname, ReceivedPart, Value, VariableType,
     },
     Context,
 };
 
-use super::Variable;
+use super::{tests::authresults::AuthResults, Variable};
 
 impl<'x> Context<'x> {
     pub(crate) fn variable<'y: 'x>(&'y self, var: &VariableType) -> Option<Variable> {
@@ -50,6 +50,11 @@ impl<'x> Context<'x> {
                 .get(var_name.as_str())
                 .or_else(|| self.runtime.environment.get(var_name.as_str()))
                 .cloned(),
+            VariableType::Store(key) => self
+                .runtime
+                .store
+                .as_ref()?
+                .get(&self.user_address, key.as_str()),
             VariableType::Envelope(envelope) => {
                 self.envelope.iter().find_map(
                     |(e, v)| {
@@ -139,7 +144,31 @@ impl<'x> Context<'x> {
         let mut result = Vec::new();
         let part = self.message.part(self.part)?;
         let raw = self.message.raw_message();
-        if !header.name.is_empty() {
+        if let HeaderPart::AuthResults(auth_part) = &header.part {
+            // Only results from trusted authentication servers are exposed
+            let auth_results = self.trusted_auth_results();
+            let mut auth_results = auth_results.iter();
+            match header.index_hdr.cmp(&0) {
+                Ordering::Greater => {
+                    if let Some(ar) = auth_results.nth((header.index_hdr - 1) as usize) {
+                        header.eval_auth_results(ar, auth_part, &mut result);
+                    }
+                }
+                Ordering::Less => {
+                    if let Some(ar) = auth_results
+                        .rev()
+                        .nth((header.index_hdr.unsigned_abs() - 1) as usize)
+                    {
+                        header.eval_auth_results(ar, auth_part, &mut result);
+                    }
+                }
+                Ordering::Equal => {
+                    for ar in auth_results {
+                        header.eval_auth_results(ar, auth_part, &mut result);
+                    }
+                }
+            }
+        } else if !header.name.is_empty() {
             let mut headers = part
                 .headers
                 .iter()
@@ -379,6 +408,8 @@ impl HeaderVariable {
                 .map(|bytes| std::str::from_utf8(bytes).unwrap_or_default())
                 .map(Variable::from),
             HeaderPart::Exists => Variable::from(true).into(),
+            // Evaluated from the trusted results by eval_header
+            HeaderPart::AuthResults(_) => None,
             _ => match (&header.value, &self.part) {
                 (HeaderValue::ContentType(ct), HeaderPart::ContentType(part)) => match part {
                     ContentTypePart::Type => Variable::from(ct.c_type.as_ref()).into(),
@@ -403,12 +434,63 @@ impl HeaderVariable {
         result.push(var.unwrap_or_default());
     }
 
+    fn eval_auth_results(
+        &self,
+        auth_results: &AuthResults,
+        part: &AuthResultsPart,
+        result: &mut Vec<Variable>,
+    ) {
+        let mut values = part.eval(auth_results).into_iter();
+        let var = match self.index_part.cmp(&0) {
+            Ordering::Greater => values.nth((self.index_part - 1) as usize),
+            Ordering::Less => values
+                .rev()
+                .nth((self.index_part.unsigned_abs() - 1) as usize),
+            Ordering::Equal => {
+                result.extend(values);
+                return;
+            }
+        };
+
+        result.push(var.unwrap_or_default());
+    }
+
     #[inline(always)]
     fn include_single_part(&self) -> bool {
         [-1, 0, 1].contains(&self.index_part)
     }
 }
 
+impl AuthResultsPart {
+    pub(crate) fn eval(&self, auth_results: &AuthResults) -> Vec<Variable> {
+        match self {
+            AuthResultsPart::AuthservId => vec![auth_results.authserv_id.as_str().into()],
+            AuthResultsPart::Result(method) => auth_results
+                .results
+                .iter()
+                .filter(|r| &r.method == method)
+                .map(|r| Variable::from(r.result.as_str()))
+                .collect(),
+            AuthResultsPart::Reason(method) => auth_results
+                .results
+                .iter()
+                .filter(|r| &r.method == method)
+                .filter_map(|r| r.reason.as_deref().map(Variable::from))
+                .collect(),
+            AuthResultsPart::Property {
+                method,
+                ptype,
+                name,
+            } => auth_results
+                .results
+                .iter()
+                .filter(|r| &r.method == method)
+                .filter_map(|r| r.property(ptype, name).map(Variable::from))
+                .collect(),
+        }
+    }
+}
+
 impl ReceivedPart {
     pub fn eval<'x>(&self, rcvd: &'x Received<'x>) -> Option<Variable> {
         match self {
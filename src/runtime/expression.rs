@@ -21,16 +21,28 @@
  * for more details.
 */
 
-use std::{cmp::Ordering, fmt::Display};
+use std::{cmp::Ordering, fmt::Display, sync::Arc};
 
 use crate::compiler::grammar::expr::parser::ID_EXTERNAL;
 use crate::Event;
-use crate::{compiler::Number, runtime::Variable, Context};
+use crate::{
+    compiler::Number,
+    runtime::{RuntimeError, Variable},
+    Context,
+};
 
 use crate::compiler::grammar::expr::{BinaryOperator, Constant, Expression, UnaryOperator};
 
+// Reasons for suspending an expression, it is resumed by executing the
+// same instruction again.
+pub(crate) enum Interrupt {
+    Event(Event),
+    Call,
+    Error(RuntimeError),
+}
+
 impl<'x> Context<'x> {
-    pub(crate) fn eval_expression(&mut self, expr: &[Expression]) -> Result<Variable, Event> {
+    pub(crate) fn eval_expression(&mut self, expr: &[Expression]) -> Result<Variable, Interrupt> {
         let mut exprs = expr.iter().skip(self.expr_pos);
         while let Some(expr) = exprs.next() {
             self.expr_pos += 1;
@@ -84,14 +96,43 @@ impl<'x> Context<'x> {
                                 self.expr_stack.pop().unwrap_or_default();
                         }
                         self.pos -= 1; // We need to re-evaluate the function call
-                        return Err(Event::Function {
+                        return Err(Interrupt::Event(Event::Function {
                             id: ID_EXTERNAL - *id,
                             arguments,
-                        });
+                        }));
+                    }
+                }
+                Expression::Call { pos, num_args } => {
+                    let num_args = *num_args as usize;
+                    let mut arguments = vec![Variable::Integer(0); num_args];
+                    for arg_num in 0..num_args {
+                        arguments[num_args - arg_num - 1] =
+                            self.expr_stack.pop().unwrap_or_default();
                     }
+                    return Err(match self.call_function(*pos as usize, arguments) {
+                        Ok(()) => Interrupt::Call,
+                        Err(err) => Interrupt::Error(err),
+                    });
+                }
+                Expression::BuiltIn {
+                    fnc,
+                    num_args,
+                    pattern,
+                } => {
+                    let num_args = *num_args as usize;
+                    let mut arguments = vec![Variable::Integer(0); num_args];
+                    for arg_num in 0..num_args {
+                        arguments[num_args - arg_num - 1] =
+                            self.expr_stack.pop().unwrap_or_default();
+                    }
+                    let result = self.exec_builtin(*fnc, pattern.as_ref(), arguments);
+                    self.expr_stack.push(result);
+                }
+                Expression::Value(value) => {
+                    self.expr_stack.push(self.eval_value(value));
                 }
                 Expression::JmpIf { val, pos } => {
-                    if self.expr_stack.last().map_or(false, |v| v.to_bool()) == *val {
+                    if self.expr_stack.last().is_some_and(|v| v.to_bool()) == *val {
                         self.expr_pos += *pos as usize;
                         for _ in 0..*pos {
                             exprs.next();
@@ -99,10 +140,12 @@ impl<'x> Context<'x> {
                     }
                 }
                 Expression::ArrayAccess => {
-                    let index = self.expr_stack.pop().unwrap_or_default().to_usize();
-                    let array = self.expr_stack.pop().unwrap_or_default().into_array();
-                    self.expr_stack
-                        .push(array.get(index).cloned().unwrap_or_default());
+                    let index = self.expr_stack.pop().unwrap_or_default();
+                    let value = match self.expr_stack.pop().unwrap_or_default() {
+                        Variable::Object(obj) => obj.get(index.to_string().as_ref()).cloned(),
+                        array => array.into_array().get(index.to_usize()).cloned(),
+                    };
+                    self.expr_stack.push(value.unwrap_or_default());
                 }
                 Expression::ArrayBuild(num_items) => {
                     let num_items = *num_items as usize;
@@ -138,6 +181,11 @@ impl Variable {
                 .chain(b.iter().cloned())
                 .collect::<Vec<_>>()
                 .into(),
+            (Variable::Object(a), Variable::Object(b)) => {
+                let mut a = Arc::unwrap_or_clone(a);
+                a.extend(b.iter().map(|(k, v)| (k.clone(), v.clone())));
+                a.into()
+            }
             (Variable::String(a), b) => {
                 if !a.is_empty() {
                     Variable::String(format!("{}{}", a, b).into())
@@ -152,6 +200,7 @@ impl Variable {
                     a
                 }
             }
+            (a, b) => Variable::String(format!("{}{}", a, b).into()),
         }
     }
 
@@ -168,6 +217,11 @@ impl Variable {
                     .collect::<Vec<_>>()
                     .into(),
             ),
+            (Variable::Object(a), b) => {
+                let mut a = Arc::unwrap_or_clone(a);
+                a.remove(b.to_string().as_ref());
+                a.into()
+            }
             (a, b) => a.parse_number().op_subtract(b.parse_number()),
         }
     }
@@ -272,6 +326,7 @@ impl Variable {
             Variable::Integer(n) => *n != 0,
             Variable::String(s) => !s.is_empty(),
             Variable::Array(a) => !a.is_empty(),
+            Variable::Object(o) => !o.is_empty(),
         }
     }
 }
@@ -288,6 +343,7 @@ impl PartialEq for Variable {
             (Self::String(_), Self::Integer(_) | Self::Float(_)) => &self.parse_number() == other,
             (Self::Integer(_) | Self::Float(_), Self::String(_)) => self == &other.parse_number(),
             (Self::Array(a), Self::Array(b)) => a == b,
+            (Self::Object(a), Self::Object(b)) => a == b,
             _ => false,
         }
     }
@@ -311,6 +367,9 @@ impl PartialOrd for Variable {
                 self.partial_cmp(&other.parse_number())
             }
             (Self::Array(a), Self::Array(b)) => a.partial_cmp(b),
+            (Self::Object(a), Self::Object(b)) => a.partial_cmp(b),
+            (Self::Object(_), _) => Ordering::Greater.into(),
+            (_, Self::Object(_)) => Ordering::Less.into(),
             (Self::Array(_) | Self::String(_), _) => Ordering::Greater.into(),
             (_, Self::Array(_)) => Ordering::Less.into(),
         }
@@ -338,6 +397,7 @@ impl Display for Variable {
                 }
                 Ok(())
             }
+            Variable::Object(_) => f.write_str(&self.to_json()),
         }
     }
 }
@@ -357,24 +417,6 @@ impl Default for Number {
     }
 }
 
-trait IntoBool {
-    fn into_bool(self) -> bool;
-}
-
-impl IntoBool for f64 {
-    #[inline(always)]
-    fn into_bool(self) -> bool {
-        self != 0.0
-    }
-}
-
-impl IntoBool for i64 {
-    #[inline(always)]
-    fn into_bool(self) -> bool {
-        self != 0
-    }
-}
-
 impl From<bool> for Number {
     #[inline(always)]
     fn from(b: bool) -> Self {
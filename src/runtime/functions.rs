@@ -0,0 +1,272 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::borrow::Cow;
+
+use chrono::{
+    format::{Item, StrftimeItems},
+    Datelike, FixedOffset, Timelike,
+};
+use chrono_tz::Tz;
+use mail_parser::DateTime;
+
+use crate::{
+    compiler::grammar::expr::{BuiltIn, Pattern},
+    runtime::{
+        tests::{glob::GlobPattern, test_date::tz_offset},
+        Variable,
+    },
+    Context,
+};
+
+impl Context<'_> {
+    // Arity is checked at compile time, missing arguments are empty strings
+    pub(crate) fn exec_builtin(
+        &mut self,
+        fnc: BuiltIn,
+        pattern: Option<&Pattern>,
+        arguments: Vec<Variable>,
+    ) -> Variable {
+        let mut arguments = arguments.into_iter();
+        let mut next_arg = || arguments.next().unwrap_or_default();
+
+        match fnc {
+            BuiltIn::JsonParse => {
+                let result = Variable::from_json(next_arg().to_string().as_ref());
+                result.unwrap_or_default()
+            }
+            BuiltIn::JsonEncode => Variable::from(next_arg().to_json()),
+            BuiltIn::Matches => {
+                // Case-insensitive, as with the default "i;ascii-casemap" comparator
+                let value = next_arg();
+                let pattern_expr = next_arg();
+                let value = value.to_string();
+                match pattern {
+                    Some(Pattern::Glob(pattern)) => pattern.matches(value.as_ref()),
+                    _ => GlobPattern::compile(pattern_expr.to_string().as_ref(), true)
+                        .matches(value.as_ref()),
+                }
+                .into()
+            }
+            BuiltIn::RegexMatch | BuiltIn::RegexCaptures | BuiltIn::RegexReplace => {
+                let value = next_arg();
+                let value = value.to_string();
+                let pattern_expr = next_arg();
+                let regex = match pattern {
+                    Some(Pattern::Regex(pattern)) => Cow::Borrowed(&pattern.regex),
+                    _ => match fancy_regex::Regex::new(pattern_expr.to_string().as_ref()) {
+                        Ok(regex) => Cow::Owned(regex),
+                        Err(_) => {
+                            // Invalid patterns never match
+                            return match fnc {
+                                BuiltIn::RegexMatch => false.into(),
+                                BuiltIn::RegexCaptures => Vec::<Variable>::new().into(),
+                                _ => value.into(),
+                            };
+                        }
+                    },
+                };
+
+                match fnc {
+                    BuiltIn::RegexMatch => regex.is_match(&value).unwrap_or_default().into(),
+                    BuiltIn::RegexCaptures => match regex.captures(&value) {
+                        Ok(Some(captures)) => captures
+                            .iter()
+                            .map(|capture| {
+                                capture.map_or_else(Variable::default, |c| c.as_str().into())
+                            })
+                            .collect::<Vec<_>>()
+                            .into(),
+                        _ => Vec::<Variable>::new().into(),
+                    },
+                    _ => {
+                        let replacement = next_arg();
+                        match regex.try_replacen(&value, 0, replacement.to_string().as_ref()) {
+                            Ok(result) => result.into_owned().into(),
+                            Err(_) => value.into(),
+                        }
+                    }
+                }
+            }
+            BuiltIn::Now => self.current_time.into(),
+            BuiltIn::ParseDate => {
+                parse_timestamp(&next_arg()).map_or_else(Variable::default, Variable::from)
+            }
+            BuiltIn::DateDiff => {
+                let start = parse_timestamp(&next_arg());
+                let end = parse_timestamp(&next_arg());
+                let unit = match next_arg()
+                    .to_string()
+                    .to_ascii_lowercase()
+                    .trim_end_matches('s')
+                {
+                    "second" => 1,
+                    "minute" => 60,
+                    "hour" => 3600,
+                    "day" => 86400,
+                    "week" => 604800,
+                    _ => return Variable::default(),
+                };
+                match (start, end) {
+                    (Some(start), Some(end)) => Variable::Integer(end.saturating_sub(start) / unit),
+                    _ => Variable::default(),
+                }
+            }
+            BuiltIn::FormatDate | BuiltIn::Weekday | BuiltIn::Hour => {
+                let timestamp = next_arg();
+                let format = if fnc == BuiltIn::FormatDate {
+                    next_arg()
+                } else {
+                    Variable::default()
+                };
+                let dt = match parse_timestamp(&timestamp).and_then(|timestamp| {
+                    self.to_datetime(timestamp, next_arg().to_string().as_ref())
+                }) {
+                    Some(dt) => dt,
+                    None => return Variable::default(),
+                };
+
+                match fnc {
+                    BuiltIn::Weekday => Variable::from(dt.weekday().num_days_from_sunday()),
+                    BuiltIn::Hour => Variable::from(dt.hour()),
+                    _ => {
+                        // Invalid specifiers would make chrono fail while formatting
+                        let format = format.to_string();
+                        let items = StrftimeItems::new(format.as_ref()).collect::<Vec<_>>();
+                        if !items.iter().any(|item| matches!(item, Item::Error)) {
+                            dt.format_with_items(items.into_iter()).to_string().into()
+                        } else {
+                            Variable::default()
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Converts a timestamp to a zone given as an IANA name or an offset
+    // such as "+0100", defaults to the runtime's time zone.
+    fn to_datetime(&self, timestamp: i64, zone: &str) -> Option<chrono::DateTime<FixedOffset>> {
+        let offset = match zone.trim() {
+            "" => tz_offset(self.runtime.default_timezone, timestamp),
+            zone => match zone.parse::<i64>() {
+                Ok(zone) if zone.abs() % 100 >= 60 => return None,
+                Ok(zone @ 0..=1400) => (zone / 100 * 3600) + (zone % 100 * 60),
+                Ok(zone @ -1200..=-1) => (zone / 100 * 3600) - (-zone % 100 * 60),
+                Ok(_) => return None,
+                Err(_) => tz_offset(zone.parse::<Tz>().ok()?, timestamp),
+            },
+        };
+
+        chrono::DateTime::from_timestamp(timestamp, 0)?
+            .with_timezone(&FixedOffset::east_opt(offset as i32)?)
+            .into()
+    }
+}
+
+// Accepts timestamps and RFC 3339 or RFC 822 dates
+fn parse_timestamp(value: &Variable) -> Option<i64> {
+    match value {
+        Variable::Integer(n) => Some(*n),
+        Variable::Float(n) => Some(*n as i64),
+        Variable::String(s) => {
+            let s = s.trim();
+            s.parse::<i64>()
+                .ok()
+                .or_else(|| DateTime::parse_rfc3339(s).map(|dt| dt.to_timestamp()))
+                .or_else(|| DateTime::parse_rfc822(s).map(|dt| dt.to_timestamp()))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compiler::{
+            grammar::{
+                expr::{Expression, Pattern},
+                instruction::Instruction,
+            },
+            ErrorType, Regex,
+        },
+        runtime::tests::glob::GlobPattern,
+        Compiler,
+    };
+
+    fn compiled_patterns(expr: &str) -> Vec<Option<Pattern>> {
+        let script = format!(
+            "require [\"variables\", \"vnd.stalwart.expressions\"];\nset \"p\" \"x\";\nif eval \"{expr}\" {{}}"
+        );
+        let script = Compiler::new().compile(script.as_bytes()).unwrap();
+        script
+            .instructions
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Eval(expr) => Some(expr),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(|expr| match expr {
+                Expression::BuiltIn { pattern, .. } => Some(pattern.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn regex(expr: &str) -> Option<Pattern> {
+        Some(Pattern::Regex(Regex {
+            regex: fancy_regex::Regex::new(expr).unwrap(),
+            expr: expr.to_string(),
+        }))
+    }
+
+    #[test]
+    fn constant_patterns() {
+        assert_eq!(
+            compiled_patterns("regex_match(p, '^a+') || regex_captures(p, p)"),
+            vec![regex("^a+"), None]
+        );
+        assert_eq!(
+            compiled_patterns("regex_replace(p + 'x', '[0-9]', '#') == regex_replace(p, 'b', p)"),
+            vec![regex("[0-9]"), None]
+        );
+        assert_eq!(
+            compiled_patterns("matches(p, '*.example') && matches(p, p)"),
+            vec![
+                Some(Pattern::Glob(GlobPattern::compile("*.example", true))),
+                None
+            ]
+        );
+
+        let err = Compiler::new()
+            .compile(
+                b"require \"vnd.stalwart.expressions\";\nif eval \"regex_match('a', '(unclosed')\" {}",
+            )
+            .unwrap_err();
+        assert!(
+            matches!(err.error_type(), ErrorType::InvalidExpression(e) if e.contains("Invalid regular expression")),
+            "{err:?}"
+        );
+    }
+}
@@ -0,0 +1,118 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use serde_json::{Map, Number, Value};
+
+use super::Variable;
+
+/*
+ JSON conversion for variables. Booleans become integers and null an empty
+ string, matching how the interpreter represents them elsewhere.
+*/
+impl Variable {
+    pub fn from_json(json: &str) -> Option<Variable> {
+        serde_json::from_str::<Value>(json).ok().map(Variable::from)
+    }
+
+    pub fn to_json(&self) -> String {
+        Value::from(self).to_string()
+    }
+}
+
+impl From<Value> for Variable {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Null => Variable::default(),
+            Value::Bool(b) => Variable::from(b),
+            Value::Number(n) => n
+                .as_i64()
+                .map(Variable::Integer)
+                .unwrap_or_else(|| Variable::Float(n.as_f64().unwrap_or_default())),
+            Value::String(s) => Variable::from(s),
+            Value::Array(a) => a.into_iter().map(Variable::from).collect::<Vec<_>>().into(),
+            Value::Object(o) => o
+                .into_iter()
+                .map(|(k, v)| (k, Variable::from(v)))
+                .collect::<std::collections::BTreeMap<_, _>>()
+                .into(),
+        }
+    }
+}
+
+impl From<&Variable> for Value {
+    fn from(value: &Variable) -> Self {
+        match value {
+            Variable::String(s) => Value::String(s.as_ref().clone()),
+            Variable::Integer(n) => Value::Number((*n).into()),
+            Variable::Float(n) => Number::from_f64(*n).map_or(Value::Null, Value::Number),
+            Variable::Array(a) => Value::Array(a.iter().map(Value::from).collect()),
+            Variable::Object(o) => Value::Object(
+                o.iter()
+                    .map(|(k, v)| (k.clone(), Value::from(v)))
+                    .collect::<Map<_, _>>(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::Variable;
+
+    #[test]
+    fn json_variables() {
+        let json = r#"{"a":[1,2.5,"x"],"b":{"c":true,"d":null}}"#;
+        let variable = Variable::from_json(json).unwrap();
+        let obj = variable.as_object().unwrap();
+        assert_eq!(
+            obj["a"],
+            Variable::from(vec![
+                Variable::Integer(1),
+                Variable::Float(2.5),
+                Variable::from("x")
+            ])
+        );
+        assert_eq!(
+            obj["b"].as_object().unwrap()["c"],
+            Variable::Integer(1),
+            "booleans are integers"
+        );
+        assert_eq!(
+            variable.to_string(),
+            r#"{"a":[1,2.5,"x"],"b":{"c":1,"d":""}}"#
+        );
+        assert_eq!(Variable::from_json("{"), None);
+
+        // Serde round trip, as used when suspending a script
+        let serialized = serde_json::to_string(&variable).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Variable>(&serialized).unwrap(),
+            variable
+        );
+        let serialized = bincode::serialize(&variable).unwrap();
+        assert_eq!(
+            bincode::deserialize::<Variable>(&serialized).unwrap(),
+            variable
+        );
+    }
+}
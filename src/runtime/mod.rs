@@ -22,20 +22,39 @@
 */
 
 pub mod actions;
+pub mod batch;
+pub mod chain;
+pub mod changes;
 pub mod context;
+pub mod delivery;
 pub mod eval;
 pub mod expression;
+pub mod functions;
+pub mod json;
+pub mod notify;
+pub mod policy;
+pub mod ratelimit;
 pub mod serialize;
+pub mod srs;
+pub mod store;
+pub mod suspend;
+pub mod template;
 pub mod tests;
 pub mod variables;
 
-use std::{borrow::Cow, fmt::Display, hash::Hash, ops::Deref, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fmt::Display,
+    hash::Hash,
+    ops::Deref,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use ahash::{AHashMap, AHashSet};
-#[cfg(not(test))]
-use mail_parser::{Encoding, Message, MessageParser, MessagePart, PartType};
-
-use mail_parser::HeaderName;
+use chrono_tz::Tz;
+use mail_parser::{Encoding, HeaderName, Message, MessageParser, MessagePart, PartType};
 use serde::{Deserialize, Serialize};
 
 #[cfg(not(test))]
@@ -49,7 +68,15 @@ use crate::{
     ExternalId, Function, FunctionMap, Input, Metadata, Runtime, Script, Sieve,
 };
 
-use self::eval::ToString;
+use self::{
+    eval::ToString,
+    notify::{NotifyMethod, SmsMethod, WebhookMethod, XmppMethod},
+    policy::{Policy, PolicyMode, PolicyViolation},
+    ratelimit::{MemoryRateLimiter, RateLimit, RateLimitMode, RateLimiter},
+    srs::Srs,
+    store::KeyValueStore,
+    template::{Localized, NotifyTemplate, VacationTemplate},
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Variable {
@@ -57,16 +84,22 @@ pub enum Variable {
     Integer(i64),
     Float(f64),
     Array(Arc<Vec<Variable>>),
+    Object(Arc<BTreeMap<String, Variable>>),
 }
 
 #[derive(Debug)]
 pub enum RuntimeError {
     TooManyIncludes,
+    TooManyNestedCalls,
     InvalidInstruction(Invalid),
     ScriptErrorMessage(String),
     CapabilityNotAllowed(Capability),
     CapabilityNotSupported(String),
     CPULimitReached,
+    MemoryLimitReached,
+    TimeLimitReached,
+    RateLimitExceeded,
+    PolicyViolation(PolicyViolation),
 }
 
 impl Default for Variable {
@@ -82,6 +115,7 @@ impl Variable {
             Variable::Integer(n) => Cow::Owned(n.to_string()),
             Variable::Float(n) => Cow::Owned(n.to_string()),
             Variable::Array(l) => Cow::Owned(l.to_string()),
+            Variable::Object(_) => Cow::Owned(self.to_json()),
         }
     }
 
@@ -128,6 +162,7 @@ impl Variable {
             Variable::String(s) => s.len(),
             Variable::Integer(_) | Variable::Float(_) => 2,
             Variable::Array(l) => l.iter().map(|v| v.len() + 2).sum(),
+            Variable::Object(o) => o.iter().map(|(k, v)| k.len() + v.len() + 2).sum(),
         }
     }
 
@@ -145,6 +180,13 @@ impl Variable {
         }
     }
 
+    pub fn as_object(&self) -> Option<&BTreeMap<String, Variable>> {
+        match self {
+            Variable::Object(o) => Some(o),
+            _ => None,
+        }
+    }
+
     pub fn into_array(self) -> Arc<Vec<Variable>> {
         match self {
             Variable::Array(l) => l,
@@ -211,6 +253,12 @@ impl From<Vec<Variable>> for Variable {
     }
 }
 
+impl From<BTreeMap<String, Variable>> for Variable {
+    fn from(o: BTreeMap<String, Variable>) -> Self {
+        Variable::Object(o.into())
+    }
+}
+
 impl From<Number> for Variable {
     fn from(n: Number) -> Self {
         match n {
@@ -298,6 +346,7 @@ impl self::eval::ToString for Vec<Variable> {
                 Variable::String(v) => result.push_str(v),
                 Variable::Integer(v) => result.push_str(&v.to_string()),
                 Variable::Float(v) => result.push_str(&v.to_string()),
+                Variable::Object(_) => result.push_str(&item.to_json()),
                 Variable::Array(_) => {}
             }
         }
@@ -312,36 +361,51 @@ impl Hash for Variable {
             Variable::Integer(n) => n.hash(state),
             Variable::Float(n) => n.to_bits().hash(state),
             Variable::Array(l) => l.hash(state),
+            Variable::Object(o) => o.hash(state),
         }
     }
 }
 
+pub(crate) fn parse_message(raw_message: &[u8]) -> Message<'_> {
+    MessageParser::new()
+        .parse(raw_message)
+        .unwrap_or_else(|| Message {
+            parts: vec![MessagePart {
+                headers: vec![],
+                is_encoding_problem: false,
+                body: PartType::Text("".into()),
+                encoding: Encoding::None,
+                offset_header: 0,
+                offset_body: 0,
+                offset_end: 0,
+            }],
+            raw_message: b""[..].into(),
+            ..Default::default()
+        })
+}
+
 #[cfg(not(test))]
 impl Runtime {
     pub fn filter<'z: 'x, 'x>(&'z self, raw_message: &'x [u8]) -> Context<'x> {
-        Context::new(
-            self,
-            MessageParser::new()
-                .parse(raw_message)
-                .unwrap_or_else(|| Message {
-                    parts: vec![MessagePart {
-                        headers: vec![],
-                        is_encoding_problem: false,
-                        body: PartType::Text("".into()),
-                        encoding: Encoding::None,
-                        offset_header: 0,
-                        offset_body: 0,
-                        offset_end: 0,
-                    }],
-                    raw_message: b""[..].into(),
-                    ..Default::default()
-                }),
-        )
+        Context::new(self, parse_message(raw_message))
     }
 
     pub fn filter_parsed<'z: 'x, 'x>(&'z self, message: Message<'x>) -> Context<'x> {
         Context::new(self, message)
     }
+
+    // Parses only the header block, the MIME structure is parsed once an
+    // instruction needs the message body or parts.
+    pub fn filter_lazy<'z: 'x, 'x>(&'z self, raw_message: &'x [u8]) -> Context<'x> {
+        match MessageParser::new().parse_headers(raw_message) {
+            Some(message) => {
+                let mut ctx = Context::new(self, message);
+                ctx.pending_message = raw_message.into();
+                ctx
+            }
+            None => self.filter(raw_message),
+        }
+    }
 }
 
 impl Default for Runtime {
@@ -368,6 +432,8 @@ impl Runtime {
             include_scripts: AHashMap::new(),
             max_nested_includes: 3,
             cpu_limit: 5000,
+            memory_limit: usize::MAX,
+            time_limit: None,
             max_variable_size: 4096,
             max_redirects: 1,
             max_received_headers: 10,
@@ -376,14 +442,39 @@ impl Runtime {
                 HeaderName::Other("Original-From".into()),
             ],
             valid_notification_uris: AHashSet::new(),
+            notify_methods: AHashMap::from_iter([
+                (
+                    "xmpp".to_string(),
+                    Arc::new(XmppMethod::new()) as Arc<dyn NotifyMethod>,
+                ),
+                ("tel".to_string(), Arc::new(SmsMethod::default())),
+                ("sms".to_string(), Arc::new(SmsMethod::default())),
+                ("https".to_string(), Arc::new(WebhookMethod::new())),
+            ]),
+            trusted_authserv_ids: AHashSet::new(),
+            trusted_relays: AHashSet::new(),
+            srs: None,
+            store: None,
+            rate_limiter: Arc::new(MemoryRateLimiter::new()),
+            send_rate_limit: None,
+            notify_rate_limit: None,
+            rate_limit_mode: RateLimitMode::Error,
+            policy: None,
+            policy_mode: PolicyMode::Error,
+            reject_reports: false,
+            reject_status: "550 5.7.1".into(),
             valid_ext_lists: AHashSet::new(),
             vacation_use_orig_rcpt: false,
             vacation_default_subject: "Automated reply".into(),
             vacation_subject_prefix: "Auto: ".into(),
+            vacation_templates: Localized::default(),
+            notify_templates: Localized::default(),
+            domain_languages: AHashMap::new(),
             max_header_size: 1024,
             max_out_messages: 3,
             default_vacation_expiry: 30 * 86400,
             default_duplicate_expiry: 7 * 86400,
+            default_timezone: Tz::UTC,
             local_hostname: "localhost".into(),
             functions: Vec::new(),
         }
@@ -398,6 +489,26 @@ impl Runtime {
         self
     }
 
+    // Maximum number of bytes a script may store in variables, arrays
+    // and created messages during a single execution.
+    pub fn set_memory_limit(&mut self, size: usize) {
+        self.memory_limit = size;
+    }
+
+    pub fn with_memory_limit(mut self, size: usize) -> Self {
+        self.memory_limit = size;
+        self
+    }
+
+    pub fn set_time_limit(&mut self, limit: Duration) {
+        self.time_limit = limit.into();
+    }
+
+    pub fn with_time_limit(mut self, limit: Duration) -> Self {
+        self.time_limit = limit.into();
+        self
+    }
+
     pub fn set_max_nested_includes(&mut self, size: usize) {
         self.max_nested_includes = size;
     }
@@ -470,6 +581,15 @@ impl Runtime {
         self
     }
 
+    pub fn set_default_timezone(&mut self, timezone: Tz) {
+        self.default_timezone = timezone;
+    }
+
+    pub fn with_default_timezone(mut self, timezone: Tz) -> Self {
+        self.default_timezone = timezone;
+        self
+    }
+
     pub fn set_capability(&mut self, capability: impl Into<Capability>) {
         self.allowed_capabilities.insert(capability.into());
     }
@@ -554,6 +674,135 @@ impl Runtime {
         self
     }
 
+    pub fn set_srs(&mut self, srs: Srs) {
+        self.srs = srs.into();
+    }
+
+    pub fn set_store(&mut self, store: Arc<dyn KeyValueStore>) {
+        self.store = store.into();
+    }
+
+    pub fn with_store(mut self, store: Arc<dyn KeyValueStore>) -> Self {
+        self.store = store.into();
+        self
+    }
+
+    pub fn set_rate_limiter(&mut self, rate_limiter: Arc<dyn RateLimiter>) {
+        self.rate_limiter = rate_limiter;
+    }
+
+    pub fn with_rate_limiter(mut self, rate_limiter: Arc<dyn RateLimiter>) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    // Destinations rejected by the policy abort the script or drop the action.
+    pub fn set_policy(&mut self, policy: impl Policy + 'static) {
+        self.policy = Some(Arc::new(policy));
+    }
+
+    pub fn with_policy(mut self, policy: impl Policy + 'static) -> Self {
+        self.set_policy(policy);
+        self
+    }
+
+    // Error aborts the script on a violation, Drop skips the action and records it.
+    pub fn set_policy_mode(&mut self, mode: PolicyMode) {
+        self.policy_mode = mode;
+    }
+
+    pub fn with_policy_mode(mut self, mode: PolicyMode) -> Self {
+        self.policy_mode = mode;
+        self
+    }
+
+    // Maximum number of messages a user may send through redirect,
+    // vacation or mailto notifications within the given period.
+    pub fn set_send_rate_limit(&mut self, count: u64, period: u64) {
+        self.send_rate_limit = RateLimit { count, period }.into();
+    }
+
+    pub fn with_send_rate_limit(mut self, count: u64, period: u64) -> Self {
+        self.set_send_rate_limit(count, period);
+        self
+    }
+
+    // Maximum number of non-mailto notifications a user may trigger
+    // within the given period.
+    pub fn set_notify_rate_limit(&mut self, count: u64, period: u64) {
+        self.notify_rate_limit = RateLimit { count, period }.into();
+    }
+
+    pub fn with_notify_rate_limit(mut self, count: u64, period: u64) -> Self {
+        self.set_notify_rate_limit(count, period);
+        self
+    }
+
+    pub fn set_rate_limit_mode(&mut self, mode: RateLimitMode) {
+        self.rate_limit_mode = mode;
+    }
+
+    pub fn with_rate_limit_mode(mut self, mode: RateLimitMode) -> Self {
+        self.rate_limit_mode = mode;
+        self
+    }
+
+    pub fn with_srs(mut self, srs: Srs) -> Self {
+        self.srs = srs.into();
+        self
+    }
+
+    pub fn srs_reverse(&self, address: &str) -> Option<String> {
+        self.srs.as_ref()?.reverse(
+            address,
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        )
+    }
+
+    pub fn set_trusted_authserv_id(&mut self, authserv_id: impl AsRef<str>) {
+        self.trusted_authserv_ids
+            .insert(authserv_id.as_ref().to_ascii_lowercase());
+    }
+
+    pub fn with_trusted_authserv_id(mut self, authserv_id: impl AsRef<str>) -> Self {
+        self.set_trusted_authserv_id(authserv_id);
+        self
+    }
+
+    pub fn with_trusted_authserv_ids(
+        mut self,
+        authserv_ids: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Self {
+        self.trusted_authserv_ids = authserv_ids
+            .into_iter()
+            .map(|id| id.as_ref().to_ascii_lowercase())
+            .collect();
+        self
+    }
+
+    pub fn set_trusted_relay(&mut self, hostname: impl AsRef<str>) {
+        self.trusted_relays
+            .insert(hostname.as_ref().to_ascii_lowercase());
+    }
+
+    pub fn with_trusted_relay(mut self, hostname: impl AsRef<str>) -> Self {
+        self.set_trusted_relay(hostname);
+        self
+    }
+
+    pub fn with_trusted_relays(
+        mut self,
+        hostnames: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Self {
+        self.trusted_relays = hostnames
+            .into_iter()
+            .map(|hostname| hostname.as_ref().to_ascii_lowercase())
+            .collect();
+        self
+    }
+
     pub fn set_valid_notification_uri(&mut self, uri: impl Into<Cow<'static, str>>) {
         self.valid_notification_uris.insert(uri.into());
     }
@@ -571,6 +820,32 @@ impl Runtime {
         self
     }
 
+    // Registers the notification method used for URIs with this scheme,
+    // replacing any built-in method.
+    pub fn set_notify_method(
+        &mut self,
+        scheme: impl AsRef<str>,
+        method: impl NotifyMethod + 'static,
+    ) {
+        self.notify_methods
+            .insert(scheme.as_ref().to_ascii_lowercase(), Arc::new(method));
+    }
+
+    pub fn with_notify_method(
+        mut self,
+        scheme: impl AsRef<str>,
+        method: impl NotifyMethod + 'static,
+    ) -> Self {
+        self.set_notify_method(scheme, method);
+        self
+    }
+
+    pub(crate) fn notify_method(&self, scheme: &str) -> Option<&dyn NotifyMethod> {
+        self.notify_methods
+            .get(scheme.to_ascii_lowercase().as_str())
+            .map(|method| method.as_ref())
+    }
+
     pub fn set_valid_ext_list(&mut self, name: impl Into<Cow<'static, str>>) {
         self.valid_ext_lists.insert(name.into());
     }
@@ -615,6 +890,89 @@ impl Runtime {
         self
     }
 
+    pub fn set_vacation_template(&mut self, template: VacationTemplate) {
+        self.vacation_templates.default = template;
+    }
+
+    pub fn with_vacation_template(mut self, template: VacationTemplate) -> Self {
+        self.vacation_templates.default = template;
+        self
+    }
+
+    pub fn set_localized_vacation_template(&mut self, language: &str, template: VacationTemplate) {
+        self.vacation_templates.insert(language, template);
+    }
+
+    pub fn with_localized_vacation_template(
+        mut self,
+        language: &str,
+        template: VacationTemplate,
+    ) -> Self {
+        self.vacation_templates.insert(language, template);
+        self
+    }
+
+    pub fn set_notify_template(&mut self, template: NotifyTemplate) {
+        self.notify_templates.default = template;
+    }
+
+    pub fn with_notify_template(mut self, template: NotifyTemplate) -> Self {
+        self.notify_templates.default = template;
+        self
+    }
+
+    pub fn set_localized_notify_template(&mut self, language: &str, template: NotifyTemplate) {
+        self.notify_templates.insert(language, template);
+    }
+
+    pub fn with_localized_notify_template(
+        mut self,
+        language: &str,
+        template: NotifyTemplate,
+    ) -> Self {
+        self.notify_templates.insert(language, template);
+        self
+    }
+
+    // Language used for replies to senders from this domain when the
+    // original message does not indicate one.
+    pub fn set_domain_language(&mut self, domain: impl AsRef<str>, language: impl AsRef<str>) {
+        self.domain_languages.insert(
+            domain.as_ref().to_ascii_lowercase(),
+            language.as_ref().trim().to_ascii_lowercase(),
+        );
+    }
+
+    pub fn with_domain_language(
+        mut self,
+        domain: impl AsRef<str>,
+        language: impl AsRef<str>,
+    ) -> Self {
+        self.set_domain_language(domain, language);
+        self
+    }
+
+    // Generates an MDN (reject) or DSN (ereject) addressed to the envelope sender
+    // when a message is rejected after delivery.
+    pub fn set_reject_reports(&mut self, value: bool) {
+        self.reject_reports = value;
+    }
+
+    pub fn with_reject_reports(mut self, value: bool) -> Self {
+        self.set_reject_reports(value);
+        self
+    }
+
+    // Reply code and enhanced status code used for rejections, i.e. "550 5.7.1".
+    pub fn set_reject_status(&mut self, value: impl Into<Cow<'static, str>>) {
+        self.reject_status = value.into();
+    }
+
+    pub fn with_reject_status(mut self, value: impl Into<Cow<'static, str>>) -> Self {
+        self.set_reject_status(value);
+        self
+    }
+
     pub fn set_local_hostname(&mut self, value: impl Into<Cow<'static, str>>) {
         self.local_hostname = value.into();
     }
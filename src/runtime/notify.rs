@@ -0,0 +1,575 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::fmt::{Debug, Write};
+
+use mail_parser::decoders::quoted_printable::HEX_MAP;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    runtime::actions::action_notify::{parse_mailto, parse_uri, validate_uri},
+    Context, Importance,
+};
+
+/*
+ Notification methods other than mailto, registered on the Runtime by URI
+ scheme. A method validates its URIs for valid_notify_method, answers
+ notify_method_capability queries and turns a notify action into the
+ payload the host delivers.
+*/
+pub trait NotifyMethod: Debug + Send + Sync {
+    fn validate_uri(&self, uri: &str) -> bool;
+
+    fn capability(&self, uri: &str, capability: &str) -> Option<String> {
+        let _ = uri;
+        if capability.eq_ignore_ascii_case("online") {
+            Some("maybe".to_string())
+        } else {
+            None
+        }
+    }
+
+    // Options are "name=value" pairs (RFC 5435, section 3.4)
+    fn parse_options(&self, options: &[String]) -> Option<Vec<(String, String)>> {
+        options
+            .iter()
+            .map(|option| {
+                let (name, value) = option.split_once('=')?;
+                if !name.is_empty()
+                    && name
+                        .chars()
+                        .all(|ch| ch.is_ascii_alphanumeric() || ['-', '_', '.'].contains(&ch))
+                {
+                    Some((name.to_string(), value.to_string()))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn build(&self, notification: &Notification<'_>) -> Option<NotifyPayload>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification<'x> {
+    pub uri: &'x str,
+    pub from: Option<&'x str>,
+    pub recipient: &'x str,
+    pub importance: Importance,
+    pub options: &'x [(String, String)],
+    pub message: &'x str,
+    pub subject: &'x str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NotifyPayload {
+    pub recipients: Vec<String>,
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+// RFC 5437 - Sieve Notification Mechanism: Extensible Messaging and Presence Protocol (XMPP)
+#[derive(Debug, Default, Clone)]
+pub struct XmppMethod;
+
+// Text messages to tel: and sms: URIs, truncated to `max_length` characters
+#[derive(Debug, Clone)]
+pub struct SmsMethod {
+    pub max_length: usize,
+}
+
+// JSON document posted to an https: URI
+#[derive(Debug, Default, Clone)]
+pub struct WebhookMethod;
+
+impl XmppMethod {
+    pub fn new() -> Self {
+        XmppMethod
+    }
+
+    // Returns the JID and the parameters of a "message" query
+    fn parse(uri: &str) -> Option<(String, Vec<(String, String)>)> {
+        let (_, uri) = parse_uri(uri)?;
+        let uri = if let Some(uri) = uri.strip_prefix("//") {
+            uri.split_once('/')?.1
+        } else {
+            uri
+        };
+        let (jid, query) = uri.split_once('?').unwrap_or((uri, ""));
+        let jid = decode_uri(jid)?;
+        let domain = jid
+            .rsplit_once('@')
+            .map_or(jid.as_str(), |(_, domain)| domain);
+        let domain = domain.split_once('/').map_or(domain, |(domain, _)| domain);
+        if domain.is_empty() || jid.contains(|ch: char| ch.is_whitespace() || ch == '?') {
+            return None;
+        }
+
+        let mut params = Vec::new();
+        if !query.is_empty() {
+            let mut query = query.split(';');
+            if !query.next()?.eq_ignore_ascii_case("message") {
+                return None;
+            }
+            for param in query {
+                let (name, value) = param.split_once('=')?;
+                params.push((name.to_ascii_lowercase(), decode_uri(value)?));
+            }
+        }
+
+        Some((jid, params))
+    }
+}
+
+impl NotifyMethod for XmppMethod {
+    fn validate_uri(&self, uri: &str) -> bool {
+        Self::parse(uri).is_some()
+    }
+
+    fn build(&self, notification: &Notification<'_>) -> Option<NotifyPayload> {
+        let (jid, params) = Self::parse(notification.uri)?;
+        let subject = params
+            .iter()
+            .find_map(|(name, value)| (name == "subject").then_some(value.as_str()));
+        let body = params
+            .iter()
+            .find_map(|(name, value)| (name == "body").then_some(value.as_str()))
+            .unwrap_or(notification.message);
+
+        let mut stanza = format!("<message to='{}' type='normal'>", escape_xml(jid.as_str()));
+        if let Some(subject) = subject {
+            let _ = write!(stanza, "<subject>{}</subject>", escape_xml(subject));
+        }
+        let _ = write!(stanza, "<body>{}</body>", escape_xml(body));
+        stanza.push_str("<headers xmlns='http://jabber.org/protocol/shim'>");
+        stanza.push_str("<header name='Auto-Submitted'>auto-notified</header>");
+        if let Some(urgency) = match notification.importance {
+            Importance::High => Some("high"),
+            Importance::Low => Some("low"),
+            Importance::Normal => None,
+        } {
+            let _ = write!(stanza, "<header name='Urgency'>{urgency}</header>");
+        }
+        stanza.push_str("</headers></message>");
+
+        Some(NotifyPayload {
+            recipients: vec![jid],
+            content_type: "application/xmpp+xml".to_string(),
+            body: stanza.into_bytes(),
+        })
+    }
+}
+
+impl SmsMethod {
+    pub fn new(max_length: usize) -> Self {
+        SmsMethod { max_length }
+    }
+
+    // Returns the phone numbers and the optional body of a tel: or sms: URI
+    fn parse(uri: &str) -> Option<(Vec<String>, Option<String>)> {
+        let (scheme, uri) = parse_uri(uri)?;
+        let (numbers, query) = uri.split_once('?').unwrap_or((uri, ""));
+        let mut recipients = Vec::new();
+        for number in numbers.split(',') {
+            // Drop parameters such as ";phone-context="
+            let number = number.split_once(';').map_or(number, |(number, _)| number);
+            let mut normalized = String::with_capacity(number.len());
+            for (pos, ch) in number.chars().enumerate() {
+                match ch {
+                    '0'..='9' => normalized.push(ch),
+                    '+' if pos == 0 => normalized.push(ch),
+                    '-' | '.' | '(' | ')' => (),
+                    _ => return None,
+                }
+            }
+            if !normalized.trim_start_matches('+').is_empty() {
+                recipients.push(normalized);
+            } else {
+                return None;
+            }
+        }
+        if recipients.len() > 1 && !scheme.eq_ignore_ascii_case("sms") {
+            return None;
+        }
+
+        let mut body = None;
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            let (name, value) = param.split_once('=')?;
+            if name.eq_ignore_ascii_case("body") {
+                body = decode_uri(value)?.into();
+            }
+        }
+
+        Some((recipients, body))
+    }
+}
+
+impl Default for SmsMethod {
+    fn default() -> Self {
+        SmsMethod::new(160)
+    }
+}
+
+impl NotifyMethod for SmsMethod {
+    fn validate_uri(&self, uri: &str) -> bool {
+        Self::parse(uri).is_some()
+    }
+
+    fn build(&self, notification: &Notification<'_>) -> Option<NotifyPayload> {
+        let (recipients, body) = Self::parse(notification.uri)?;
+        let text = body.as_deref().unwrap_or(notification.message);
+        let mut body = String::with_capacity(text.len().min(self.max_length * 4));
+        if text.chars().count() > self.max_length {
+            body.extend(text.chars().take(self.max_length.saturating_sub(1)));
+            body.push('…');
+        } else {
+            body.push_str(text);
+        }
+
+        Some(NotifyPayload {
+            recipients,
+            content_type: "text/plain; charset=utf-8".to_string(),
+            body: body.into_bytes(),
+        })
+    }
+}
+
+impl WebhookMethod {
+    pub fn new() -> Self {
+        WebhookMethod
+    }
+}
+
+impl NotifyMethod for WebhookMethod {
+    fn validate_uri(&self, uri: &str) -> bool {
+        parse_uri(uri)
+            .and_then(|(_, uri)| uri.strip_prefix("//"))
+            .is_some_and(|uri| {
+                let host = uri.split(['/', '?', '#']).next().unwrap_or_default();
+                !host.is_empty() && !uri.contains(|ch: char| ch.is_whitespace())
+            })
+    }
+
+    fn build(&self, notification: &Notification<'_>) -> Option<NotifyPayload> {
+        let json = json!({
+            "from": notification.from,
+            "recipient": notification.recipient,
+            "importance": match notification.importance {
+                Importance::High => "high",
+                Importance::Normal => "normal",
+                Importance::Low => "low",
+            },
+            "message": notification.message,
+            "subject": notification.subject,
+            "options": notification
+                .options
+                .iter()
+                .map(|(name, value)| (name.clone(), Value::from(value.as_str())))
+                .collect::<Map<_, _>>(),
+        });
+
+        Some(NotifyPayload {
+            recipients: vec![notification.uri.to_string()],
+            content_type: "application/json".to_string(),
+            body: json.to_string().into_bytes(),
+        })
+    }
+}
+
+impl Context<'_> {
+    // Validates a notification URI against the allowed schemes and, when
+    // registered, the scheme's notification method.
+    pub(crate) fn validate_notify_uri(&self, uri: &str) -> bool {
+        let Some((scheme, params)) = parse_uri(uri) else {
+            return false;
+        };
+        if !self.runtime.valid_notification_uris.contains(scheme)
+            && !self.runtime.valid_notification_uris.contains(uri)
+        {
+            return false;
+        }
+
+        if scheme.eq_ignore_ascii_case("mailto") {
+            parse_mailto(params).is_some()
+        } else if let Some(method) = self.runtime.notify_method(scheme) {
+            method.validate_uri(uri)
+        } else {
+            validate_uri(uri).is_some()
+        }
+    }
+
+    pub(crate) fn notify_capability(&self, uri: &str, capability: &str) -> Option<String> {
+        if !self.validate_notify_uri(uri) {
+            None
+        } else if let Some(method) = parse_uri(uri).and_then(|(s, _)| self.runtime.notify_method(s))
+        {
+            method.capability(uri, capability)
+        } else if capability.eq_ignore_ascii_case("online") {
+            Some("maybe".to_string())
+        } else {
+            None
+        }
+    }
+}
+
+fn decode_uri(value: &str) -> Option<String> {
+    let mut result = Vec::with_capacity(value.len());
+    let mut iter = value.as_bytes().iter();
+    while let Some(&ch) = iter.next() {
+        if ch == b'%' {
+            let hex1 = HEX_MAP[*iter.next()? as usize];
+            let hex2 = HEX_MAP[*iter.next()? as usize];
+            if hex1 == -1 || hex2 == -1 {
+                return None;
+            }
+            result.push(((hex1 as u8) << 4) | hex2 as u8);
+        } else {
+            result.push(ch);
+        }
+    }
+    String::from_utf8(result).ok()
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '&' => result.push_str("&amp;"),
+            '\'' => result.push_str("&apos;"),
+            '"' => result.push_str("&quot;"),
+            _ => result.push(ch),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::MessageParser;
+
+    use super::{Notification, NotifyMethod, NotifyPayload};
+    use crate::{Compiler, Context, Event, Importance, Input, Runtime};
+
+    #[derive(Debug)]
+    struct PagerMethod;
+
+    impl NotifyMethod for PagerMethod {
+        fn validate_uri(&self, uri: &str) -> bool {
+            uri.len() > 6
+        }
+
+        fn capability(&self, _uri: &str, capability: &str) -> Option<String> {
+            (capability == "online").then(|| "yes".to_string())
+        }
+
+        fn build(&self, notification: &Notification<'_>) -> Option<NotifyPayload> {
+            Some(NotifyPayload {
+                recipients: vec![notification.uri[6..].to_string()],
+                content_type: "text/plain".to_string(),
+                body: notification.message.as_bytes().to_vec(),
+            })
+        }
+    }
+
+    fn run_notify(runtime: &Runtime, compiler: &Compiler, script: &str) -> Vec<Event> {
+        let script = compiler.compile(script.as_bytes()).unwrap();
+        let raw_message = concat!(
+            "From: Joe <joe@example.org>\r\n",
+            "To: jane@example.org\r\n",
+            "Subject: Lunch \"today\"?\r\n",
+            "\r\n",
+            "Let's meet at noon.\r\n",
+        );
+        let (events, error) = Context::new(
+            runtime,
+            MessageParser::new().parse(raw_message.as_bytes()).unwrap(),
+        )
+        .with_user_address("jane@example.org")
+        .run_input(Input::script("test", script));
+        assert!(error.is_none(), "{error:?}");
+        events
+            .into_iter()
+            .filter(|event| matches!(event, Event::Notify { .. }))
+            .collect()
+    }
+
+    fn payload(event: &Event) -> (Vec<String>, String, String) {
+        match event {
+            Event::Notify {
+                payload: Some(payload),
+                ..
+            } => (
+                payload.recipients.clone(),
+                payload.content_type.clone(),
+                String::from_utf8(payload.body.clone()).unwrap(),
+            ),
+            event => panic!("unexpected event {event:?}"),
+        }
+    }
+
+    #[test]
+    fn builtin_methods() {
+        let runtime = Runtime::new();
+        let compiler = Compiler::new();
+
+        // XMPP
+        let events = run_notify(
+            &runtime,
+            &compiler,
+            concat!(
+                "require \"enotify\";\n",
+                "notify :importance \"1\" ",
+                "\"xmpp:romeo@example.net?message;subject=Mail%20<alert>\";"
+            ),
+        );
+        assert_eq!(
+            payload(&events[0]),
+            (
+                vec!["romeo@example.net".to_string()],
+                "application/xmpp+xml".to_string(),
+                concat!(
+                    "<message to='romeo@example.net' type='normal'>",
+                    "<subject>Mail &lt;alert&gt;</subject>",
+                    "<body>Lunch &quot;today&quot;?</body>",
+                    "<headers xmlns='http://jabber.org/protocol/shim'>",
+                    "<header name='Auto-Submitted'>auto-notified</header>",
+                    "<header name='Urgency'>high</header>",
+                    "</headers></message>"
+                )
+                .to_string()
+            )
+        );
+
+        // SMS, truncated
+        let events = run_notify(
+            &Runtime::new().with_notify_method("sms", super::SmsMethod::new(10)),
+            &compiler,
+            concat!(
+                "require \"enotify\";\n",
+                "notify :message \"You have new mail from Joe\" ",
+                "\"sms:+1-510-555-0101,+15105550102\";"
+            ),
+        );
+        assert_eq!(
+            payload(&events[0]),
+            (
+                vec!["+15105550101".to_string(), "+15105550102".to_string()],
+                "text/plain; charset=utf-8".to_string(),
+                "You have …".to_string()
+            )
+        );
+
+        // Webhook
+        let events = run_notify(
+            &runtime,
+            &compiler,
+            concat!(
+                "require \"enotify\";\n",
+                "notify :options [\"id=42\"] ",
+                "\"https://hooks.example.org/sieve\";"
+            ),
+        );
+        let (recipients, content_type, body) = payload(&events[0]);
+        assert_eq!(
+            recipients,
+            vec!["https://hooks.example.org/sieve".to_string()]
+        );
+        assert_eq!(content_type, "application/json");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({
+                "from": null,
+                "recipient": "jane@example.org",
+                "importance": "normal",
+                "message": "Lunch \"today\"?",
+                "subject": "Lunch \"today\"?",
+                "options": {"id": "42"}
+            })
+        );
+
+        // Invalid options cancel the notification
+        let events = run_notify(
+            &runtime,
+            &compiler,
+            concat!(
+                "require \"enotify\";\n",
+                "notify :options [\"invalid\"] \"https://hooks.example.org/sieve\";"
+            ),
+        );
+        assert!(events.is_empty());
+
+        // Methods without an implementation are passed through
+        let events = run_notify(
+            &runtime,
+            &compiler,
+            "require \"enotify\";\nnotify \"http://hooks.example.org/sieve\";",
+        );
+        assert!(matches!(
+            &events[0],
+            Event::Notify {
+                payload: None,
+                importance: Importance::Normal,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn custom_method() {
+        let script = "require \"enotify\";\nnotify :message \"Ping\" \"pager:1234\";";
+        assert!(Compiler::new().compile(script.as_bytes()).is_err());
+
+        let runtime = Runtime::new()
+            .with_notify_method("Pager", PagerMethod)
+            .with_valid_notification_uri("pager");
+        let compiler = Compiler::new().with_notify_scheme("pager");
+        let events = run_notify(&runtime, &compiler, script);
+        assert_eq!(
+            payload(&events[0]),
+            (
+                vec!["1234".to_string()],
+                "text/plain".to_string(),
+                "Ping".to_string()
+            )
+        );
+
+        let mut ctx = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(b"Subject: test\r\n\r\n")
+                .unwrap(),
+        );
+        assert!(ctx.validate_notify_uri("pager:1234"));
+        assert!(!ctx.validate_notify_uri("pager:"));
+        assert_eq!(
+            ctx.notify_capability("pager:1234", "online"),
+            Some("yes".to_string())
+        );
+        ctx.runtime.valid_notification_uris.clear();
+        assert!(!ctx.validate_notify_uri("pager:1234"));
+    }
+}
@@ -0,0 +1,231 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::fmt::Debug;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{runtime::RuntimeError, Context};
+
+/*
+ Host-defined restrictions on the destination of an action, checked before
+ the action is queued. The target is the redirect address, the notification
+ URI ("mailto:" followed by each recipient for mailto notifications), the
+ folder name or the vacation recipient.
+*/
+pub trait Policy: Debug + Send + Sync {
+    fn is_allowed(&self, account: &str, action: PolicyAction, target: &str) -> bool;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum PolicyAction {
+    Redirect,
+    Notify,
+    FileInto,
+    Vacation,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PolicyMode {
+    // Abort the script with RuntimeError::PolicyViolation
+    #[default]
+    Error,
+    // Drop the action and record the violation
+    Drop,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PolicyViolation {
+    pub action: PolicyAction,
+    pub target: String,
+}
+
+impl Context<'_> {
+    pub(crate) fn check_policy(
+        &mut self,
+        action: PolicyAction,
+        target: &str,
+    ) -> Result<bool, RuntimeError> {
+        match &self.runtime.policy {
+            Some(policy) if !policy.is_allowed(&self.user_address, action, target) => {
+                let violation = PolicyViolation {
+                    action,
+                    target: target.to_string(),
+                };
+                match self.runtime.policy_mode {
+                    PolicyMode::Error => Err(RuntimeError::PolicyViolation(violation)),
+                    PolicyMode::Drop => {
+                        self.policy_violations.push(violation);
+                        Ok(false)
+                    }
+                }
+            }
+            _ => Ok(true),
+        }
+    }
+
+    // Actions dropped by the policy while processing the current message
+    pub fn policy_violations(&self) -> &[PolicyViolation] {
+        &self.policy_violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::MessageParser;
+
+    use super::{Policy, PolicyAction, PolicyMode, PolicyViolation};
+    use crate::{runtime::RuntimeError, Context, Envelope, Event, Recipient, Runtime};
+
+    #[derive(Debug)]
+    struct CompliancePolicy;
+
+    impl Policy for CompliancePolicy {
+        fn is_allowed(&self, account: &str, action: PolicyAction, target: &str) -> bool {
+            assert_eq!(account, "jane@example.org");
+            match action {
+                PolicyAction::Redirect => target.ends_with("@example.org"),
+                PolicyAction::Notify => {
+                    !target.starts_with("mailto:") || target.ends_with("@example.org")
+                }
+                PolicyAction::FileInto => ["INBOX", "Work"].contains(&target),
+                PolicyAction::Vacation => target == "friend@example.org",
+            }
+        }
+    }
+
+    fn run(
+        runtime: &Runtime,
+        sender: &str,
+    ) -> (Vec<Event>, Option<RuntimeError>, Vec<PolicyViolation>) {
+        let script = concat!(
+            "require [\"fileinto\", \"enotify\", \"vacation\", \"copy\", \"fcc\"];\n",
+            "redirect :copy \"boss@example.org\";\n",
+            "redirect :copy \"friend@gmail.com\";\n",
+            "fileinto \"Work\";\n",
+            "fileinto \"Archive\";\n",
+            "notify :fcc \"Sent\" \"mailto:team@example.org\";\n",
+            "notify \"mailto:team@example.org?cc=spy@example.com\";\n",
+            "vacation :fcc \"Work\" \"I'm away\";\n",
+        );
+        let mut instance = Context::new(
+            runtime,
+            MessageParser::new()
+                .parse(b"From: sender@example.org\r\nTo: jane@example.org\r\nSubject: Hi\r\n\r\nHi\r\n")
+                .unwrap(),
+        )
+        .with_user_address("jane@example.org")
+        .with_envelope(Envelope::From, sender)
+        .with_envelope(Envelope::To, "jane@example.org");
+
+        let (mut events, error) = instance.run_script(script);
+        events.retain(|event| {
+            !matches!(
+                event,
+                Event::DuplicateId { .. } | Event::CreatedMessage { .. }
+            )
+        });
+        (events, error, instance.policy_violations().to_vec())
+    }
+
+    #[test]
+    fn action_policies() {
+        // Violations are dropped and recorded
+        let runtime = Runtime::new()
+            .with_policy(CompliancePolicy)
+            .with_policy_mode(PolicyMode::Drop)
+            .with_max_out_messages(10)
+            .with_max_redirects(10);
+        let (events, error, violations) = run(&runtime, "stranger@example.com");
+        assert!(error.is_none());
+        let targets = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::SendMessage {
+                    recipient: Recipient::Address(address),
+                    ..
+                } => Some(address.as_str()),
+                Event::SendMessage {
+                    recipient: Recipient::Group(addresses),
+                    ..
+                } => addresses.first().map(|a| a.as_str()),
+                Event::FileInto { folder, .. } => Some(folder.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(targets, ["boss@example.org", "Work", "team@example.org"]);
+        assert_eq!(
+            violations,
+            [
+                (PolicyAction::Redirect, "friend@gmail.com"),
+                (PolicyAction::FileInto, "Archive"),
+                (PolicyAction::FileInto, "Sent"),
+                (PolicyAction::Notify, "mailto:spy@example.com"),
+                (PolicyAction::Vacation, "stranger@example.com"),
+            ]
+            .into_iter()
+            .map(|(action, target)| PolicyViolation {
+                action,
+                target: target.to_string()
+            })
+            .collect::<Vec<_>>()
+        );
+
+        // Allowed vacation recipient, file carbon copies are checked too
+        let (events, _, violations) = run(&runtime, "friend@example.org");
+        assert_eq!(violations.len(), 4);
+        assert!(events.iter().any(|event| matches!(
+            event,
+            Event::SendMessage { recipient: Recipient::Address(address), .. }
+                if address == "friend@example.org"
+        )));
+        assert_eq!(
+            events
+                .iter()
+                .filter(|event| matches!(event, Event::FileInto { folder, .. } if folder == "Work"))
+                .count(),
+            2
+        );
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, Event::FileInto { folder, .. } if folder == "Sent")));
+
+        // Violations abort the script
+        let (events, error, violations) = run(
+            &runtime.with_policy_mode(PolicyMode::Error),
+            "friend@example.org",
+        );
+        assert!(matches!(
+            error,
+            Some(RuntimeError::PolicyViolation(PolicyViolation {
+                action: PolicyAction::Redirect,
+                ..
+            }))
+        ));
+        assert!(matches!(&events[0], Event::SendMessage { .. }));
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, Event::FileInto { .. })));
+        assert!(violations.is_empty());
+    }
+}
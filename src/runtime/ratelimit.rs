@@ -0,0 +1,257 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{fmt::Debug, sync::Mutex, time::Instant};
+
+use ahash::AHashMap;
+
+use crate::{runtime::RuntimeError, Context};
+
+/*
+ Backend for the vnd.stalwart.ratelimit extension and the built-in
+ outgoing message policies. Each call takes a token from the bucket
+ identified by account and key, which holds up to `count` tokens and
+ refills completely every `period` seconds.
+*/
+pub trait RateLimiter: Debug + Send + Sync {
+    fn is_allowed(&self, account: &str, key: &str, count: u64, period: u64) -> bool;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateLimitMode {
+    // Abort the script with RuntimeError::RateLimitExceeded
+    #[default]
+    Error,
+    // Silently drop the action
+    Skip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RateLimit {
+    pub count: u64,
+    pub period: u64,
+}
+
+// Keys starting with this prefix belong to host configured policies
+// and are not available to scripts.
+pub(crate) const RESERVED_KEY_PREFIX: char = '!';
+pub(crate) const SEND_POLICY_KEY: &str = "!send";
+pub(crate) const NOTIFY_POLICY_KEY: &str = "!notify";
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    count: u64,
+    period: u64,
+    updated: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryRateLimiter {
+    buckets: Mutex<AHashMap<(String, String), Bucket>>,
+}
+
+impl MemoryRateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Removes buckets that have refilled completely.
+    pub fn purge_expired(&self) {
+        let now = Instant::now();
+        if let Ok(mut buckets) = self.buckets.lock() {
+            buckets.retain(|_, bucket| bucket.refill(now) < bucket.count as f64);
+        }
+    }
+}
+
+impl Bucket {
+    fn refill(&self, now: Instant) -> f64 {
+        if self.period == 0 {
+            return self.count as f64;
+        }
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * self.count as f64 / self.period as f64).min(self.count as f64)
+    }
+}
+
+impl RateLimiter for MemoryRateLimiter {
+    fn is_allowed(&self, account: &str, key: &str, count: u64, period: u64) -> bool {
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return true;
+        };
+        let now = Instant::now();
+        let bucket = buckets
+            .entry((account.to_string(), key.to_string()))
+            .or_insert_with(|| Bucket {
+                tokens: count as f64,
+                count,
+                period,
+                updated: now,
+            });
+
+        // The most recent limits apply if the script changed them
+        bucket.count = count;
+        bucket.period = period;
+        bucket.tokens = bucket.refill(now);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Context<'_> {
+    // Applies a built-in policy before an outgoing message or notification
+    // is queued, returns false if the action has to be skipped.
+    pub(crate) fn check_rate_limit(
+        &self,
+        key: &str,
+        limit: Option<RateLimit>,
+    ) -> Result<bool, RuntimeError> {
+        match limit {
+            Some(limit)
+                if !self.runtime.rate_limiter.is_allowed(
+                    &self.user_address,
+                    key,
+                    limit.count,
+                    limit.period,
+                ) =>
+            {
+                match self.runtime.rate_limit_mode {
+                    RateLimitMode::Error => Err(RuntimeError::RateLimitExceeded),
+                    RateLimitMode::Skip => Ok(false),
+                }
+            }
+            _ => Ok(true),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::MessageParser;
+
+    use super::{MemoryRateLimiter, RateLimitMode, RateLimiter};
+    use crate::{
+        compiler::grammar::Capability, runtime::RuntimeError, Context, Envelope, Event, Runtime,
+    };
+
+    const SCRIPT: &str = concat!(
+        "require [\"enotify\", \"vacation\"];\n",
+        "notify \"mailto:jane@example.org\";\n",
+        "notify \"xmpp:jane@example.org\";\n",
+        "vacation \"I am away\";\n",
+    );
+
+    fn run(runtime: &Runtime, script: &str) -> (Vec<Event>, Option<RuntimeError>) {
+        Context::new(
+            runtime,
+            MessageParser::new()
+                .parse(b"From: john@example.org\r\nTo: jane@example.org\r\nSubject: Hi\r\n\r\nHello\r\n".as_slice())
+                .unwrap(),
+        )
+        .with_user_address("jane@example.org")
+        .with_envelope(Envelope::From, "john@example.org")
+        .run_script(script)
+    }
+
+    fn count_sent(events: &[Event]) -> (usize, usize) {
+        (
+            events
+                .iter()
+                .filter(|e| matches!(e, Event::SendMessage { .. }))
+                .count(),
+            events
+                .iter()
+                .filter(|e| matches!(e, Event::Notify { .. }))
+                .count(),
+        )
+    }
+
+    #[test]
+    fn token_bucket() {
+        let limiter = MemoryRateLimiter::new();
+        assert!(limiter.is_allowed("john", "key", 2, 3600));
+        assert!(limiter.is_allowed("john", "key", 2, 3600));
+        assert!(!limiter.is_allowed("john", "key", 2, 3600));
+        assert!(limiter.is_allowed("jane", "key", 2, 3600));
+        assert!(!limiter.is_allowed("john", "key", 0, 3600));
+        assert!(limiter.is_allowed("john", "key", 2, 0));
+        limiter.purge_expired();
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rate_limit_policies() {
+        // No policies configured
+        let (events, error) = run(&Runtime::new(), SCRIPT);
+        assert!(error.is_none());
+        assert_eq!(count_sent(&events), (2, 1));
+
+        // Skipped actions leave the rest of the script untouched
+        let runtime = Runtime::new()
+            .with_send_rate_limit(1, 3600)
+            .with_notify_rate_limit(1, 3600)
+            .with_rate_limit_mode(RateLimitMode::Skip);
+        let (events, error) = run(&runtime, SCRIPT);
+        assert!(error.is_none());
+        assert_eq!(count_sent(&events), (1, 1));
+        let (events, error) = run(&runtime, SCRIPT);
+        assert!(error.is_none());
+        assert_eq!(count_sent(&events), (0, 0));
+        assert!(events.iter().any(|e| matches!(e, Event::Keep { .. })));
+
+        // Exceeding a limit aborts the script
+        let runtime = Runtime::new().with_send_rate_limit(1, 3600);
+        let (events, error) = run(&runtime, SCRIPT);
+        assert!(matches!(error, Some(RuntimeError::RateLimitExceeded)));
+        assert_eq!(count_sent(&events), (1, 1));
+    }
+
+    #[test]
+    fn reserved_keys() {
+        // Scripts cannot refill or change the limits of a policy bucket
+        let runtime = Runtime::new()
+            .with_capability(Capability::RateLimit)
+            .with_send_rate_limit(1, 3600)
+            .with_rate_limit_mode(RateLimitMode::Skip);
+        let (events, _) = run(&runtime, "require \"vacation\";\nvacation \"I am away\";\n");
+        assert_eq!(count_sent(&events), (1, 0));
+        let (events, error) = run(
+            &runtime,
+            concat!(
+                "require [\"vnd.stalwart.ratelimit\", \"vacation\"];\n",
+                "if ratelimit :key \"!send\" :count 100 :period 0 { discard; }\n",
+                "vacation \"I am away\";\n",
+            ),
+        );
+        assert!(error.is_none());
+        assert_eq!(count_sent(&events), (0, 0));
+        assert!(!events.iter().any(|e| matches!(e, Event::Discard)));
+    }
+}
@@ -0,0 +1,266 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+/*
+   Sender Rewriting Scheme
+
+   SRS0=HHHH=TT=orig-domain=orig-local@srs-domain
+   SRS1=HHHH=first-srs-domain==HHHH=TT=orig-domain=orig-local@srs-domain
+*/
+
+const BASE32: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const TIMESTAMP_SLOTS: u64 = 1024;
+
+#[derive(Debug, Clone)]
+pub struct Srs {
+    key: Vec<u8>,
+    domain: String,
+    max_age: u64,
+}
+
+impl Srs {
+    pub fn new(key: impl Into<Vec<u8>>, domain: impl Into<String>) -> Self {
+        Srs {
+            key: key.into(),
+            domain: domain.into().to_ascii_lowercase(),
+            max_age: 21,
+        }
+    }
+
+    pub fn with_max_age(mut self, days: u64) -> Self {
+        self.max_age = days.clamp(1, TIMESTAMP_SLOTS - 1);
+        self
+    }
+
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    // Rewrites a sender address, returns it unchanged if it already
+    // belongs to the SRS domain.
+    pub fn forward(&self, address: &str, now: u64) -> Option<String> {
+        let (local, domain) = address.rsplit_once('@')?;
+        if local.is_empty() || domain.is_empty() {
+            return None;
+        } else if domain.eq_ignore_ascii_case(&self.domain) {
+            return Some(address.to_string());
+        }
+
+        let srs_local = if let Some(rest) = strip_prefix_ignore_case(local, "SRS0=") {
+            // Address was rewritten by another forwarder
+            let user = format!("={rest}");
+            format!("SRS1={}={}={}", self.hash(&[domain, &user]), domain, user)
+        } else if let Some(rest) = strip_prefix_ignore_case(local, "SRS1=") {
+            // Keep the first forwarder, replace the hash
+            let (_, rest) = rest.split_once('=')?;
+            let (first_domain, user) = rest.split_once('=')?;
+            format!(
+                "SRS1={}={}={}",
+                self.hash(&[first_domain, user]),
+                first_domain,
+                user
+            )
+        } else {
+            let timestamp = encode_timestamp(now);
+            format!(
+                "SRS0={}={}={}={}",
+                self.hash(&[&timestamp, domain, local]),
+                timestamp,
+                domain,
+                local
+            )
+        };
+
+        Some(format!("{srs_local}@{}", self.domain))
+    }
+
+    // Reverses an SRS address, returning the address bounces should be
+    // routed to. Returns None if the address is not a valid SRS address.
+    pub fn reverse(&self, address: &str, now: u64) -> Option<String> {
+        let (local, domain) = address.rsplit_once('@')?;
+        if !domain.eq_ignore_ascii_case(&self.domain) {
+            return None;
+        }
+
+        if let Some(rest) = strip_prefix_ignore_case(local, "SRS0=") {
+            let mut parts = rest.splitn(4, '=');
+            let hash = parts.next()?;
+            let timestamp = parts.next()?;
+            let orig_domain = parts.next()?;
+            let orig_local = parts.next()?;
+
+            if !orig_domain.is_empty()
+                && !orig_local.is_empty()
+                && self.check_timestamp(timestamp, now)
+                && hash.eq_ignore_ascii_case(&self.hash(&[timestamp, orig_domain, orig_local]))
+            {
+                Some(format!("{orig_local}@{orig_domain}"))
+            } else {
+                None
+            }
+        } else if let Some(rest) = strip_prefix_ignore_case(local, "SRS1=") {
+            let (hash, rest) = rest.split_once('=')?;
+            let (first_domain, user) = rest.split_once('=')?;
+
+            if !first_domain.is_empty()
+                && user.starts_with('=')
+                && hash.eq_ignore_ascii_case(&self.hash(&[first_domain, user]))
+            {
+                Some(format!("SRS0{user}@{first_domain}"))
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
+
+    fn hash(&self, parts: &[&str]) -> String {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.key).expect("HMAC accepts any key size");
+        for part in parts {
+            mac.update(part.to_ascii_lowercase().as_bytes());
+        }
+        let digest = mac.finalize().into_bytes();
+        let bits = ((digest[0] as u32) << 16) | ((digest[1] as u32) << 8) | digest[2] as u32;
+        (0..4)
+            .map(|i| BASE64[((bits >> (18 - (i * 6))) & 0x3f) as usize] as char)
+            .collect()
+    }
+
+    fn check_timestamp(&self, timestamp: &str, now: u64) -> bool {
+        decode_timestamp(timestamp).is_some_and(|timestamp| {
+            let today = (now / 86400) % TIMESTAMP_SLOTS;
+            (today + TIMESTAMP_SLOTS - timestamp) % TIMESTAMP_SLOTS <= self.max_age
+        })
+    }
+}
+
+fn encode_timestamp(now: u64) -> String {
+    let days = (now / 86400) % TIMESTAMP_SLOTS;
+    [
+        BASE32[(days >> 5) as usize & 0x1f] as char,
+        BASE32[days as usize & 0x1f] as char,
+    ]
+    .iter()
+    .collect()
+}
+
+fn decode_timestamp(timestamp: &str) -> Option<u64> {
+    let mut days = 0;
+    if timestamp.len() != 2 {
+        return None;
+    }
+    for ch in timestamp.bytes() {
+        let pos = BASE32.iter().position(|b| *b == ch.to_ascii_uppercase())?;
+        days = (days << 5) | pos as u64;
+    }
+    Some(days)
+}
+
+fn strip_prefix_ignore_case<'x>(value: &'x str, prefix: &str) -> Option<&'x str> {
+    if value.len() >= prefix.len()
+        && value.is_char_boundary(prefix.len())
+        && value[..prefix.len()].eq_ignore_ascii_case(prefix)
+    {
+        Some(&value[prefix.len()..])
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Srs;
+
+    const NOW: u64 = 1_700_000_000;
+    const DAY: u64 = 86400;
+
+    #[test]
+    fn srs_forward_reverse() {
+        let srs = Srs::new(b"secret".to_vec(), "forward.example.org");
+
+        // SRS0
+        let srs0 = srs.forward("john.doe@example.com", NOW).unwrap();
+        assert!(srs0.starts_with("SRS0="), "{srs0}");
+        assert!(srs0.ends_with("=example.com=john.doe@forward.example.org"));
+        assert_eq!(
+            srs.reverse(&srs0, NOW + 3 * DAY).unwrap(),
+            "john.doe@example.com"
+        );
+        assert_eq!(
+            srs.reverse(&srs0.to_ascii_lowercase(), NOW).unwrap(),
+            "john.doe@example.com"
+        );
+
+        // Expired timestamp
+        assert_eq!(srs.reverse(&srs0, NOW + 30 * DAY), None);
+
+        // Tampered address
+        assert_eq!(
+            srs.reverse(&srs0.replace("john.doe", "jane.doe"), NOW),
+            None
+        );
+        assert_eq!(
+            Srs::new(b"other".to_vec(), "forward.example.org").reverse(&srs0, NOW),
+            None
+        );
+
+        // Own domain and invalid addresses
+        assert_eq!(
+            srs.forward("user@Forward.Example.org", NOW).unwrap(),
+            "user@Forward.Example.org"
+        );
+        assert_eq!(srs.forward("invalid", NOW), None);
+        assert_eq!(srs.reverse("user@forward.example.org", NOW), None);
+
+        // SRS1 from an address rewritten by another forwarder
+        let srs_other = Srs::new(b"other-secret".to_vec(), "first.example.net");
+        let first = srs_other.forward("john.doe@example.com", NOW).unwrap();
+        let srs1 = srs.forward(&first, NOW).unwrap();
+        assert!(
+            srs1.starts_with("SRS1=")
+                && srs1.contains("=first.example.net==")
+                && srs1.ends_with("@forward.example.org"),
+            "{srs1}"
+        );
+        assert_eq!(srs.reverse(&srs1, NOW).unwrap(), first);
+        assert_eq!(
+            srs_other.reverse(&first, NOW).unwrap(),
+            "john.doe@example.com"
+        );
+
+        // SRS1 from an SRS1 address keeps the first forwarder
+        let srs_third = Srs::new(b"third-secret".to_vec(), "third.example.com");
+        let srs1_again = srs_third.forward(&srs1, NOW).unwrap();
+        assert!(
+            srs1_again.contains("=first.example.net==")
+                && srs1_again.ends_with("@third.example.com"),
+            "{srs1_again}"
+        );
+        assert_eq!(srs_third.reverse(&srs1_again, NOW).unwrap(), first);
+    }
+}
@@ -0,0 +1,85 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    fmt::Debug,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use ahash::AHashMap;
+
+use super::Variable;
+
+/*
+ Backend for the vnd.stalwart.store extension. Keys are scoped by the
+ account the script runs for, expired entries must not be returned.
+*/
+pub trait KeyValueStore: Debug + Send + Sync {
+    fn get(&self, account: &str, key: &str) -> Option<Variable>;
+    fn set(&self, account: &str, key: &str, value: Variable, ttl: Option<u64>);
+}
+
+type Entries = AHashMap<(String, String), (Variable, Option<Instant>)>;
+
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    entries: Mutex<Entries>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn purge_expired(&self) {
+        let now = Instant::now();
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.retain(|_, (_, expires)| expires.is_none_or(|expires| expires > now));
+        }
+    }
+}
+
+impl KeyValueStore for MemoryStore {
+    fn get(&self, account: &str, key: &str) -> Option<Variable> {
+        let entries = self.entries.lock().ok()?;
+        let (value, expires) = entries.get(&(account.to_string(), key.to_string()))?;
+        if expires.is_none_or(|expires| expires > Instant::now()) {
+            Some(value.clone())
+        } else {
+            None
+        }
+    }
+
+    fn set(&self, account: &str, key: &str, value: Variable, ttl: Option<u64>) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.insert(
+                (account.to_string(), key.to_string()),
+                (
+                    value,
+                    ttl.map(|ttl| Instant::now() + Duration::from_secs(ttl)),
+                ),
+            );
+        }
+    }
+}
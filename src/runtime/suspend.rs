@@ -0,0 +1,426 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{borrow::Cow, fmt::Display, sync::Arc, time::Instant};
+
+use mail_parser::MessageParser;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    compiler::grammar::Capability, Context, Envelope, Event, Metadata, Script, Sieve, SpamStatus,
+    VirusStatus,
+};
+
+use super::{context::ScriptStack, policy::PolicyViolation, Variable};
+
+/*
+ Snapshot of a paused execution. It can be persisted with any serde format
+ and resumed later on a Context created from the same raw message, the
+ scripts themselves are not included and have to be provided again.
+*/
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExecutionState {
+    message_checksum: [u8; 20],
+    modified_message: Option<Vec<u8>>,
+    is_message_parsed: bool,
+    message_size: usize,
+    original_headers: Option<Vec<u8>>,
+
+    scripts: Vec<(Script, [u8; 20])>,
+    script_stack: Vec<ScriptState>,
+    pos: usize,
+    test_result: bool,
+
+    part: usize,
+    part_iter: Vec<usize>,
+    part_iter_stack: Vec<(usize, Vec<usize>)>,
+
+    vars_global: Vec<(String, Variable)>,
+    vars_env: Vec<(String, Variable)>,
+    vars_local: Vec<Variable>,
+    vars_match: Vec<Variable>,
+    expr_stack: Vec<Variable>,
+    expr_pos: usize,
+
+    envelope: Vec<(Envelope, Variable)>,
+    metadata: Vec<(Metadata<String>, String)>,
+    user_address: String,
+    user_full_name: String,
+    vacation_return_date: String,
+    current_time: i64,
+    spam_status: SpamStatus,
+    virus_status: VirusStatus,
+
+    queued_events: Vec<Event>,
+    final_event: Option<Event>,
+    last_message_id: usize,
+    main_message_id: usize,
+    has_changes: bool,
+    body_changed: bool,
+
+    num_redirects: usize,
+    num_instructions: usize,
+    num_out_messages: usize,
+    policy_violations: Vec<PolicyViolation>,
+    memory_used: usize,
+
+    denied_capabilities: Vec<Capability>,
+    chain_pending: bool,
+    has_stopped: bool,
+    final_event_set: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ScriptState {
+    script: Script,
+    prev_pos: usize,
+    prev_vars_local: Vec<Variable>,
+    prev_vars_match: Vec<Variable>,
+    prev_expr: Option<(Vec<Variable>, usize)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResumeError {
+    MessageMismatch,
+    ScriptNotFound(Script),
+    ScriptMismatch(Script),
+    InvalidMessage,
+}
+
+impl ExecutionState {
+    // Scripts that have to be provided when resuming
+    pub fn scripts(&self) -> impl Iterator<Item = &Script> {
+        self.scripts.iter().map(|(name, _)| name)
+    }
+}
+
+impl<'x> Context<'x> {
+    pub fn suspend(&self) -> ExecutionState {
+        let is_modified = self.has_changes || self.main_message_id > 0;
+
+        ExecutionState {
+            message_checksum: self.message_checksum.unwrap_or_else(|| {
+                checksum(
+                    self.pending_message
+                        .unwrap_or(self.message.raw_message.as_ref()),
+                )
+            }),
+            modified_message: is_modified.then(|| self.build_message()),
+            is_message_parsed: self.pending_message.is_none(),
+            message_size: self.message_size,
+            original_headers: self.original_headers.clone(),
+            scripts: self
+                .script_cache
+                .iter()
+                .map(|(name, script)| (name.clone(), script_checksum(script)))
+                .collect(),
+            script_stack: self
+                .script_stack
+                .iter()
+                .map(|stack| ScriptState {
+                    script: self
+                        .script_cache
+                        .iter()
+                        .find(|(_, script)| Arc::ptr_eq(script, &stack.script))
+                        .map(|(name, _)| name.clone())
+                        .unwrap_or_else(|| {
+                            debug_assert!(false, "Script not found in cache.");
+                            Script::Personal(String::new())
+                        }),
+                    prev_pos: stack.prev_pos,
+                    prev_vars_local: stack.prev_vars_local.clone(),
+                    prev_vars_match: stack.prev_vars_match.clone(),
+                    prev_expr: stack.prev_expr.clone(),
+                })
+                .collect(),
+            pos: self.pos,
+            test_result: self.test_result,
+            part: self.part,
+            part_iter: self.part_iter.as_slice().to_vec(),
+            part_iter_stack: self
+                .part_iter_stack
+                .iter()
+                .map(|(part, iter)| (*part, iter.as_slice().to_vec()))
+                .collect(),
+            vars_global: self
+                .vars_global
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.clone()))
+                .collect(),
+            vars_env: self
+                .vars_env
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.clone()))
+                .collect(),
+            vars_local: self.vars_local.clone(),
+            vars_match: self.vars_match.clone(),
+            expr_stack: self.expr_stack.clone(),
+            expr_pos: self.expr_pos,
+            envelope: self.envelope.clone(),
+            metadata: self
+                .metadata
+                .iter()
+                .map(|(metadata, value)| (metadata.clone(), value.to_string()))
+                .collect(),
+            user_address: self.user_address.to_string(),
+            user_full_name: self.user_full_name.to_string(),
+            vacation_return_date: self.vacation_return_date.to_string(),
+            current_time: self.current_time,
+            spam_status: self.spam_status,
+            virus_status: self.virus_status,
+            queued_events: self.queued_events.as_slice().to_vec(),
+            final_event: self.final_event.clone(),
+            last_message_id: self.last_message_id,
+            main_message_id: self.main_message_id,
+            has_changes: self.has_changes,
+            body_changed: self.body_changed,
+            num_redirects: self.num_redirects,
+            num_instructions: self.num_instructions,
+            num_out_messages: self.num_out_messages,
+            policy_violations: self.policy_violations.clone(),
+            memory_used: self.memory_used,
+            denied_capabilities: self.denied_capabilities.iter().cloned().collect(),
+            chain_pending: self.chain_pending,
+            has_stopped: self.has_stopped,
+            final_event_set: self.final_event_set,
+        }
+    }
+
+    /*
+     Restores a suspended execution, the context has to be created from the
+     same raw message. The callback returns the scripts listed by
+     ExecutionState::scripts, which are verified against their checksums.
+     Execution continues with the next call to run.
+    */
+    pub fn resume(
+        &mut self,
+        state: ExecutionState,
+        mut get_script: impl FnMut(&Script) -> Option<Arc<Sieve>>,
+    ) -> Result<(), ResumeError> {
+        let raw_message = self
+            .pending_message
+            .unwrap_or(self.message.raw_message.as_ref());
+        if checksum(raw_message) != state.message_checksum {
+            return Err(ResumeError::MessageMismatch);
+        }
+
+        // Verify scripts before modifying the context
+        let mut scripts = Vec::with_capacity(state.scripts.len());
+        for (name, expected_checksum) in state.scripts {
+            let script =
+                get_script(&name).ok_or_else(|| ResumeError::ScriptNotFound(name.clone()))?;
+            if script_checksum(&script) != expected_checksum {
+                return Err(ResumeError::ScriptMismatch(name));
+            }
+            scripts.push((name, script));
+        }
+        let mut script_stack = Vec::with_capacity(state.script_stack.len());
+        for stack in state.script_stack {
+            script_stack.push(ScriptStack {
+                script: scripts
+                    .iter()
+                    .find(|(name, _)| name == &stack.script)
+                    .map(|(_, script)| script.clone())
+                    .ok_or(ResumeError::ScriptNotFound(stack.script))?,
+                prev_pos: stack.prev_pos,
+                prev_vars_local: stack.prev_vars_local,
+                prev_vars_match: stack.prev_vars_match,
+                prev_expr: stack.prev_expr,
+            });
+        }
+
+        if let Some(modified_message) = state.modified_message {
+            self.message = MessageParser::new()
+                .parse(&modified_message)
+                .ok_or(ResumeError::InvalidMessage)?
+                .into_owned();
+            self.pending_message = None;
+        } else if state.is_message_parsed {
+            self.parse_message();
+        }
+        self.message_checksum = state.message_checksum.into();
+        self.message_size = state.message_size;
+        self.original_headers = state.original_headers;
+
+        self.script_cache = scripts.into_iter().collect();
+        self.script_stack = script_stack;
+        self.pos = state.pos;
+        self.test_result = state.test_result;
+        self.part = state.part;
+        self.part_iter = state.part_iter.into_iter();
+        self.part_iter_stack = state
+            .part_iter_stack
+            .into_iter()
+            .map(|(part, iter)| (part, iter.into_iter()))
+            .collect();
+        self.vars_global = state
+            .vars_global
+            .into_iter()
+            .map(|(name, value)| (Cow::Owned(name), value))
+            .collect();
+        self.vars_env = state
+            .vars_env
+            .into_iter()
+            .map(|(name, value)| (Cow::Owned(name), value))
+            .collect();
+        self.vars_local = state.vars_local;
+        self.vars_match = state.vars_match;
+        self.expr_stack = state.expr_stack;
+        self.expr_pos = state.expr_pos;
+        self.envelope = state.envelope;
+        self.metadata = state
+            .metadata
+            .into_iter()
+            .map(|(metadata, value)| (metadata, Cow::Owned(value)))
+            .collect();
+        self.user_address = state.user_address.into();
+        self.user_full_name = state.user_full_name.into();
+        self.vacation_return_date = state.vacation_return_date.into();
+        self.current_time = state.current_time;
+        self.spam_status = state.spam_status;
+        self.virus_status = state.virus_status;
+        self.queued_events = state.queued_events.into_iter();
+        self.final_event = state.final_event;
+        self.last_message_id = state.last_message_id;
+        self.main_message_id = state.main_message_id;
+        self.has_changes = state.has_changes;
+        self.body_changed = state.body_changed;
+        self.num_redirects = state.num_redirects;
+        self.num_instructions = state.num_instructions;
+        self.num_out_messages = state.num_out_messages;
+        self.policy_violations = state.policy_violations;
+        self.memory_used = state.memory_used;
+        self.denied_capabilities = state.denied_capabilities.into_iter().collect();
+        self.chain_pending = state.chain_pending;
+        self.has_stopped = state.has_stopped;
+        self.final_event_set = state.final_event_set;
+        self.deadline = self.runtime.time_limit.map(|limit| Instant::now() + limit);
+
+        Ok(())
+    }
+}
+
+pub(crate) fn checksum(bytes: &[u8]) -> [u8; 20] {
+    Sha1::digest(bytes).into()
+}
+
+fn script_checksum(script: &Sieve) -> [u8; 20] {
+    checksum(&script.serialize().unwrap_or_default())
+}
+
+impl Display for ResumeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResumeError::MessageMismatch => {
+                write!(f, "Message does not match the suspended execution.")
+            }
+            ResumeError::ScriptNotFound(script) => write!(f, "Script {script:?} not found."),
+            ResumeError::ScriptMismatch(script) => {
+                write!(f, "Script {script:?} was modified after suspending.")
+            }
+            ResumeError::InvalidMessage => write!(f, "Failed to parse the modified message."),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::MessageParser;
+
+    use crate::{Compiler, Context, Event, Input, Runtime, Script};
+
+    use super::{ExecutionState, ResumeError};
+
+    const MESSAGE: &str = "From: john@example.org\r\nSubject: Hello\r\n\r\nHi there\r\n";
+
+    #[test]
+    fn suspend_resume() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["mailbox", "variables", "editheader", "fileinto"];
+set "folder" "Friends";
+addheader "X-Sorted" "yes";
+if mailboxexists "${folder}" {
+    fileinto "${folder}";
+} else {
+    discard;
+}
+"#,
+            )
+            .unwrap();
+        let runtime = Runtime::new();
+
+        // Run until the script asks for a mailbox lookup
+        let state = {
+            let mut ctx = Context::new(&runtime, MessageParser::new().parse(MESSAGE).unwrap());
+            let event = ctx
+                .run(Input::script("personal", script.clone()))
+                .unwrap()
+                .unwrap();
+            assert!(matches!(event, Event::MailboxExists { .. }), "{event:?}");
+            serde_json::to_string(&ctx.suspend()).unwrap()
+        };
+        let state: ExecutionState = serde_json::from_str(&state).unwrap();
+        assert_eq!(
+            state.scripts().collect::<Vec<_>>(),
+            vec![&Script::from("personal")]
+        );
+
+        // Message and scripts are verified
+        let mut ctx = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(b"Subject: Other\r\n\r\nHi\r\n")
+                .unwrap(),
+        );
+        assert_eq!(
+            ctx.resume(state.clone(), |_| Some(script.clone().into())),
+            Err(ResumeError::MessageMismatch)
+        );
+        let other_script = Compiler::new().compile(b"discard;").unwrap();
+        let mut ctx = Context::new(&runtime, MessageParser::new().parse(MESSAGE).unwrap());
+        assert_eq!(
+            ctx.resume(state.clone(), |_| Some(other_script.clone().into())),
+            Err(ResumeError::ScriptMismatch(Script::from("personal")))
+        );
+
+        // Resume with the answer to the lookup
+        let mut ctx = Context::new(&runtime, MessageParser::new().parse(MESSAGE).unwrap());
+        ctx.resume(state, |name| {
+            (name.as_str() == "personal").then(|| script.clone().into())
+        })
+        .unwrap();
+        let (events, error) = ctx.run_input(Input::True);
+        assert!(error.is_none());
+
+        assert!(
+            matches!(&events[..], [
+                Event::CreatedMessage { message, .. },
+                Event::FileInto { folder, .. },
+            ] if folder == "Friends"
+                && std::str::from_utf8(message).unwrap().contains("X-Sorted: yes")),
+            "{events:?}"
+        );
+    }
+}
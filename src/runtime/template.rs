@@ -0,0 +1,366 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::borrow::Cow;
+
+use ahash::AHashMap;
+use mail_parser::HeaderName;
+
+use crate::{Context, Envelope};
+
+/*
+ Templates for the messages generated by vacation and notify. Placeholders
+ are written as {name} and replaced with the values available when the
+ message is built, unknown placeholders are left untouched:
+
+   {reason}       the reason given in the script (vacation)
+   {message}      the :message given in the script (notify)
+   {name}         display name (or address) of the original sender
+   {subject}      subject of the original message
+   {return_date}  return date set on the Context (vacation)
+
+ Templates can be registered per language, the language of the reply is
+ taken from the Accept-Language and Content-Language headers of the
+ original message or from the language configured for the sender's domain.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Quote {
+    #[default]
+    None,
+    Subject,
+    Lines(usize),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VacationTemplate {
+    pub(crate) body: Option<Cow<'static, str>>,
+    pub(crate) quote: Quote,
+    pub(crate) default_subject: Option<Cow<'static, str>>,
+    pub(crate) subject_prefix: Option<Cow<'static, str>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NotifyTemplate {
+    pub(crate) subject: Option<Cow<'static, str>>,
+    pub(crate) body: Option<Cow<'static, str>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Localized<T> {
+    pub default: T,
+    pub languages: AHashMap<String, T>,
+}
+
+impl VacationTemplate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Plain text or HTML body, the reply is sent as multipart/alternative
+    // if either the template or the reason contain HTML.
+    pub fn with_body(mut self, body: impl Into<Cow<'static, str>>) -> Self {
+        self.body = Some(body.into());
+        self
+    }
+
+    pub fn with_quote(mut self, quote: Quote) -> Self {
+        self.quote = quote;
+        self
+    }
+
+    // Override Runtime::vacation_default_subject for this template.
+    pub fn with_default_subject(mut self, subject: impl Into<Cow<'static, str>>) -> Self {
+        self.default_subject = Some(subject.into());
+        self
+    }
+
+    // Override Runtime::vacation_subject_prefix for this template.
+    pub fn with_subject_prefix(mut self, prefix: impl Into<Cow<'static, str>>) -> Self {
+        self.subject_prefix = Some(prefix.into());
+        self
+    }
+}
+
+impl NotifyTemplate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Used when the mailto URI does not specify a subject.
+    pub fn with_subject(mut self, subject: impl Into<Cow<'static, str>>) -> Self {
+        self.subject = Some(subject.into());
+        self
+    }
+
+    // Used when the mailto URI does not specify a body.
+    pub fn with_body(mut self, body: impl Into<Cow<'static, str>>) -> Self {
+        self.body = Some(body.into());
+        self
+    }
+}
+
+impl<T> Localized<T> {
+    pub fn insert(&mut self, language: &str, template: T) {
+        self.languages
+            .insert(language.trim().to_ascii_lowercase(), template);
+    }
+
+    // Returns the template for the first language available, matching
+    // either the full tag or its primary subtag ("de-AT" matches "de").
+    pub fn get(&self, languages: &[String]) -> &T {
+        if !self.languages.is_empty() {
+            for language in languages {
+                if let Some(template) = self.languages.get(language).or_else(|| {
+                    language
+                        .split_once('-')
+                        .and_then(|(primary, _)| self.languages.get(primary))
+                }) {
+                    return template;
+                }
+            }
+        }
+        &self.default
+    }
+}
+
+impl Context<'_> {
+    // Languages to reply in, in order of preference.
+    pub(crate) fn reply_languages(&self) -> Vec<String> {
+        let mut accept = Vec::new();
+        let mut content = Vec::new();
+
+        for header in &self.message.parts[0].headers {
+            match &header.name {
+                HeaderName::Other(name) if name.eq_ignore_ascii_case("Accept-Language") => {
+                    for (pos, item) in header
+                        .value
+                        .as_text()
+                        .unwrap_or_default()
+                        .split(',')
+                        .enumerate()
+                    {
+                        let (language, quality) = item.split_once(";q=").map_or(
+                            (item.trim(), 1.0f32),
+                            |(language, quality)| {
+                                (language.trim(), quality.trim().parse().unwrap_or(0.0))
+                            },
+                        );
+                        if !language.is_empty() && language != "*" && quality > 0.0 {
+                            accept.push((language.to_ascii_lowercase(), quality, pos));
+                        }
+                    }
+                }
+                HeaderName::ContentLanguage => {
+                    if let Some(languages) = header.value.as_text_list() {
+                        content.extend(languages.iter().map(|l| l.trim().to_ascii_lowercase()));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        accept.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.2.cmp(&b.2)));
+        let mut languages = accept
+            .into_iter()
+            .map(|(language, _, _)| language)
+            .chain(content)
+            .collect::<Vec<_>>();
+
+        if let Some(language) = self.envelope.iter().find_map(|(name, value)| {
+            if matches!(name, Envelope::From) {
+                let address = value.to_string();
+                let (_, domain) = address.rsplit_once('@')?;
+                self.runtime
+                    .domain_languages
+                    .get(&domain.to_ascii_lowercase())
+            } else {
+                None
+            }
+        }) {
+            languages.push(language.clone());
+        }
+
+        languages
+    }
+}
+
+pub(crate) fn render(template: &str, values: &[(&str, &str)], is_html: bool) -> String {
+    let mut result = String::with_capacity(template.len() + 64);
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let placeholder = rest.find('}').and_then(|end| {
+            values
+                .iter()
+                .find(|(name, _)| *name == &rest[1..end])
+                .map(|(name, value)| (*name, *value, end))
+        });
+        if let Some((name, value, end)) = placeholder {
+            if is_html && name != "reason" {
+                result.push_str(&escape_html(value));
+            } else {
+                result.push_str(value);
+            }
+            rest = &rest[end + 1..];
+        } else {
+            result.push('{');
+            rest = &rest[1..];
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+pub(crate) fn is_html(text: &str) -> bool {
+    let text = text.trim();
+    text.starts_with('<') && text.ends_with('>') && text.contains("</")
+}
+
+pub(crate) fn escape_html(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '&' => result.push_str("&amp;"),
+            '"' => result.push_str("&quot;"),
+            '\n' => result.push_str("<br>"),
+            '\r' => (),
+            _ => result.push(ch),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::MessageParser;
+
+    use super::{is_html, render, NotifyTemplate};
+    use crate::{Compiler, Context, Envelope, Event, Input, Runtime};
+
+    #[test]
+    fn render_template() {
+        let values = [
+            ("name", "Jane <jane@example.org>"),
+            ("reason", "<b>Away</b>"),
+        ];
+        assert_eq!(
+            render("Dear {name}, {reason} {unknown} {", &values, false),
+            "Dear Jane <jane@example.org>, <b>Away</b> {unknown} {"
+        );
+        assert_eq!(
+            render("<p>Dear {name},</p>{reason}", &values, true),
+            "<p>Dear Jane &lt;jane@example.org&gt;,</p><b>Away</b>"
+        );
+        assert!(is_html("<p>Out of office</p>\n"));
+        assert!(!is_html("I am <away> today"));
+    }
+
+    #[test]
+    fn localized_templates() {
+        let runtime = Runtime::new()
+            .with_notify_template(NotifyTemplate::new().with_subject("New message: {subject}"))
+            .with_localized_notify_template(
+                "de",
+                NotifyTemplate::new()
+                    .with_subject("Neue Nachricht: {subject}")
+                    .with_body("{name} hat geschrieben: {message}"),
+            )
+            .with_localized_notify_template(
+                "fr",
+                NotifyTemplate::new().with_subject("Nouveau message : {subject}"),
+            )
+            .with_localized_notify_template(
+                "pt",
+                NotifyTemplate::new().with_subject("Você tem uma nova mensagem: {subject}"),
+            )
+            .with_domain_language("example.fr", "FR");
+        let script = Compiler::new()
+            .compile(b"require \"enotify\"; notify :message \"Hallo\" \"mailto:jane@example.org\";")
+            .unwrap();
+
+        for (headers, sender, expected_subject, expected_body) in [
+            (
+                "Accept-Language: en;q=0.2, de-AT, fr;q=0.8\r\n",
+                "john@example.org",
+                "Neue Nachricht: Report",
+                "John hat geschrieben: Hallo",
+            ),
+            (
+                "Content-Language: es, fr\r\n",
+                "john@example.org",
+                "Nouveau message : Report",
+                "Hallo",
+            ),
+            ("", "john@example.fr", "Nouveau message : Report", "Hallo"),
+            (
+                "Accept-Language: it\r\n",
+                "john@example.org",
+                "New message: Report",
+                "Hallo",
+            ),
+            (
+                "Accept-Language: pt-BR\r\n",
+                "john@example.org",
+                "Você tem uma nova mensagem: Report",
+                "Hallo",
+            ),
+        ] {
+            let raw_message =
+                format!("From: John <{sender}>\r\n{headers}Subject: Report\r\n\r\nHi\r\n");
+            let (events, _) = Context::new(
+                &runtime,
+                MessageParser::new().parse(raw_message.as_bytes()).unwrap(),
+            )
+            .with_user_address("jane@example.org")
+            .with_envelope(Envelope::From, sender)
+            .run_input(Input::script("test", script.clone()));
+            let notification = events
+                .into_iter()
+                .find_map(|event| match event {
+                    Event::CreatedMessage { message, .. } => Some(message),
+                    _ => None,
+                })
+                .unwrap();
+
+            // Non-ASCII subjects are sent as encoded-words
+            let headers_end = notification
+                .windows(4)
+                .position(|w| w == b"\r\n\r\n")
+                .unwrap();
+            assert!(notification[..headers_end].is_ascii(), "{headers}");
+            let notification = MessageParser::new().parse(&notification).unwrap();
+            assert_eq!(notification.subject(), Some(expected_subject), "{headers}");
+            assert_eq!(
+                notification.body_text(0).unwrap().trim(),
+                expected_body,
+                "{headers}"
+            );
+        }
+    }
+}
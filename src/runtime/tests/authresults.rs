@@ -0,0 +1,302 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{iter::Peekable, str::Chars};
+
+/*
+   RFC 8601 - Message Header Field for Indicating Message Authentication Status
+
+   authres-header-field = "Authentication-Results:" authres-payload
+   authres-payload = [CFWS] authserv-id
+            [ CFWS authres-version ]
+            ( no-result / 1*resinfo ) [CFWS] CRLF
+   resinfo = [CFWS] ";" methodspec [ CFWS reasonspec ]
+             [ CFWS 1*propspec ]
+*/
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub(crate) struct AuthResults {
+    pub authserv_id: String,
+    pub results: Vec<AuthResult>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub(crate) struct AuthResult {
+    pub method: String,
+    pub result: String,
+    pub reason: Option<String>,
+    pub properties: Vec<AuthProperty>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub(crate) struct AuthProperty {
+    pub ptype: String,
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Value(String),
+    Semicolon,
+    Equal,
+}
+
+impl AuthResults {
+    pub fn parse(value: &str) -> Option<AuthResults> {
+        let mut tokens = Tokenizer {
+            iter: value.chars().peekable(),
+        }
+        .peekable();
+
+        let mut auth_results = AuthResults {
+            authserv_id: match tokens.next()? {
+                Token::Value(authserv_id) => authserv_id.to_ascii_lowercase(),
+                _ => return None,
+            },
+            results: Vec::new(),
+        };
+
+        // Skip the optional version
+        while !matches!(tokens.peek(), Some(Token::Semicolon) | None) {
+            tokens.next();
+        }
+
+        while tokens.next().is_some() {
+            let method = match tokens.next() {
+                Some(Token::Value(method)) => method,
+                _ => break,
+            };
+            if !matches!(tokens.next(), Some(Token::Equal)) {
+                // 'none' or malformed result
+                while !matches!(tokens.peek(), Some(Token::Semicolon) | None) {
+                    tokens.next();
+                }
+                continue;
+            }
+            let result = match tokens.next() {
+                Some(Token::Value(result)) => result,
+                _ => break,
+            };
+
+            let mut auth_result = AuthResult {
+                method: method
+                    .split_once('/')
+                    .map_or(method.as_str(), |(method, _)| method)
+                    .trim()
+                    .to_ascii_lowercase(),
+                result: result.to_ascii_lowercase(),
+                reason: None,
+                properties: Vec::new(),
+            };
+
+            while let Some(Token::Value(name)) = tokens.peek() {
+                let name = name.to_ascii_lowercase();
+                tokens.next();
+                if !matches!(tokens.peek(), Some(Token::Equal)) {
+                    continue;
+                }
+                tokens.next();
+                let value = match tokens.next() {
+                    Some(Token::Value(value)) => value,
+                    _ => break,
+                };
+                if name == "reason" {
+                    auth_result.reason = value.into();
+                } else if let Some((ptype, name)) = name.split_once('.') {
+                    auth_result.properties.push(AuthProperty {
+                        ptype: ptype.to_string(),
+                        name: name.to_string(),
+                        value,
+                    });
+                }
+            }
+
+            auth_results.results.push(auth_result);
+        }
+
+        Some(auth_results)
+    }
+}
+
+impl AuthResult {
+    pub fn property(&self, ptype: &str, name: &str) -> Option<&str> {
+        self.properties.iter().find_map(|p| {
+            if p.ptype == ptype && p.name == name {
+                Some(p.value.as_str())
+            } else {
+                None
+            }
+        })
+    }
+}
+
+struct Tokenizer<'x> {
+    iter: Peekable<Chars<'x>>,
+}
+
+impl Iterator for Tokenizer<'_> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut value = String::new();
+
+        while let Some(ch) = self.iter.peek() {
+            match ch {
+                ';' | '=' if !value.is_empty() => break,
+                ';' => {
+                    self.iter.next();
+                    return Some(Token::Semicolon);
+                }
+                '=' => {
+                    self.iter.next();
+                    return Some(Token::Equal);
+                }
+                '(' => {
+                    // Skip comment
+                    self.iter.next();
+                    let mut depth = 1;
+                    while let Some(ch) = self.iter.next() {
+                        match ch {
+                            '(' => depth += 1,
+                            ')' => {
+                                depth -= 1;
+                                if depth == 0 {
+                                    break;
+                                }
+                            }
+                            '\\' => {
+                                self.iter.next();
+                            }
+                            _ => (),
+                        }
+                    }
+                    if !value.is_empty() {
+                        break;
+                    }
+                }
+                '"' if value.is_empty() => {
+                    self.iter.next();
+                    while let Some(ch) = self.iter.next() {
+                        match ch {
+                            '"' => break,
+                            '\\' => {
+                                if let Some(ch) = self.iter.next() {
+                                    value.push(ch);
+                                }
+                            }
+                            _ => value.push(ch),
+                        }
+                    }
+                    return Some(Token::Value(value));
+                }
+                ch if ch.is_whitespace() => {
+                    self.iter.next();
+                    if !value.is_empty() {
+                        break;
+                    }
+                }
+                ch => {
+                    value.push(*ch);
+                    self.iter.next();
+                }
+            }
+        }
+
+        if !value.is_empty() {
+            Some(Token::Value(value))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuthProperty, AuthResult, AuthResults};
+
+    #[test]
+    fn parse_auth_results() {
+        for (header, expected) in [
+            (
+                "example.org 1; none",
+                AuthResults {
+                    authserv_id: "example.org".to_string(),
+                    results: vec![],
+                },
+            ),
+            (
+                concat!(
+                    " mx.example.com (version 2.1);\n",
+                    "  dkim=pass (good signature) header.d=example.net header.s=sel1;\n",
+                    "  spf=fail reason=\"no matching record\" smtp.mailfrom=user@example.net;\n",
+                    "  dmarc/1 = FAIL (p=reject) header.from=example.net"
+                ),
+                AuthResults {
+                    authserv_id: "mx.example.com".to_string(),
+                    results: vec![
+                        AuthResult {
+                            method: "dkim".to_string(),
+                            result: "pass".to_string(),
+                            reason: None,
+                            properties: vec![
+                                AuthProperty {
+                                    ptype: "header".to_string(),
+                                    name: "d".to_string(),
+                                    value: "example.net".to_string(),
+                                },
+                                AuthProperty {
+                                    ptype: "header".to_string(),
+                                    name: "s".to_string(),
+                                    value: "sel1".to_string(),
+                                },
+                            ],
+                        },
+                        AuthResult {
+                            method: "spf".to_string(),
+                            result: "fail".to_string(),
+                            reason: Some("no matching record".to_string()),
+                            properties: vec![AuthProperty {
+                                ptype: "smtp".to_string(),
+                                name: "mailfrom".to_string(),
+                                value: "user@example.net".to_string(),
+                            }],
+                        },
+                        AuthResult {
+                            method: "dmarc".to_string(),
+                            result: "fail".to_string(),
+                            reason: None,
+                            properties: vec![AuthProperty {
+                                ptype: "header".to_string(),
+                                name: "from".to_string(),
+                                value: "example.net".to_string(),
+                            }],
+                        },
+                    ],
+                },
+            ),
+        ] {
+            assert_eq!(AuthResults::parse(header), Some(expected), "{header}");
+        }
+    }
+}
@@ -35,7 +35,7 @@ use crate::{
 use super::glob::GlobPattern;
 
 pub(crate) trait Comparable {
-    fn to_str(&self) -> Cow<str>;
+    fn to_str(&self) -> Cow<'_, str>;
     fn to_number(&self) -> Number;
 }
 
@@ -132,7 +132,7 @@ impl Comparator {
 }
 
 impl Comparable for Variable {
-    fn to_str(&self) -> Cow<str> {
+    fn to_str(&self) -> Cow<'_, str> {
         self.to_string()
     }
 
@@ -142,7 +142,7 @@ impl Comparable for Variable {
 }
 
 impl Comparable for &str {
-    fn to_str(&self) -> Cow<str> {
+    fn to_str(&self) -> Cow<'_, str> {
         (*self).into()
     }
 
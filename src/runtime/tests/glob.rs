@@ -23,15 +23,17 @@
 
 use std::char::REPLACEMENT_CHARACTER;
 
+use serde::{Deserialize, Serialize};
+
 use crate::MAX_MATCH_VARIABLES;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct GlobPattern {
     pattern: Vec<PatternChar>,
     to_lower: bool,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PatternChar {
     WildcardMany { num: usize, match_pos: usize },
     WildcardSingle { match_pos: usize },
@@ -104,12 +106,10 @@ impl GlobPattern {
                         continue;
                     }
                 }
-                Some(PatternChar::WildcardSingle { .. }) => {
-                    if nx < value.len() {
-                        px += 1;
-                        nx += 1;
-                        continue;
-                    }
+                Some(PatternChar::WildcardSingle { .. }) if nx < value.len() => {
+                    px += 1;
+                    nx += 1;
+                    continue;
                 }
                 Some(PatternChar::WildcardMany { .. }) => {
                     next_px = px;
@@ -173,13 +173,11 @@ impl GlobPattern {
                         continue;
                     }
                 }
-                Some(PatternChar::WildcardSingle { match_pos }) => {
-                    if nx < value.len() {
-                        *match_pos = nx;
-                        px += 1;
-                        nx += 1;
-                        continue;
-                    }
+                Some(PatternChar::WildcardSingle { match_pos }) if nx < value.len() => {
+                    *match_pos = nx;
+                    px += 1;
+                    nx += 1;
+                    continue;
                 }
                 Some(PatternChar::WildcardMany { match_pos, .. }) => {
                     *match_pos = nx;
@@ -28,10 +28,12 @@ use crate::{
 
 use super::RuntimeError;
 
+pub mod authresults;
 pub mod comparator;
 pub mod glob;
 pub mod mime;
 pub mod test_address;
+pub mod test_authresults;
 pub mod test_body;
 pub mod test_date;
 pub mod test_duplicate;
@@ -42,8 +44,10 @@ pub mod test_hasflag;
 pub mod test_header;
 pub mod test_metadata;
 pub mod test_notify;
+pub mod test_ratelimit;
 pub mod test_size;
 pub mod test_spamtest;
+pub mod test_stored;
 pub mod test_string;
 
 pub(crate) enum TestResult {
@@ -73,7 +77,7 @@ impl Test {
             Test::Ihave(test) => TestResult::Bool(
                 test.capabilities.iter().all(|c| {
                     ![Capability::Variables, Capability::EncodedCharacter].contains(c)
-                        && ctx.runtime.allowed_capabilities.contains(c)
+                        && ctx.is_capability_allowed(c)
                 }) ^ test.is_not,
             ),
             Test::MailboxExists(test) => TestResult::Event {
@@ -102,6 +106,9 @@ impl Test {
                 is_not: test.is_not,
             },
             Test::SpamTest(test) => test.exec(ctx),
+            Test::AuthResults(test) => test.exec(ctx),
+            Test::Stored(test) => test.exec(ctx),
+            Test::RateLimit(test) => test.exec(ctx),
             Test::VirusTest(test) => test.exec(ctx),
             Test::SpecialUseExists(test) => TestResult::Event {
                 event: Event::MailboxExists {
@@ -0,0 +1,116 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use mail_parser::{HeaderName, HeaderValue, Host};
+
+use crate::{compiler::grammar::tests::test_authresults::TestAuthResults, Context};
+
+use super::{authresults::AuthResults, TestResult};
+
+impl TestAuthResults {
+    pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
+        let method = ctx
+            .eval_value(&self.method)
+            .to_string()
+            .to_ascii_lowercase();
+        let results = ctx.eval_values(&self.results);
+        let authserv_ids = ctx.eval_values(&self.authserv_ids);
+
+        let result = ctx.trusted_auth_results().iter().any(|auth_results| {
+            (authserv_ids.is_empty()
+                || authserv_ids.iter().any(|id| {
+                    id.to_string()
+                        .eq_ignore_ascii_case(auth_results.authserv_id.as_str())
+                }))
+                && auth_results.results.iter().any(|auth_result| {
+                    auth_result.method == method
+                        && if results.is_empty() {
+                            auth_result.result != "none"
+                        } else {
+                            results.iter().any(|result| {
+                                result
+                                    .to_string()
+                                    .eq_ignore_ascii_case(auth_result.result.as_str())
+                            })
+                        }
+                })
+        });
+
+        TestResult::Bool(result ^ self.is_not)
+    }
+}
+
+impl Context<'_> {
+    // Returns the Authentication-Results headers from trusted authentication
+    // servers that were added above the first Received hop not recorded by a
+    // trusted relay. Each header is attributed to the host that recorded the
+    // Received header immediately below it.
+    pub(crate) fn trusted_auth_results(&self) -> Vec<AuthResults> {
+        let mut auth_results = Vec::new();
+        let trusted_ids = &self.runtime.trusted_authserv_ids;
+        let trusted_relays = &self.runtime.trusted_relays;
+        if trusted_ids.is_empty() || trusted_relays.is_empty() {
+            return auth_results;
+        }
+
+        let raw_message = self.message.raw_message();
+        let mut pending = Vec::new();
+        for header in self
+            .message
+            .parts
+            .first()
+            .map(|part| part.headers.as_slice())
+            .unwrap_or_default()
+        {
+            match &header.name {
+                HeaderName::Received => {
+                    let is_trusted = matches!(&header.value, HeaderValue::Received(rcvd)
+                        if matches!(rcvd.by(), Some(Host::Name(name))
+                            if trusted_relays.contains(&name.to_ascii_lowercase())));
+                    if is_trusted {
+                        auth_results.append(&mut pending);
+                    } else {
+                        break;
+                    }
+                }
+                HeaderName::Other(name)
+                    if name.eq_ignore_ascii_case("Authentication-Results")
+                        && header.offset_end > 0 =>
+                {
+                    if let Some(result) = raw_message
+                        .get(header.offset_start..header.offset_end)
+                        .and_then(|bytes| std::str::from_utf8(bytes).ok())
+                        .and_then(AuthResults::parse)
+                    {
+                        if trusted_ids.contains(&result.authserv_id) {
+                            pending.push(result);
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        auth_results
+    }
+}
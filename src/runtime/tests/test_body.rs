@@ -31,6 +31,7 @@ use crate::{
         },
         Number,
     },
+    runtime::RuntimeError,
     Context,
 };
 
@@ -93,6 +94,7 @@ impl TestBody {
             }
         };
 
+        let mut timed_out = false;
         let result = if let MatchType::Count(rel_match) = &self.match_type {
             let mut count = 0;
             let mut result = false;
@@ -112,6 +114,12 @@ impl TestBody {
             result
         } else {
             ctx.find_nested_parts(&ctx.message, &ct_filter, &mut |part, raw_message| {
+                // Large messages can take a while to scan
+                if ctx.is_past_deadline() {
+                    timed_out = true;
+                    return true;
+                }
+
                 let text = match (&self.body_transform, &part.body) {
                     (BodyTransform::Content(_), PartType::Message(message)) => {
                         if let Some(part) = message.parts.first() {
@@ -176,9 +184,9 @@ impl TestBody {
                     (
                         BodyTransform::Text,
                         PartType::Binary(bytes) | PartType::InlineBinary(bytes),
-                    ) if part.content_type().map_or(false, |ct| {
+                    ) if part.content_type().is_some_and(|ct| {
                         ct.c_type.eq_ignore_ascii_case("application")
-                            && ct.c_subtype.as_ref().map_or(false, |st| st.contains("xml"))
+                            && ct.c_subtype.as_ref().is_some_and(|st| st.contains("xml"))
                     }) =>
                     {
                         html_to_text(std::str::from_utf8(bytes.as_ref()).unwrap_or("")).into()
@@ -224,6 +232,10 @@ impl TestBody {
             })
         };
 
-        TestResult::Bool(result ^ self.is_not)
+        if timed_out {
+            TestResult::Error(RuntimeError::TimeLimitReached)
+        } else {
+            TestResult::Bool(result ^ self.is_not)
+        }
     }
 }
@@ -23,6 +23,8 @@
 
 use std::borrow::Cow;
 
+use chrono::{Offset, TimeZone};
+use chrono_tz::Tz;
 use mail_parser::{parsers::MessageStream, DateTime, Header, HeaderValue};
 
 use crate::{
@@ -78,7 +80,11 @@ impl TestDate {
                     self.mime_anychild,
                     |header, _, _| {
                         if let Some(dt) = ctx.find_dates(header) {
-                            let value = self.date_part.eval(self.zone.eval(dt.as_ref()).as_ref());
+                            let value = self.date_part.eval(
+                                self.zone
+                                    .eval(dt.as_ref(), ctx.runtime.default_timezone)
+                                    .as_ref(),
+                            );
                             if !value.is_empty() && !values.iter().any(|v: &String| v.eq(&value)) {
                                 values.push(value);
                             }
@@ -109,8 +115,11 @@ impl TestDate {
                     self.mime_anychild,
                     |header, _, _| {
                         if let Some(dt) = ctx.find_dates(header) {
-                            let date_part =
-                                self.date_part.eval(self.zone.eval(dt.as_ref()).as_ref());
+                            let date_part = self.date_part.eval(
+                                self.zone
+                                    .eval(dt.as_ref(), ctx.runtime.default_timezone)
+                                    .as_ref(),
+                            );
                             for key in &key_list {
                                 if match &self.match_type {
                                     MatchType::Is => self.comparator.is(&date_part.as_str(), key),
@@ -172,11 +181,13 @@ impl TestCurrentDate {
             }
             MatchType::List => {
                 let value = self.date_part.eval(
-                    &(if let Some(zone) = self.zone {
-                        DateTime::from_timestamp(ctx.current_time).to_timezone(zone)
-                    } else {
-                        DateTime::from_timestamp(ctx.current_time)
-                    }),
+                    self.zone
+                        .unwrap_or(Zone::Local)
+                        .eval(
+                            &DateTime::from_timestamp(ctx.current_time),
+                            ctx.runtime.default_timezone,
+                        )
+                        .as_ref(),
                 );
                 if !value.is_empty() {
                     return TestResult::Event {
@@ -192,11 +203,13 @@ impl TestCurrentDate {
             _ => {
                 let mut captured_values = Vec::new();
                 let date_part = self.date_part.eval(
-                    &(if let Some(zone) = self.zone {
-                        DateTime::from_timestamp(ctx.current_time).to_timezone(zone)
-                    } else {
-                        DateTime::from_timestamp(ctx.current_time)
-                    }),
+                    self.zone
+                        .unwrap_or(Zone::Local)
+                        .eval(
+                            &DateTime::from_timestamp(ctx.current_time),
+                            ctx.runtime.default_timezone,
+                        )
+                        .as_ref(),
                 );
 
                 for key in &self.key_list {
@@ -300,11 +313,23 @@ impl DatePart {
 }
 
 impl Zone {
-    pub(crate) fn eval<'x>(&self, dt: &'x DateTime) -> Cow<'x, DateTime> {
+    pub(crate) fn eval<'x>(&self, dt: &'x DateTime, local_tz: Tz) -> Cow<'x, DateTime> {
         match self {
             Zone::Time(tz) => Cow::Owned(dt.to_timezone(*tz)),
+            Zone::Named(tz) => Cow::Owned(dt.to_timezone(tz_offset(*tz, dt.to_timestamp()))),
             Zone::Original => Cow::Borrowed(dt),
-            Zone::Local => Cow::Owned(DateTime::from_timestamp(dt.to_timestamp())),
+            Zone::Local => Cow::Owned(dt.to_timezone(tz_offset(local_tz, dt.to_timestamp()))),
         }
     }
 }
+
+// Returns the UTC offset in seconds of a named time zone at the given instant
+pub(crate) fn tz_offset(tz: Tz, timestamp: i64) -> i64 {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|dt| {
+            tz.offset_from_utc_datetime(&dt.naive_utc())
+                .fix()
+                .local_minus_utc() as i64
+        })
+        .unwrap_or_default()
+}
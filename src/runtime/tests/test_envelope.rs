@@ -170,7 +170,11 @@ impl<'x> Context<'x> {
                     }
                     Envelope::ByTimeAbsolute if test_envelope.zone.is_some() => {
                         if let Some(dt) = DateTime::parse_rfc3339(value.to_string().as_ref()) {
-                            cb(&dt.to_timezone(test_envelope.zone.unwrap()).to_rfc3339())
+                            cb(&test_envelope
+                                .zone
+                                .unwrap()
+                                .eval(&dt, self.runtime.default_timezone)
+                                .to_rfc3339())
                         } else {
                             cb("")
                         }
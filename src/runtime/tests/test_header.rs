@@ -28,7 +28,7 @@ use crate::{
         grammar::{actions::action_mime::MimeOpts, tests::test_header::TestHeader, MatchType},
         Number, Value,
     },
-    runtime::Variable,
+    runtime::{RuntimeError, Variable},
     Context, Event,
 };
 
@@ -87,6 +87,7 @@ impl TestHeader {
             ),
             MatchType::Matches(capture_positions) | MatchType::Regex(capture_positions) => {
                 let mut captured_values = Vec::new();
+                let mut timed_out = false;
                 let is_matches = matches!(&self.match_type, MatchType::Matches(_));
                 let result = ctx.find_headers(
                     &header_list,
@@ -94,6 +95,11 @@ impl TestHeader {
                     self.mime_anychild,
                     |header, _, _| {
                         ctx.find_header_values(header, &mime_opts, |value| {
+                            if ctx.is_past_deadline() {
+                                timed_out = true;
+                                return true;
+                            }
+
                             for (pattern_expr, pattern) in key_list.iter().zip(self.key_list.iter())
                             {
                                 if is_matches {
@@ -119,6 +125,9 @@ impl TestHeader {
                         })
                     },
                 );
+                if timed_out {
+                    return TestResult::Error(RuntimeError::TimeLimitReached);
+                }
                 if !captured_values.is_empty() {
                     ctx.set_match_variables(captured_values);
                 }
@@ -21,8 +21,6 @@
  * for more details.
 */
 
-use std::borrow::Cow;
-
 use crate::{
     compiler::{
         grammar::{
@@ -31,7 +29,6 @@ use crate::{
         },
         Number,
     },
-    runtime::actions::action_notify::validate_uri,
     Context,
 };
 
@@ -42,17 +39,9 @@ impl TestValidNotifyMethod {
         let mut num_valid = 0;
 
         for uri in &self.notification_uris {
-            let uri_ = ctx.eval_value(uri);
-            let uri = uri_.to_string();
-            if let Some(scheme) = validate_uri(uri.as_ref()) {
-                if ctx
-                    .runtime
-                    .valid_notification_uris
-                    .contains(&Cow::from(scheme))
-                    || ctx.runtime.valid_notification_uris.contains(&uri)
-                {
-                    num_valid += 1;
-                }
+            let uri = ctx.eval_value(uri).to_string().into_owned();
+            if ctx.validate_notify_uri(&uri) {
+                num_valid += 1;
             }
         }
 
@@ -62,21 +51,17 @@ impl TestValidNotifyMethod {
 
 impl TestNotifyMethodCapability {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
-        let uri_ = ctx.eval_value(&self.notification_uri);
-        let uri = uri_.to_string();
-        if !ctx
+        let uri = ctx
+            .eval_value(&self.notification_uri)
+            .to_string()
+            .into_owned();
+        let capability = ctx
             .eval_value(&self.notification_capability)
             .to_string()
-            .eq_ignore_ascii_case("online")
-            || !validate_uri(uri.as_ref()).map_or(false, |scheme| {
-                ctx.runtime
-                    .valid_notification_uris
-                    .contains(&Cow::from(scheme))
-                    || ctx.runtime.valid_notification_uris.contains(&uri)
-            })
-        {
+            .into_owned();
+        let Some(value) = ctx.notify_capability(&uri, &capability) else {
             return TestResult::Bool(false ^ self.is_not);
-        }
+        };
 
         if let MatchType::Count(rel_match) = &self.match_type {
             for key in &self.key_list {
@@ -88,22 +73,22 @@ impl TestNotifyMethodCapability {
             for pattern in &self.key_list {
                 let key = ctx.eval_value(pattern);
                 if match &self.match_type {
-                    MatchType::Is => self.comparator.is(&"maybe", &key),
-                    MatchType::Contains => {
-                        self.comparator.contains("maybe", key.to_string().as_ref())
-                    }
+                    MatchType::Is => self.comparator.is(&value.as_str(), &key),
+                    MatchType::Contains => self
+                        .comparator
+                        .contains(value.as_str(), key.to_string().as_ref()),
                     MatchType::Value(relation) => {
-                        self.comparator.relational(relation, &"maybe", &key)
+                        self.comparator.relational(relation, &value.as_str(), &key)
                     }
                     MatchType::Matches(_) => self.comparator.matches(
-                        "maybe",
+                        value.as_str(),
                         key.to_string().as_ref(),
                         0,
                         &mut Vec::new(),
                     ),
                     MatchType::Regex(_) => {
                         self.comparator
-                            .regex(pattern, &key, "maybe", 0, &mut Vec::new())
+                            .regex(pattern, &key, value.as_str(), 0, &mut Vec::new())
                     }
                     _ => false,
                 } {
@@ -0,0 +1,50 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use crate::{
+    compiler::grammar::tests::test_ratelimit::TestRateLimit,
+    runtime::ratelimit::RESERVED_KEY_PREFIX, Context,
+};
+
+use super::TestResult;
+
+impl TestRateLimit {
+    pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
+        // Keys are case-insensitive, each evaluation takes a token.
+        // Reserved keys are never rate limited so scripts cannot alter
+        // the buckets used by host policies.
+        let key = ctx.eval_value(&self.key).to_string().to_lowercase();
+
+        TestResult::Bool(
+            (!key.is_empty()
+                && !key.starts_with(RESERVED_KEY_PREFIX)
+                && !ctx.runtime.rate_limiter.is_allowed(
+                    &ctx.user_address,
+                    &key,
+                    self.count,
+                    self.period,
+                ))
+                ^ self.is_not,
+        )
+    }
+}
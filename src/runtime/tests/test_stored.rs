@@ -0,0 +1,42 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use crate::{compiler::grammar::tests::test_stored::TestStored, Context};
+
+use super::TestResult;
+
+impl TestStored {
+    pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
+        let key = ctx.eval_value(&self.key).to_string().to_lowercase();
+
+        TestResult::Bool(
+            (!key.is_empty()
+                && ctx
+                    .runtime
+                    .store
+                    .as_ref()
+                    .is_some_and(|store| store.get(&ctx.user_address, &key).is_some()))
+                ^ self.is_not,
+        )
+    }
+}
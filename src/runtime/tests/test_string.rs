@@ -26,6 +26,7 @@ use crate::{
         grammar::{tests::test_string::TestString, MatchType},
         Number,
     },
+    runtime::RuntimeError,
     Context, Event,
 };
 
@@ -75,10 +76,14 @@ impl TestString {
             _ => {
                 let mut captured_values = Vec::new();
                 let sources = ctx.eval_values(&self.source);
+                let is_regex = matches!(&self.match_type, MatchType::Regex(_));
 
                 for pattern in &self.key_list {
                     let key = ctx.eval_value(pattern);
                     for source in &sources {
+                        if is_regex && ctx.is_past_deadline() {
+                            return TestResult::Error(RuntimeError::TimeLimitReached);
+                        }
                         if !empty_is_null || !source.is_empty() {
                             result = match &self.match_type {
                                 MatchType::Is => self.comparator.is(source, &key),
@@ -28,6 +28,7 @@ use super::Variable;
 impl<'x> Context<'x> {
     pub(crate) fn set_match_variables(&mut self, set_vars: Vec<(usize, String)>) {
         for (var_num, value) in set_vars {
+            self.use_memory(value.len());
             if let Some(var) = self.vars_match.get_mut(var_num) {
                 *var = value.into();
             } else {